@@ -1,91 +1,709 @@
-use std::{future::Future, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use aria2_rs::{
-    call::{TellActiveCall, TellStoppedCall, TellWaitingCall},
-    status::Status,
-    BatchClient, ConnectionMeta,
+    call::{GetGlobalStatCall, TellActiveCall, TellStatusCall, TellStoppedCall, TellWaitingCall},
+    options::TaskOptions,
+    status::{File, Stat, Status},
+    BatchClient, Call, ConnectionMeta, NotificationCallback, Reply, SmallVec, OK,
 };
+use parking_lot::Mutex;
+use serde::{ser::SerializeSeq as _, Deserialize};
 use smol_str::SmolStr;
+use tokio::sync::broadcast;
 
 use crate::config::{Aria2Config, Param};
-use crate::constants::{ARIA2_MAX_RETRIES, ARIA2_RETRY_DELAY};
+use crate::constants::{
+    ARIA2_CONNECT_POLL_INTERVAL, ARIA2_CONNECT_TIMEOUT, ARIA2_EVENT_BUFFER_SIZE, ARIA2_MAX_RETRIES,
+    ARIA2_OP_TIMEOUT, ARIA2_RECONNECT_MAX_DELAY, ARIA2_RECONNECT_MIN_DELAY, ARIA2_RETRY_DELAY,
+};
+use crate::metrics::METRICS;
+
+type SerializeSeq = <serde_json::value::Serializer as serde::Serializer>::SerializeSeq;
+
+/// `aria2.getFiles`, not provided by aria2-rs.
+struct GetFilesCall {
+    gid: SmolStr,
+}
+
+impl Reply for GetFilesCall {
+    type Reply = Vec<File>;
+}
+
+impl Call for GetFilesCall {
+    fn method(&self) -> &'static str {
+        "aria2.getFiles"
+    }
+
+    fn serialize_params(
+        &self,
+        serializer: &mut SerializeSeq,
+        token: Option<&str>,
+    ) -> Result<(), serde_json::Error> {
+        if let Some(token) = token {
+            serializer.serialize_element(token)?;
+        }
+        serializer.serialize_element(&self.gid)?;
+        Ok(())
+    }
+}
+
+/// `aria2.changeOption`, or `aria2.changeGlobalOption` without gid, not provided by aria2-rs.
+struct ChangeOptionCall {
+    gid: Option<SmolStr>,
+    options: TaskOptions,
+}
+
+impl Reply for ChangeOptionCall {
+    type Reply = OK;
+}
+
+impl Call for ChangeOptionCall {
+    fn method(&self) -> &'static str {
+        match self.gid {
+            Some(_) => "aria2.changeOption",
+            None => "aria2.changeGlobalOption",
+        }
+    }
+
+    fn serialize_params(
+        &self,
+        serializer: &mut SerializeSeq,
+        token: Option<&str>,
+    ) -> Result<(), serde_json::Error> {
+        if let Some(token) = token {
+            serializer.serialize_element(token)?;
+        }
+        if let Some(gid) = &self.gid {
+            serializer.serialize_element(gid)?;
+        }
+        serializer.serialize_element(&self.options)?;
+        Ok(())
+    }
+}
+
+/// `aria2.getOption`, or `aria2.getGlobalOption` without gid, not provided by aria2-rs.
+struct GetOptionCall {
+    gid: Option<SmolStr>,
+}
+
+impl Reply for GetOptionCall {
+    type Reply = HashMap<String, String>;
+}
+
+impl Call for GetOptionCall {
+    fn method(&self) -> &'static str {
+        match self.gid {
+            Some(_) => "aria2.getOption",
+            None => "aria2.getGlobalOption",
+        }
+    }
+
+    fn serialize_params(
+        &self,
+        serializer: &mut SerializeSeq,
+        token: Option<&str>,
+    ) -> Result<(), serde_json::Error> {
+        if let Some(token) = token {
+            serializer.serialize_element(token)?;
+        }
+        if let Some(gid) = &self.gid {
+            serializer.serialize_element(gid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parameterless aria2 methods replying `OK`, e.g. `aria2.pauseAll`.
+struct GlobalActionCall(&'static str);
+
+impl Reply for GlobalActionCall {
+    type Reply = OK;
+}
+
+impl Call for GlobalActionCall {
+    fn method(&self) -> &'static str {
+        self.0
+    }
+
+    fn serialize_params(
+        &self,
+        serializer: &mut SerializeSeq,
+        token: Option<&str>,
+    ) -> Result<(), serde_json::Error> {
+        if let Some(token) = token {
+            serializer.serialize_element(token)?;
+        }
+        Ok(())
+    }
+}
+
+/// `aria2.removeDownloadResult`, not provided by aria2-rs.
+struct RemoveDownloadResultCall {
+    gid: SmolStr,
+}
+
+impl Reply for RemoveDownloadResultCall {
+    type Reply = OK;
+}
+
+impl Call for RemoveDownloadResultCall {
+    fn method(&self) -> &'static str {
+        "aria2.removeDownloadResult"
+    }
+
+    fn serialize_params(
+        &self,
+        serializer: &mut SerializeSeq,
+        token: Option<&str>,
+    ) -> Result<(), serde_json::Error> {
+        if let Some(token) = token {
+            serializer.serialize_element(token)?;
+        }
+        serializer.serialize_element(&self.gid)?;
+        Ok(())
+    }
+}
+
+/// Reply of `aria2.getVersion`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Version {
+    pub version: String,
+    pub enabled_features: Vec<String>,
+}
+
+/// `aria2.getVersion`, not provided by aria2-rs.
+struct GetVersionCall;
+
+impl Reply for GetVersionCall {
+    type Reply = Version;
+}
+
+impl Call for GetVersionCall {
+    fn method(&self) -> &'static str {
+        "aria2.getVersion"
+    }
+
+    fn serialize_params(
+        &self,
+        serializer: &mut SerializeSeq,
+        token: Option<&str>,
+    ) -> Result<(), serde_json::Error> {
+        if let Some(token) = token {
+            serializer.serialize_element(token)?;
+        }
+        Ok(())
+    }
+}
+
+/// Download or upload direction of a speed limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Download,
+    Upload,
+}
+
+impl LimitKind {
+    /// aria2 option name, overall limits apply to the whole server.
+    pub fn option(self, overall: bool) -> &'static str {
+        match (self, overall) {
+            (Self::Download, false) => "max-download-limit",
+            (Self::Upload, false) => "max-upload-limit",
+            (Self::Download, true) => "max-overall-download-limit",
+            (Self::Upload, true) => "max-overall-upload-limit",
+        }
+    }
+}
+
+/// Speed limits in bytes/sec, 0 means unrestricted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpeedLimits {
+    pub download: u64,
+    pub upload: u64,
+}
+
+/// Parse a speed in aria2 style, bytes/sec with optional K, M or G suffix.
+pub fn parse_speed(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1024),
+        (i, 'm' | 'M') => (&s[..i], 1024 * 1024),
+        (i, 'g' | 'G') => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    num.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Explanation and suggested remedy for an aria2 exit code.
+///
+/// See the "EXIT STATUS" section of the aria2c manual.
+pub fn error_code_info(code: &str) -> Option<(&'static str, &'static str)> {
+    let info = match code.trim().parse::<u8>().ok()? {
+        1 => ("Unknown error", "Check the aria2 log for details."),
+        2 => (
+            "Timeout",
+            "The server may be slow or unreachable, retry later or raise --timeout.",
+        ),
+        3 => (
+            "Resource not found",
+            "The link may be dead or expired, get a fresh one.",
+        ),
+        4 => (
+            "Too many \"resource not found\" errors",
+            "The mirrors are gone, check the links or raise --max-file-not-found.",
+        ),
+        5 => (
+            "Download speed too slow",
+            "Speed stayed below --lowest-speed-limit, retry later or lower that limit.",
+        ),
+        6 => (
+            "Network problem",
+            "Check the connectivity and DNS of the aria2 server.",
+        ),
+        7 => (
+            "Unfinished download when aria2 exited",
+            "aria2 was stopped before the task completed, just retry it.",
+        ),
+        8 => (
+            "Server does not support resume",
+            "Remove the partial file and download again from scratch.",
+        ),
+        9 => (
+            "Not enough disk space",
+            "Free some space or download to another directory.",
+        ),
+        10 => (
+            "Piece length differs from the .aria2 control file",
+            "Remove the .aria2 control file and the partial file, then retry.",
+        ),
+        11 => (
+            "Same file is already being downloaded",
+            "Wait for the other task to finish.",
+        ),
+        12 => (
+            "Same torrent is already being downloaded",
+            "A task with the same info hash is running, remove one of them.",
+        ),
+        13 => (
+            "File already exists",
+            "Remove the existing file or enable --allow-overwrite or --auto-file-renaming.",
+        ),
+        14 => (
+            "Renaming file failed",
+            "Check the permissions of the download directory.",
+        ),
+        15 => (
+            "Could not open existing file",
+            "Check the file permissions on the server.",
+        ),
+        16 => (
+            "Could not create or truncate file",
+            "Check the permissions and free space of the download directory.",
+        ),
+        17 => (
+            "File I/O error",
+            "The disk may be failing or full, check the server.",
+        ),
+        18 => (
+            "Could not create directory",
+            "Check the path and permissions of the download directory.",
+        ),
+        19 => (
+            "Name resolution failed",
+            "Check the domain name and the DNS of the aria2 server.",
+        ),
+        20 => (
+            "Could not parse Metalink document",
+            "The metalink file may be broken, get it again.",
+        ),
+        21 => (
+            "FTP command failed",
+            "The FTP server rejected a command, check the link and credentials.",
+        ),
+        22 => (
+            "Bad or unexpected HTTP response header",
+            "The server answered unexpectedly, retry later or use another mirror.",
+        ),
+        23 => (
+            "Too many redirects",
+            "The link loops between redirects, get a direct link.",
+        ),
+        24 => (
+            "HTTP authorization failed",
+            "The link needs credentials or a valid cookie.",
+        ),
+        25 => (
+            "Could not parse bencoded file",
+            "The torrent file is broken, get it again.",
+        ),
+        26 => (
+            "Torrent file is corrupted or missing information",
+            "The torrent file is broken, get it again.",
+        ),
+        27 => (
+            "Bad magnet URI",
+            "Check the magnet link for typos or truncation.",
+        ),
+        28 => (
+            "Bad or unrecognized option",
+            "Check the options passed to the task.",
+        ),
+        29 => (
+            "Remote server is temporarily overloaded or in maintenance",
+            "Retry later.",
+        ),
+        30 => (
+            "Could not parse JSON-RPC request",
+            "The request was malformed, please report it.",
+        ),
+        32 => (
+            "Checksum validation failed",
+            "The file is corrupted, remove it and download again.",
+        ),
+        _ => return None,
+    };
+    Some(info)
+}
+
+/// Format 1-based file indexes as aria2 `select-file` value, e.g. `1-3,5`.
+pub fn select_file_option(indexes: &[u64]) -> SmolStr {
+    let mut sorted = indexes.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for idx in sorted {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == idx => *end = idx,
+            _ => ranges.push((idx, idx)),
+        }
+    }
+    ranges
+        .into_iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+        .into()
+}
+
+/// Uris to re-add a stopped task from: the distinct uris of its download, all of
+/// them point to the same file, or a magnet link built from its info hash.
+pub fn retry_uris(task: &Status) -> SmallVec<String> {
+    let mut uris: SmallVec<String> = SmallVec::new();
+    let file_uris = task
+        .files
+        .iter()
+        .flatten()
+        .take(1)
+        .flat_map(|f| f.uris.iter());
+    for uri in file_uris {
+        if !uris.contains(&uri.uri) {
+            uris.push(uri.uri.clone());
+        }
+    }
+    if uris.is_empty() {
+        if let Some(info_hash) = task.info_hash.as_deref().filter(|h| !h.is_empty()) {
+            uris.push(format!("magnet:?xt=urn:btih:{info_hash}"));
+        }
+    }
+    uris
+}
+
+fn ok_or_err(ok: OK) -> Result<()> {
+    match ok {
+        OK::Ok => Ok(()),
+        OK::Err(e) => Err(anyhow::anyhow!("aria2 returned {e}")),
+    }
+}
 
 pub struct AddUrisResult {
     pub gids: Vec<SmolStr>,
     pub error: Option<anyhow::Error>,
 }
 
-async fn retry_call<T, F, Fut>(op_name: &str, f: F) -> Result<T>
-where
-    F: Fn() -> Fut,
-    Fut: Future<Output = Result<T, aria2_rs::Error>>,
-{
-    let mut last_err = None;
-    for attempt in 0..ARIA2_MAX_RETRIES {
-        match f().await {
-            Ok(result) => return Ok(result),
-            Err(e) => {
-                tracing::warn!(
-                    "{} attempt {}/{} failed: {}",
-                    op_name,
-                    attempt + 1,
-                    ARIA2_MAX_RETRIES,
-                    e
-                );
-                last_err = Some(e);
-                if attempt + 1 < ARIA2_MAX_RETRIES {
-                    tokio::time::sleep(ARIA2_RETRY_DELAY).await;
-                }
-            }
+/// Task events pushed by aria2 over the websocket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aria2Event {
+    Start,
+    Pause,
+    Stop,
+    Complete,
+    Error,
+    BtComplete,
+}
+
+impl Aria2Event {
+    pub fn from_method(method: &str) -> Option<Self> {
+        match method {
+            "aria2.onDownloadStart" => Some(Self::Start),
+            "aria2.onDownloadPause" => Some(Self::Pause),
+            "aria2.onDownloadStop" => Some(Self::Stop),
+            "aria2.onDownloadComplete" => Some(Self::Complete),
+            "aria2.onDownloadError" => Some(Self::Error),
+            "aria2.onBtDownloadComplete" => Some(Self::BtComplete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Aria2Notification {
+    pub event: Aria2Event,
+    pub gid: SmolStr,
+}
+
+/// Forward aria2 notifications to a broadcast channel.
+struct NotificationForwarder(broadcast::Sender<Aria2Notification>);
+
+impl NotificationCallback for NotificationForwarder {
+    fn on_notification(&self, method: SmolStr, gid: SmolStr) {
+        let Some(event) = Aria2Event::from_method(&method) else {
+            tracing::debug!("Ignore unknown aria2 notification {method} for {gid}");
+            return;
+        };
+        // No receiver is fine, the event is simply dropped.
+        let _ = self.0.send(Aria2Notification { event, gid });
+    }
+}
+
+/// Connection health of an aria2 server.
+#[derive(Debug, Clone, Default)]
+pub struct Health {
+    pub last_success: Option<Instant>,
+    pub last_error: Option<(Instant, String)>,
+    // round trip time of the last successful health check
+    pub latency: Option<Duration>,
+}
+
+impl Health {
+    /// Whether the server answered since the last error.
+    pub fn healthy(&self) -> bool {
+        match (self.last_success, &self.last_error) {
+            (Some(success), Some((error, _))) => success > *error,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    fn record_success(&mut self, latency: Option<Duration>) {
+        self.last_success = Some(Instant::now());
+        if latency.is_some() {
+            self.latency = latency;
+        }
+    }
+
+    fn record_error(&mut self, error: String) {
+        self.last_error = Some((Instant::now(), error));
+    }
+
+    /// Record the outcome of a call, errors replied by aria2 still mean it is reachable.
+    fn record_call<T>(&mut self, res: &Result<T, aria2_rs::Error>) {
+        match res {
+            Ok(_) | Err(aria2_rs::Error::Rpc(_)) => self.record_success(None),
+            Err(e) => self.record_error(e.to_string()),
         }
     }
-    Err(last_err.expect("ARIA2_MAX_RETRIES must be > 0").into())
 }
 
 #[derive(Clone)]
 pub struct Aria2Client {
-    cli: BatchClient,
+    // Set once the first connection succeeds, aria2-rs reconnects by itself after that
+    cli: Arc<OnceLock<BatchClient>>,
+    events: broadcast::Sender<Aria2Notification>,
+    health: Arc<Mutex<Health>>,
 }
 
 impl Aria2Client {
     const DEFAULT_CHANNEL_BUFFER_SIZE: usize = 100;
     const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
 
-    pub async fn connect<C: Param<Aria2Config>>(cfg: &C) -> Result<Self> {
+    /// Create a client connecting in background, retrying with exponential backoff.
+    ///
+    /// Calls fail until the first connection is established.
+    pub fn connect<C: Param<Aria2Config>>(cfg: &C) -> Self {
         let aria_config = cfg.param();
+        let (events, _) = broadcast::channel(ARIA2_EVENT_BUFFER_SIZE);
+        let client = Self {
+            cli: Arc::new(OnceLock::new()),
+            events: events.clone(),
+            health: Default::default(),
+        };
+
+        let cli = Arc::downgrade(&client.cli);
+        let health = client.health.clone();
+        tokio::spawn(async move {
+            let mut delay = ARIA2_RECONNECT_MIN_DELAY;
+            loop {
+                let conn_meta = ConnectionMeta {
+                    url: aria_config.rpc_url.clone(),
+                    token: Some(aria_config.token.clone()),
+                };
+                let res = tokio::time::timeout(
+                    ARIA2_CONNECT_TIMEOUT,
+                    BatchClient::connect_with_cb(
+                        conn_meta,
+                        aria_config
+                            .channel_buffer_size
+                            .unwrap_or(Self::DEFAULT_CHANNEL_BUFFER_SIZE),
+                        aria_config
+                            .interval_secs
+                            .map(Duration::from_secs)
+                            .unwrap_or(Self::DEFAULT_INTERVAL),
+                        NotificationForwarder(events.clone()),
+                    ),
+                )
+                .await;
+                // Stop when the client is gone, e.g. removed by a config reload
+                let Some(cli) = cli.upgrade() else {
+                    return;
+                };
+                let error = match res {
+                    Ok(Ok(batch)) => {
+                        tracing::info!("Connected to aria2 {}", aria_config.rpc_url);
+                        let _ = cli.set(batch);
+                        health.lock().record_success(None);
+                        return;
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => "connect timeout".to_string(),
+                };
+                tracing::warn!(
+                    "Unable to connect aria2 {}, retry in {delay:?}: {error}",
+                    aria_config.rpc_url
+                );
+                health.lock().record_error(error);
+                drop(cli);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(ARIA2_RECONNECT_MAX_DELAY);
+            }
+        });
+        client
+    }
 
-        let conn_meta = ConnectionMeta {
-            url: aria_config.rpc_url,
-            token: Some(aria_config.token),
+    /// Wait for the first connection, returns whether it was established in time.
+    pub async fn wait_connected(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            while self.cli.get().is_none() {
+                tokio::time::sleep(ARIA2_CONNECT_POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    fn cli(&self) -> Result<&BatchClient> {
+        self.cli
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("aria2 server is not connected yet"))
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.lock().clone()
+    }
+
+    /// Ping the server and record the outcome.
+    ///
+    /// Returns whether the server is healthy now.
+    pub async fn check_health(&self) -> bool {
+        // The background connector records connection errors
+        let Ok(cli) = self.cli() else {
+            return false;
         };
-        let cli = BatchClient::connect(
-            conn_meta,
-            aria_config
-                .channel_buffer_size
-                .unwrap_or(Self::DEFAULT_CHANNEL_BUFFER_SIZE),
-            aria_config
-                .interval_secs
-                .map(Duration::from_secs)
-                .unwrap_or(Self::DEFAULT_INTERVAL),
-        )
-        .await?;
-        Ok(Self { cli })
+        let start = Instant::now();
+        let res = tokio::time::timeout(ARIA2_OP_TIMEOUT, cli.call_instantly(&GetVersionCall)).await;
+        METRICS.record_call("health_check", start.elapsed(), matches!(res, Ok(Ok(_))));
+        let mut health = self.health.lock();
+        match res {
+            Ok(Ok(_)) => health.record_success(Some(start.elapsed())),
+            Ok(Err(e)) => health.record_error(e.to_string()),
+            Err(_) => health.record_error("health check timeout".to_string()),
+        }
+        health.healthy()
+    }
+
+    /// Run a call once, recording its metrics and the outcome into the health.
+    async fn track<T>(
+        &self,
+        op_name: &'static str,
+        call: impl Future<Output = Result<T, aria2_rs::Error>>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let res = call.await;
+        self.record(op_name, start, &res);
+        Ok(res?)
+    }
+
+    fn record<T>(&self, op_name: &'static str, start: Instant, res: &Result<T, aria2_rs::Error>) {
+        METRICS.record_call(op_name, start.elapsed(), res.is_ok());
+        self.health.lock().record_call(res);
+    }
+
+    async fn retry_call<T, F, Fut>(&self, op_name: &'static str, f: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, aria2_rs::Error>>,
+    {
+        let mut last_err = None;
+        for attempt in 0..ARIA2_MAX_RETRIES {
+            let start = Instant::now();
+            let res = f().await;
+            self.record(op_name, start, &res);
+            match res {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    tracing::warn!(
+                        "{} attempt {}/{} failed: {}",
+                        op_name,
+                        attempt + 1,
+                        ARIA2_MAX_RETRIES,
+                        e
+                    );
+                    last_err = Some(e);
+                    if attempt + 1 < ARIA2_MAX_RETRIES {
+                        tokio::time::sleep(ARIA2_RETRY_DELAY).await;
+                    }
+                }
+            }
+        }
+        Err(last_err.expect("ARIA2_MAX_RETRIES must be > 0").into())
+    }
+
+    /// Subscribe to task events pushed by aria2.
+    pub fn subscribe(&self) -> broadcast::Receiver<Aria2Notification> {
+        self.events.subscribe()
     }
 
     pub async fn get_tasks(&self) -> Result<Vec<Status>> {
         let (mut active, waiting, stopped) = tokio::try_join!(
-            self.cli.call(TellActiveCall::default()),
-            self.cli.call(TellWaitingCall {
-                offset: 0,
-                num: 1000,
-                keys: Default::default(),
-            }),
-            self.cli.call(TellStoppedCall {
-                offset: 0,
-                num: 1000,
-                keys: Default::default(),
-            })
+            self.track("tell_active", self.cli()?.call(TellActiveCall::default())),
+            self.track(
+                "tell_waiting",
+                self.cli()?.call(TellWaitingCall {
+                    offset: 0,
+                    num: 1000,
+                    keys: Default::default(),
+                })
+            ),
+            self.track(
+                "tell_stopped",
+                self.cli()?.call(TellStoppedCall {
+                    offset: 0,
+                    num: 1000,
+                    keys: Default::default(),
+                })
+            )
         )?;
 
         active.extend(waiting);
@@ -94,23 +712,49 @@ impl Aria2Client {
         Ok(active)
     }
 
+    pub async fn get_active_tasks(&self) -> Result<Vec<Status>> {
+        self.track("tell_active", self.cli()?.call(TellActiveCall::default()))
+            .await
+    }
+
+    pub async fn get_task(&self, gid: &str) -> Result<Status> {
+        let call = self.cli()?.call(TellStatusCall {
+            gid: gid.to_string().into(),
+            keys: Default::default(),
+        });
+        self.track("tell_status", call).await
+    }
+
+    pub async fn get_global_stat(&self) -> Result<Stat> {
+        self.track(
+            "get_global_stat",
+            self.cli()?.call(GetGlobalStatCall::default()),
+        )
+        .await
+    }
+
+    pub async fn get_version(&self) -> Result<Version> {
+        self.track("get_version", self.cli()?.call_instantly(&GetVersionCall))
+            .await
+    }
+
     pub async fn pause(&self, gid: &str) -> Result<()> {
-        self.cli
-            .call_instantly(&aria2_rs::call::PauseCall { gid: gid.into() })
+        let call = aria2_rs::call::PauseCall { gid: gid.into() };
+        self.track("pause", self.cli()?.call_instantly(&call))
             .await?;
         Ok(())
     }
 
     pub async fn resume(&self, gid: &str) -> Result<()> {
-        self.cli
-            .call_instantly(&aria2_rs::call::UnpauseCall { gid: gid.into() })
+        let call = aria2_rs::call::UnpauseCall { gid: gid.into() };
+        self.track("resume", self.cli()?.call_instantly(&call))
             .await?;
         Ok(())
     }
 
     pub async fn remove(&self, gid: &str) -> Result<()> {
-        self.cli
-            .call_instantly(&aria2_rs::call::RemoveCall { gid: gid.into() })
+        let call = aria2_rs::call::RemoveCall { gid: gid.into() };
+        self.track("remove", self.cli()?.call_instantly(&call))
             .await?;
         Ok(())
     }
@@ -120,6 +764,15 @@ impl Aria2Client {
             dir: Some(dir),
             ..Default::default()
         });
+        let cli = match self.cli() {
+            Ok(cli) => cli,
+            Err(e) => {
+                return AddUrisResult {
+                    gids: Vec::new(),
+                    error: Some(e),
+                }
+            }
+        };
         let mut gids = Vec::with_capacity(links.len());
         for link in links.iter() {
             let link_vec: aria2_rs::SmallVec<String> = [link.to_string()].as_slice().into();
@@ -127,7 +780,10 @@ impl Aria2Client {
                 uris: link_vec,
                 options: options.clone(),
             };
-            match retry_call("add_uris", || self.cli.call_instantly(&call)).await {
+            match self
+                .retry_call("add_uris", || cli.call_instantly(&call))
+                .await
+            {
                 Ok(gid) => gids.push(gid.0),
                 Err(e) => {
                     return AddUrisResult {
@@ -140,23 +796,153 @@ impl Aria2Client {
         AddUrisResult { gids, error: None }
     }
 
-    pub async fn add_torrent(&self, torrent_data: &[u8], dir: Option<SmolStr>) -> Result<SmolStr> {
-        let options = dir.map(|dir| aria2_rs::options::TaskOptions {
-            dir: Some(dir),
+    pub async fn get_files(&self, gid: &str) -> Result<Vec<File>> {
+        let call = GetFilesCall { gid: gid.into() };
+        self.track("get_files", self.cli()?.call_instantly(&call))
+            .await
+    }
+
+    /// Only download the files with given 1-based indexes.
+    pub async fn select_files(&self, gid: &str, indexes: &[u64]) -> Result<()> {
+        let mut options = TaskOptions::default();
+        options.extra_options.insert(
+            "select-file".into(),
+            select_file_option(indexes).as_str().into(),
+        );
+        let call = ChangeOptionCall {
+            gid: Some(gid.into()),
+            options,
+        };
+        let ok = self
+            .track("select_files", self.cli()?.call_instantly(&call))
+            .await?;
+        ok_or_err(ok)
+    }
+
+    /// Speed limits of a task, or of the whole server without gid.
+    pub async fn get_speed_limits(&self, gid: Option<&str>) -> Result<SpeedLimits> {
+        let call = GetOptionCall {
+            gid: gid.map(Into::into),
+        };
+        let options = self
+            .track("get_option", self.cli()?.call_instantly(&call))
+            .await?;
+        let limit = |kind: LimitKind| {
+            options
+                .get(kind.option(gid.is_none()))
+                .and_then(|v| parse_speed(v))
+                .unwrap_or(0)
+        };
+        Ok(SpeedLimits {
+            download: limit(LimitKind::Download),
+            upload: limit(LimitKind::Upload),
+        })
+    }
+
+    /// Set a speed limit of a task, or of the whole server without gid.
+    pub async fn set_speed_limit(
+        &self,
+        gid: Option<&str>,
+        kind: LimitKind,
+        speed: u64,
+    ) -> Result<()> {
+        let mut options = TaskOptions::default();
+        options
+            .extra_options
+            .insert(kind.option(gid.is_none()).into(), speed.to_string().into());
+        let call = ChangeOptionCall {
+            gid: gid.map(Into::into),
+            options,
+        };
+        let ok = self
+            .track("set_speed_limit", self.cli()?.call_instantly(&call))
+            .await?;
+        ok_or_err(ok)
+    }
+
+    pub async fn add_torrent(
+        &self,
+        torrent_data: &[u8],
+        dir: Option<SmolStr>,
+        selected_files: Option<&[u64]>,
+    ) -> Result<SmolStr> {
+        let mut options = TaskOptions {
+            dir,
             ..Default::default()
-        });
+        };
+        if let Some(indexes) = selected_files {
+            options.extra_options.insert(
+                "select-file".into(),
+                select_file_option(indexes).as_str().into(),
+            );
+        }
         let call = aria2_rs::call::AddTorrentCall {
             torrent: torrent_data.into(),
             uris: Default::default(),
-            options,
+            options: Some(options),
         };
-        let gid = retry_call("add_torrent", || self.cli.call_instantly(&call)).await?;
+        let cli = self.cli()?;
+        let gid = self
+            .retry_call("add_torrent", || cli.call_instantly(&call))
+            .await?;
         Ok(gid.0)
     }
 
+    pub async fn pause_all(&self) -> Result<()> {
+        let call = GlobalActionCall("aria2.pauseAll");
+        let ok = self
+            .track("pause_all", self.cli()?.call_instantly(&call))
+            .await?;
+        ok_or_err(ok)
+    }
+
+    pub async fn resume_all(&self) -> Result<()> {
+        let call = GlobalActionCall("aria2.unpauseAll");
+        let ok = self
+            .track("resume_all", self.cli()?.call_instantly(&call))
+            .await?;
+        ok_or_err(ok)
+    }
+
+    /// Forget a stopped task.
+    pub async fn remove_download_result(&self, gid: &str) -> Result<()> {
+        let call = RemoveDownloadResultCall { gid: gid.into() };
+        let ok = self
+            .track("remove_download_result", self.cli()?.call_instantly(&call))
+            .await?;
+        ok_or_err(ok)
+    }
+
+    /// Re-add a stopped task into the same dir, then forget the stale one.
+    pub async fn retry_task(&self, task: &Status) -> Result<SmolStr> {
+        let gid = task
+            .gid
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("task has no gid"))?;
+        let uris = retry_uris(task);
+        if uris.is_empty() {
+            anyhow::bail!("no uri or info hash to retry from");
+        }
+        let call = aria2_rs::call::AddUriCall {
+            uris,
+            options: Some(TaskOptions {
+                dir: task.dir.as_deref().map(Into::into),
+                ..Default::default()
+            }),
+        };
+        let cli = self.cli()?;
+        let new_gid = self
+            .retry_call("retry_task", || cli.call_instantly(&call))
+            .await?;
+        if let Err(e) = self.remove_download_result(gid).await {
+            tracing::warn!("Unable to remove download result of retried task {gid}: {e}");
+        }
+        Ok(new_gid.0)
+    }
+
     pub async fn purge_downloaded(&self) -> Result<()> {
-        self.cli
-            .call_instantly(&aria2_rs::call::PurgeDownloadResultCall)
+        let call = aria2_rs::call::PurgeDownloadResultCall;
+        self.track("purge_download_result", self.cli()?.call_instantly(&call))
             .await?;
         Ok(())
     }
@@ -164,6 +950,85 @@ impl Aria2Client {
 
 #[cfg(test)]
 mod tests {
+    use super::{
+        error_code_info, parse_speed, retry_uris, select_file_option, Aria2Event, Health,
+        LimitKind, Version,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn test_select_file_option() {
+        assert_eq!(select_file_option(&[1]), "1");
+        assert_eq!(select_file_option(&[1, 2, 3, 5]), "1-3,5");
+        assert_eq!(select_file_option(&[7, 2, 3, 2, 9, 8]), "2-3,7-9");
+        assert_eq!(select_file_option(&[]), "");
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(parse_speed("0"), Some(0));
+        assert_eq!(parse_speed("1048576"), Some(1024 * 1024));
+        assert_eq!(parse_speed("512K"), Some(512 * 1024));
+        assert_eq!(parse_speed("2m"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_speed(" 1G "), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_speed(""), None);
+        assert_eq!(parse_speed("M"), None);
+        assert_eq!(parse_speed("-1"), None);
+        assert_eq!(parse_speed("1.5M"), None);
+    }
+
+    #[test]
+    fn test_limit_option() {
+        assert_eq!(LimitKind::Download.option(false), "max-download-limit");
+        assert_eq!(LimitKind::Upload.option(true), "max-overall-upload-limit");
+    }
+
+    #[test]
+    fn test_retry_uris() {
+        let task: aria2_rs::status::Status = serde_json::from_str(
+            r#"{"gid":"2089b05ecca3d829","files":[{"index":"1","path":"/d/a","length":"1",
+            "completedLength":"0","selected":"true","uris":[
+            {"uri":"http://a/a","status":"used"},{"uri":"http://a/a","status":"waiting"},
+            {"uri":"http://b/a","status":"waiting"}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(retry_uris(&task).as_slice(), ["http://a/a", "http://b/a"]);
+
+        let task: aria2_rs::status::Status = serde_json::from_str(
+            r#"{"gid":"2089b05ecca3d829","infoHash":"c9e15763f722f23e98a29decdfae341b98d53056",
+            "files":[{"index":"1","path":"/d/a","length":"1","completedLength":"0",
+            "selected":"true","uris":[]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            retry_uris(&task).as_slice(),
+            ["magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056"]
+        );
+    }
+
+    #[test]
+    fn test_version_reply() {
+        let version: Version = serde_json::from_str(
+            r#"{"enabledFeatures":["BitTorrent","Metalink"],"version":"1.37.0"}"#,
+        )
+        .unwrap();
+        assert_eq!(version.version, "1.37.0");
+        assert_eq!(version.enabled_features, ["BitTorrent", "Metalink"]);
+    }
+
+    #[test]
+    fn test_event_from_method() {
+        assert_eq!(
+            Aria2Event::from_method("aria2.onDownloadComplete"),
+            Some(Aria2Event::Complete)
+        );
+        assert_eq!(
+            Aria2Event::from_method("aria2.onBtDownloadComplete"),
+            Some(Aria2Event::BtComplete)
+        );
+        assert_eq!(Aria2Event::from_method("aria2.onSomethingElse"), None);
+    }
+
     #[tokio::test]
     #[ignore] // requires real aria2 server
     async fn it_works() {
@@ -176,10 +1041,48 @@ mod tests {
             channel_buffer_size: None,
             interval_secs: None,
             admins_override: None,
+            operators_override: None,
+            viewers_override: None,
             download_override: None,
         };
-        let cli = Aria2Client::connect(&cfg).await.unwrap();
+        let cli = Aria2Client::connect(&cfg);
+        tokio::time::sleep(Duration::from_secs(3)).await;
         let tasks = cli.get_tasks().await.unwrap();
         dbg!(tasks);
     }
+
+    #[test]
+    fn test_error_code_info() {
+        assert_eq!(error_code_info("9").unwrap().0, "Not enough disk space");
+        assert_eq!(
+            error_code_info("24").unwrap().0,
+            "HTTP authorization failed"
+        );
+        assert!(error_code_info("0").is_none());
+        assert!(error_code_info("31").is_none());
+        assert!(error_code_info("abc").is_none());
+    }
+
+    #[test]
+    fn test_health() {
+        let mut health = Health::default();
+        assert!(!health.healthy());
+        health.record_success(Some(Duration::from_millis(20)));
+        assert!(health.healthy());
+        health.record_error("connection reset".to_string());
+        assert!(!health.healthy());
+        health.record_success(None);
+        assert!(health.healthy());
+        assert_eq!(health.latency, Some(Duration::from_millis(20)));
+
+        // Errors replied by aria2 do not make it unhealthy, failing to reach it does
+        let rpc_error = aria2_rs::Error::Rpc(aria2_rs::RpcError {
+            code: 1,
+            message: "GID not found".to_string(),
+        });
+        health.record_call::<()>(&Err(rpc_error));
+        assert!(health.healthy());
+        health.record_call::<()>(&Err(aria2_rs::Error::ChannelSend));
+        assert!(!health.healthy());
+    }
 }
@@ -0,0 +1,258 @@
+//! Append-only audit log of actions changing aria2 state.
+//!
+//! Every entry is a JSON object on its own line, so the file can be inspected
+//! with usual line based tools.
+
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::constants::AUDIT_READ_CHUNK_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub user_id: i64,
+    pub username: Option<String>,
+    pub server: String,
+    pub gid: Option<SmolStr>,
+    pub action: String,
+    // extra parameters of the action, e.g. the speed limit set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    // "ok" or the error
+    pub result: String,
+}
+
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    // serializes appends from concurrent handlers
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Append the entry, failures are logged without failing the action.
+    pub async fn append(&self, entry: &AuditEntry) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Unable to encode audit entry: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let lock = self.lock.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let _guard = lock.lock();
+            append_line(&path, line).map_err(|e| (path, e))
+        })
+        .await;
+        match res {
+            Ok(Ok(())) => (),
+            Ok(Err((path, e))) => {
+                tracing::error!("Unable to write audit log {}: {e}", path.display())
+            }
+            Err(e) => tracing::error!("Unable to write audit log: {e}"),
+        }
+    }
+
+    /// Last `n` entries of the server, oldest first.
+    pub async fn recent(&self, n: usize, server: &str) -> anyhow::Result<Vec<AuditEntry>> {
+        let Some(path) = self.path.clone() else {
+            return Ok(Vec::new());
+        };
+        let server = server.to_string();
+        tokio::task::spawn_blocking(move || read_recent(&path, n, &server, AUDIT_READ_CHUNK_SIZE))
+            .await?
+    }
+}
+
+/// Append a line with a single write, ending a line torn by a crash first.
+fn append_line(path: &Path, mut line: Vec<u8>) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, b'\n');
+        }
+    }
+    file.write_all(&line)
+}
+
+/// Read the file backwards in chunks until `n` entries of the server are found.
+fn read_recent(
+    path: &Path,
+    n: usize,
+    server: &str,
+    chunk_size: u64,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut pos = file.metadata()?.len();
+    let mut entries = Vec::with_capacity(n);
+    // start of a line continuing in the chunk before
+    let mut rest = Vec::new();
+    while pos > 0 && entries.len() < n {
+        let len = pos.min(chunk_size);
+        pos -= len;
+        let mut chunk = vec![0; len as usize];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut rest);
+        let mut lines: Vec<&[u8]> = chunk.split(|b| *b == b'\n').collect();
+        if pos > 0 {
+            rest = lines.remove(0).to_vec();
+        }
+        for line in lines.into_iter().rev() {
+            // Skip lines torn by a crash instead of failing the whole log
+            let Ok(entry) = serde_json::from_slice::<AuditEntry>(line) else {
+                continue;
+            };
+            if entry.server == server {
+                entries.push(entry);
+                if entries.len() == n {
+                    break;
+                }
+            }
+        }
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Records actions of a user on a server.
+pub struct Auditor<'a> {
+    log: &'a AuditLog,
+    user_id: i64,
+    username: Option<&'a str>,
+    server: &'a str,
+}
+
+impl<'a> Auditor<'a> {
+    pub fn new(
+        log: &'a AuditLog,
+        user_id: i64,
+        username: Option<&'a str>,
+        server: &'a str,
+    ) -> Self {
+        Self {
+            log,
+            user_id,
+            username,
+            server,
+        }
+    }
+
+    pub async fn record_ok(&self, action: &str, gid: Option<&str>, detail: Option<String>) {
+        self.record(action, gid, detail, &Ok::<_, String>(())).await;
+    }
+
+    pub async fn record<T, E: Display>(
+        &self,
+        action: &str,
+        gid: Option<&str>,
+        detail: Option<String>,
+        res: &Result<T, E>,
+    ) {
+        self.log
+            .append(&AuditEntry {
+                time: Utc::now(),
+                user_id: self.user_id,
+                username: self.username.map(str::to_string),
+                server: self.server.to_string(),
+                gid: gid.map(SmolStr::from),
+                action: action.to_string(),
+                detail,
+                result: match res {
+                    Ok(_) => "ok".to_string(),
+                    Err(e) => e.to_string(),
+                },
+            })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append_recent() {
+        let path = std::env::temp_dir().join(format!("telearia2-{}.jsonl", uuid::Uuid::new_v4()));
+        let log = AuditLog::new(Some(path.clone()));
+        assert!(log.recent(10, "a").await.unwrap().is_empty());
+
+        let auditor = Auditor::new(&log, 1, Some("alice"), "a");
+        auditor
+            .record_ok("pause", Some("2089b05ecca3d829"), None)
+            .await;
+        Auditor::new(&log, 2, None, "b")
+            .record_ok("purge", None, None)
+            .await;
+        // A write torn by a crash doesn't swallow the next entry
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"torn")
+            .unwrap();
+        auditor
+            .record(
+                "remove",
+                Some("2089b05ecca3d829"),
+                None,
+                &Err::<(), _>("not found"),
+            )
+            .await;
+
+        let entries = log.recent(10, "a").await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, "pause");
+        assert_eq!(entries[0].username.as_deref(), Some("alice"));
+        assert_eq!(entries[1].result, "not found");
+        let entries = log.recent(1, "a").await.unwrap();
+        assert_eq!(entries[0].action, "remove");
+
+        // Lines split across chunks are joined back
+        for chunk_size in [1, 7, 64] {
+            let entries = read_recent(&path, 10, "a", chunk_size).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].action, "remove");
+            assert_eq!(read_recent(&path, 10, "b", chunk_size).unwrap().len(), 1);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
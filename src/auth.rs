@@ -0,0 +1,134 @@
+//! Roles granted at runtime by owners or with invite codes.
+//!
+//! Grants are layered on top of the roles in the config, a grant replaces the
+//! configured role of the user on that server until it is revoked.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::role::Role;
+use crate::store::write_atomic;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthStore {
+    // server name -> user id -> granted role
+    pub grants: BTreeMap<String, BTreeMap<i64, Role>>,
+    // code -> unused invite
+    pub invites: BTreeMap<String, Invite>,
+}
+
+/// A single-use invite granting the role on the servers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub role: Role,
+    pub servers: Vec<String>,
+    // unix timestamp in seconds
+    pub expires_at: u64,
+}
+
+impl AuthStore {
+    /// Load the grants, or none if the file does not exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the grants, replacing the file atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        write_atomic(path.as_ref(), &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Grant the role, returns the previously granted one.
+    pub fn grant(&mut self, user_id: i64, server: &str, role: Role) -> Option<Role> {
+        self.grants
+            .entry(server.to_string())
+            .or_default()
+            .insert(user_id, role)
+    }
+
+    /// Revoke grants of the user on the server, or on every server when None.
+    /// Returns the number of grants removed.
+    pub fn revoke(&mut self, user_id: i64, server: Option<&str>) -> usize {
+        let mut removed = 0;
+        self.grants.retain(|name, users| {
+            if server.is_none_or(|server| server == name) && users.remove(&user_id).is_some() {
+                removed += 1;
+            }
+            !users.is_empty()
+        });
+        removed
+    }
+
+    /// Store a new invite and return its code, forgetting expired ones.
+    pub fn add_invite(&mut self, invite: Invite, now: u64) -> String {
+        self.invites.retain(|_, invite| invite.expires_at > now);
+        let code = uuid::Uuid::new_v4().simple().to_string();
+        self.invites.insert(code.clone(), invite);
+        code
+    }
+
+    /// Consume the invite if it exists and has not expired.
+    pub fn take_invite(&mut self, code: &str, now: u64) -> Option<Invite> {
+        self.invites.retain(|_, invite| invite.expires_at > now);
+        self.invites.remove(code)
+    }
+
+    /// Granted roles on the server.
+    pub fn roles(&self, server: &str) -> impl Iterator<Item = (i64, Role)> + '_ {
+        self.grants
+            .get(server)
+            .into_iter()
+            .flat_map(|users| users.iter().map(|(&user, &role)| (user, role)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grant_revoke() {
+        let mut store = AuthStore::default();
+        assert_eq!(store.grant(1, "a", Role::Viewer), None);
+        assert_eq!(store.grant(1, "a", Role::Operator), Some(Role::Viewer));
+        store.grant(1, "b", Role::Admin);
+        store.grant(2, "b", Role::Viewer);
+        assert_eq!(store.roles("a").collect::<Vec<_>>(), [(1, Role::Operator)]);
+
+        assert_eq!(store.revoke(1, Some("b")), 1);
+        assert_eq!(store.roles("b").collect::<Vec<_>>(), [(2, Role::Viewer)]);
+        assert_eq!(store.revoke(1, None), 1);
+        assert_eq!(store.revoke(1, None), 0);
+        // Servers without grants are dropped
+        assert!(!store.grants.contains_key("a"));
+
+        let path = std::env::temp_dir().join(format!("telearia2-{}.json", uuid::Uuid::new_v4()));
+        store.save(&path).unwrap();
+        let loaded = AuthStore::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, store);
+    }
+
+    #[test]
+    fn test_invite() {
+        let mut store = AuthStore::default();
+        let invite = Invite {
+            role: Role::Operator,
+            servers: vec!["a".to_string()],
+            expires_at: 100,
+        };
+        let code = store.add_invite(invite.clone(), 0);
+        let expired = store.add_invite(invite.clone(), 0);
+        assert_eq!(store.take_invite("unknown", 0), None);
+        assert_eq!(store.take_invite(&code, 99), Some(invite));
+        // Single use
+        assert_eq!(store.take_invite(&code, 99), None);
+        assert_eq!(store.take_invite(&expired, 100), None);
+        assert!(store.invites.is_empty());
+    }
+}
@@ -1,7 +1,9 @@
-use std::path::Path;
+use std::{collections::HashMap, net::SocketAddr, path::Path};
 
 use serde::Deserialize;
+use teloxide::update_listeners::webhooks;
 
+use crate::role::Role;
 use crate::utils::SingleMultiMap;
 
 pub trait Param<T> {
@@ -21,6 +23,7 @@ pub struct Config {
     pub aria2: Aria2ConfigGroup,
     pub telegram: TelegramConfig,
     pub download: DownloadConfig,
+    pub metrics: Option<MetricsConfig>,
 }
 
 impl Config {
@@ -38,17 +41,129 @@ pub struct Aria2Config {
     pub channel_buffer_size: Option<usize>,
     pub interval_secs: Option<u64>,
     pub admins_override: Option<Vec<i64>>,
+    pub operators_override: Option<Vec<i64>>,
+    pub viewers_override: Option<Vec<i64>>,
     pub download_override: Option<DownloadConfig>,
 }
 
+impl Aria2Config {
+    /// Users of this server with their role, the highest one wins.
+    pub fn roles(&self, telegram: &TelegramConfig) -> HashMap<i64, Role> {
+        let mut roles = HashMap::new();
+        for (users, role) in [
+            (
+                self.viewers_override.as_ref().unwrap_or(&telegram.viewers),
+                Role::Viewer,
+            ),
+            (
+                self.operators_override
+                    .as_ref()
+                    .unwrap_or(&telegram.operators),
+                Role::Operator,
+            ),
+            (
+                self.admins_override.as_ref().unwrap_or(&telegram.admins),
+                Role::Admin,
+            ),
+        ] {
+            for &user in users {
+                roles.insert(user, role);
+            }
+        }
+        roles
+    }
+
+    /// Whether the other config connects the same way, so the client can be kept.
+    pub fn same_connection(&self, other: &Self) -> bool {
+        self.rpc_url == other.rpc_url
+            && self.token == other.token
+            && self.channel_buffer_size == other.channel_buffer_size
+            && self.interval_secs == other.interval_secs
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct TelegramConfig {
     pub token: String,
+    // full access, including remove, purge and option changes
     pub admins: Vec<i64>,
+    // can add, pause, resume and retry tasks
+    #[serde(default)]
+    pub operators: Vec<i64>,
+    // can only see tasks and stats
+    #[serde(default)]
+    pub viewers: Vec<i64>,
+    // groups whose members are authorized by their own user id
+    #[serde(default)]
+    pub allowed_groups: Vec<i64>,
+    // users who can grant and revoke roles at runtime
+    #[serde(default)]
+    pub owners: Vec<i64>,
+    // file to persist roles granted at runtime
+    pub auth_path: Option<String>,
+    // JSON Lines file recording actions changing aria2 state
+    pub audit_path: Option<String>,
     pub subscribe_expire_secs: Option<u64>,
+    // extra chat notified when any task completes or fails
+    pub notify_chat: Option<i64>,
+    // file to persist bot state across restarts
+    pub state_path: Option<String>,
+    // receive updates with a webhook instead of long polling
+    pub webhook: Option<WebhookConfig>,
+    // Bot API server, e.g. a self-hosted one with higher file size limits
+    pub api_url: Option<String>,
+    // whether the Bot API server runs with --local, handing out files by local path
+    #[serde(default)]
+    pub local_api: bool,
+    // largest file sent to chats, 50 MiB with the public Bot API and 2000 MiB with a local one
+    pub upload_limit_mb: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct MetricsConfig {
+    // address serving Prometheus metrics on /metrics, e.g. 127.0.0.1:9100
+    pub listen: SocketAddr,
 }
 
 #[derive(Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    // local address to listen on, e.g. 0.0.0.0:8080
+    pub listen: SocketAddr,
+    // public url Telegram sends updates to, forwarded to `listen`
+    pub url: String,
+    // file holding the secret token Telegram sends with each update, random when unset
+    pub secret_token_path: Option<String>,
+}
+
+impl WebhookConfig {
+    pub fn options(&self) -> anyhow::Result<webhooks::Options> {
+        let url = self
+            .url
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid webhook url {}: {e}", self.url))?;
+        let mut options = webhooks::Options::new(self.listen, url);
+        if let Some(path) = self.secret_token_path.as_ref() {
+            let token = std::fs::read_to_string(path)?.trim().to_string();
+            if !valid_secret_token(&token) {
+                anyhow::bail!(
+                    "webhook secret token in {path} must be 1-256 characters of A-Z, a-z, 0-9, _ and -"
+                );
+            }
+            options = options.secret_token(token);
+        }
+        Ok(options)
+    }
+}
+
+/// Whether Telegram accepts the token as a webhook secret token.
+fn valid_secret_token(token: &str) -> bool {
+    (1..=256).contains(&token.len())
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct DownloadConfig {
     // name -> path
     pub magnet_dirs: Vec<DirConfig>,
@@ -57,9 +172,21 @@ pub struct DownloadConfig {
     // name -> path
     pub link_dirs: Vec<DirConfig>,
     pub default_dir: String,
+    // opt-in removal of downloaded files with their task
+    pub delete_files: Option<LocalFilesConfig>,
+    // opt-in sending of completed files to the chat
+    pub upload_files: Option<LocalFilesConfig>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LocalFilesConfig {
+    // only files under this directory are accessed, as seen by telearia2
+    pub root: String,
+    // the same directory as seen by aria2, when mounted elsewhere
+    pub aria2_root: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct DirConfig {
     pub name: String,
     pub path: String,
@@ -166,4 +293,78 @@ default_dir = "/data"
         assert_eq!(config.download.magnet_dirs[0].name, "Movies");
         assert_eq!(config.download.magnet_dirs[0].path, "/data/movies");
     }
+
+    #[test]
+    fn test_same_connection() {
+        let toml = r#"
+rpc_url = "wss://example.org/jsonrpc"
+token = "secret"
+"#;
+        let config: Aria2Config = toml::from_str(toml).unwrap();
+        let mut other = config.clone();
+        other.admins_override = Some(vec![123]);
+        assert!(config.same_connection(&other));
+        other.token = "changed".to_string();
+        assert!(!config.same_connection(&other));
+    }
+
+    #[test]
+    fn test_parse_webhook_config() {
+        let toml = r#"
+token = "bot_token"
+admins = [123]
+
+[webhook]
+listen = "0.0.0.0:8080"
+url = "https://bot.example.org/telegram"
+"#;
+        let config: TelegramConfig = toml::from_str(toml).unwrap();
+        let webhook = config.webhook.unwrap();
+        assert_eq!(webhook.listen.port(), 8080);
+        let options = webhook.options().unwrap();
+        assert_eq!(options.url.as_str(), "https://bot.example.org/telegram");
+        assert!(options.secret_token.is_none());
+    }
+
+    #[test]
+    fn test_valid_secret_token() {
+        assert!(valid_secret_token("abc_DEF-123"));
+        assert!(!valid_secret_token(""));
+        assert!(!valid_secret_token("with space"));
+        assert!(!valid_secret_token(&"a".repeat(257)));
+    }
+
+    #[test]
+    fn test_roles() {
+        let toml = r#"
+[aria2]
+rpc_url = "wss://example.org/jsonrpc"
+token = "secret"
+operators_override = [2, 3]
+
+[telegram]
+token = "bot_token"
+admins = [1]
+operators = [4]
+viewers = [3, 5]
+
+[download]
+magnet_dirs = []
+torrent_dirs = []
+link_dirs = []
+default_dir = "/data"
+"#;
+        let config: Config = toml::from_str(toml).unwrap();
+        let crate::utils::SingleMultiMap::Single(aria2) = &config.aria2 else {
+            panic!("expect single aria2 config");
+        };
+        let roles = aria2.roles(&config.telegram);
+        assert_eq!(roles.get(&1), Some(&Role::Admin));
+        assert_eq!(roles.get(&2), Some(&Role::Operator));
+        // The highest role wins
+        assert_eq!(roles.get(&3), Some(&Role::Operator));
+        // Replaced by the override
+        assert_eq!(roles.get(&4), None);
+        assert_eq!(roles.get(&5), Some(&Role::Viewer));
+    }
 }
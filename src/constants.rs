@@ -18,6 +18,24 @@ pub const ARIA2_MAX_RETRIES: u32 = 3;
 /// Delay between aria2 operation retries
 pub const ARIA2_RETRY_DELAY: Duration = Duration::from_millis(100);
 
+/// Buffer size of the aria2 notification channel
+pub const ARIA2_EVENT_BUFFER_SIZE: usize = 256;
+
+/// Timeout for establishing the aria2 websocket connection
+pub const ARIA2_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of checking whether a new aria2 client has connected
+pub const ARIA2_CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// First delay before retrying a failed aria2 connection, doubled on each failure
+pub const ARIA2_RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between aria2 connection attempts
+pub const ARIA2_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Interval between aria2 server health checks
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 // ============================================================================
 // Task Cache Settings
 // ============================================================================
@@ -28,15 +46,30 @@ pub const DEFAULT_SUBSCRIBER_EXPIRE: Duration = Duration::from_secs(3 * 60);
 /// Timeout for refreshing task cache from aria2
 pub const REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Interval between background task cache refreshes
+/// Interval between background progress polls of subscribed tasks
 pub const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Interval between full task list syncs, in case notifications were missed
+pub const FULL_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Expiration time for cached task data
 pub const CACHE_EXPIRE: Duration = Duration::from_secs(3);
 
+/// Number of recent download speed samples averaged for the ETA
+pub const SPEED_SAMPLE_COUNT: usize = 5;
+
 /// Size of the LRU cache for URI and file mappings
 pub const URI_LRU_SIZE: usize = 4096;
 
+/// Number of uploaded torrent files kept in memory between parsing and adding them
+pub const TORRENT_DATA_LRU_SIZE: usize = 32;
+
+/// Interval between checks of the config file for changes
+pub const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Interval between saves of the bot state, when a state file is configured
+pub const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
 // ============================================================================
 // Telegram/Download Settings
 // ============================================================================
@@ -44,5 +77,69 @@ pub const URI_LRU_SIZE: usize = 4096;
 /// Maximum torrent file size (1 MiB)
 pub const MAX_TORRENT_SIZE: u32 = 1024 * 1024;
 
+/// Maximum torrent file size with a local Bot API server, read from disk instead of downloaded
+pub const LOCAL_MAX_TORRENT_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Validity of the confirm button of remove and purge
+pub const CONFIRM_EXPIRE: Duration = Duration::from_secs(10 * 60);
+
+/// Time for aria2 to stop a removed task before its files are deleted
+pub const REMOVE_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of checking whether a removed task has stopped
+pub const REMOVE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Largest file sent to chats, the limit of the public Bot API
+pub const DEFAULT_UPLOAD_LIMIT: u64 = 50 * 1024 * 1024;
+
+/// Largest file sent to chats, the limit of a local Bot API server
+pub const LOCAL_UPLOAD_LIMIT: u64 = 2000 * 1024 * 1024;
+
+/// Connect timeout of sending files, which have no overall timeout
+pub const UPLOAD_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of updating the progress of files being sent
+pub const UPLOAD_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Entries shown by /audit without a count
+pub const AUDIT_DEFAULT_ENTRIES: usize = 20;
+
+/// Most entries shown by /audit, older ones are left out past the message length
+pub const AUDIT_MAX_ENTRIES: usize = 50;
+
+/// Maximum length of the detail and result of an audit entry in /audit
+pub const MAX_AUDIT_DETAIL_LEN: usize = 80;
+
+/// Longest text of a Telegram message, in UTF-16 code units
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Bytes read at a time when reading the audit log backwards
+pub const AUDIT_READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Validity of invite codes
+pub const INVITE_EXPIRE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of largest files listed when confirming a torrent
+pub const TORRENT_TOP_FILES: usize = 5;
+
+/// Maximum number of per-task outcomes listed after a bulk action
+pub const MAX_BULK_RESULT_LINES: usize = 30;
+
+/// Speed limit presets in bytes/sec offered as buttons, 0 means unrestricted
+pub const SPEED_LIMIT_PRESETS: [u64; 6] = [
+    0,
+    512 * 1024,
+    1024 * 1024,
+    5 * 1024 * 1024,
+    10 * 1024 * 1024,
+    50 * 1024 * 1024,
+];
+
 /// Maximum length for brief task names in UI
 pub const MAX_BRIEF_NAME_LEN: usize = 40;
+
+/// Number of cells of the progress bar in task details
+pub const PROGRESS_BAR_WIDTH: usize = 20;
+
+/// Number of cells of the progress bar in the task list
+pub const BRIEF_PROGRESS_BAR_WIDTH: usize = 5;
@@ -0,0 +1,285 @@
+//! Local access to downloaded files, for deleting and sending them.
+//!
+//! aria2 never deletes or hands out files itself, so telearia2 does it when it
+//! shares the download directory. Paths reported by aria2 are untrusted: they
+//! are mapped into the configured root and anything resolving outside of it is
+//! refused.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::config::LocalFilesConfig;
+
+/// Suffix of the control file aria2 keeps next to unfinished downloads.
+const CONTROL_FILE_SUFFIX: &str = ".aria2";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct DeleteReport {
+    pub deleted: Vec<PathBuf>,
+    // files which were already gone
+    pub missing: usize,
+    // paths outside of the root
+    pub refused: Vec<String>,
+    // path, error
+    pub failed: Vec<(PathBuf, String)>,
+    // directories left empty and removed
+    pub dirs: usize,
+}
+
+enum Outcome {
+    Deleted(PathBuf),
+    Missing,
+    Refused,
+    Failed(PathBuf, String),
+}
+
+/// Delete the files, with aria2 control files, and the directories left empty.
+pub fn delete_files<'a>(
+    config: &LocalFilesConfig,
+    paths: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<DeleteReport> {
+    let root = std::fs::canonicalize(&config.root)
+        .map_err(|e| anyhow::anyhow!("unable to resolve {}: {e}", config.root))?;
+    // Paths are reported as configured, before resolving symlinks
+    let aria2_root = Path::new(config.aria2_root.as_deref().unwrap_or(&config.root));
+
+    let mut report = DeleteReport::default();
+    for path in paths.into_iter().filter(|path| !path.is_empty()) {
+        let Some(local) = map_path(&root, aria2_root, Path::new(path)) else {
+            report.refused.push(path.to_string());
+            continue;
+        };
+        match delete_file(&root, &local) {
+            Outcome::Deleted(path) => report.deleted.push(path),
+            Outcome::Missing => report.missing += 1,
+            Outcome::Refused => report.refused.push(path.to_string()),
+            Outcome::Failed(path, e) => report.failed.push((path, e)),
+        }
+        let mut control = local.into_os_string();
+        control.push(CONTROL_FILE_SUFFIX);
+        if let Outcome::Deleted(path) = delete_file(&root, Path::new(&control)) {
+            report.deleted.push(path);
+        }
+    }
+
+    let mut dirs: Vec<&Path> = report.deleted.iter().filter_map(|p| p.parent()).collect();
+    // Deepest first, so parents are empty by the time they are reached
+    dirs.sort_unstable_by(|a, b| {
+        let depth = |dir: &Path| dir.components().count();
+        depth(b).cmp(&depth(a)).then(a.cmp(b))
+    });
+    dirs.dedup();
+    let mut removed = 0;
+    for dir in dirs {
+        for dir in dir.ancestors().take_while(|dir| *dir != root) {
+            // Fails on directories which are not empty, or already removed
+            if std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+            removed += 1;
+        }
+    }
+    report.dirs = removed;
+    Ok(report)
+}
+
+/// Resolve a file to read, following symlinks as long as they stay under the root.
+/// Returns the local path and the file size.
+pub fn resolve_file(config: &LocalFilesConfig, path: &str) -> anyhow::Result<(PathBuf, u64)> {
+    let root = std::fs::canonicalize(&config.root)
+        .map_err(|e| anyhow::anyhow!("unable to resolve {}: {e}", config.root))?;
+    let aria2_root = Path::new(config.aria2_root.as_deref().unwrap_or(&config.root));
+    let local = map_path(&root, aria2_root, Path::new(path))
+        .and_then(|local| std::fs::canonicalize(local).ok())
+        .filter(|local| local.starts_with(&root))
+        .ok_or_else(|| anyhow::anyhow!("not found under {}", config.root))?;
+    let meta = std::fs::metadata(&local)?;
+    if !meta.is_file() {
+        anyhow::bail!("not a file");
+    }
+    Ok((local, meta.len()))
+}
+
+/// Reader counting the bytes read, for progress of uploads.
+pub struct ProgressReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R) -> (Self, Arc<AtomicU64>) {
+        let read = Arc::new(AtomicU64::new(0));
+        let reader = Self {
+            inner,
+            read: read.clone(),
+        };
+        (reader, read)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.read.fetch_add(read, Ordering::Relaxed);
+        res
+    }
+}
+
+/// Map a path seen by aria2 to the local one, lexically, None when it escapes the root.
+fn map_path(root: &Path, aria2_root: &Path, path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    let relative = path.strip_prefix(aria2_root).ok()?;
+    if relative.as_os_str().is_empty() {
+        return None;
+    }
+    Some(root.join(relative))
+}
+
+/// Delete a single file whose parent directory resolves under the root.
+fn delete_file(root: &Path, path: &Path) -> Outcome {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Outcome::Refused;
+    };
+    // Resolving the parent follows symlinked directories, the file itself is
+    // not followed so a symlink is deleted rather than its target
+    let parent = match std::fs::canonicalize(parent) {
+        Ok(parent) => parent,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Outcome::Missing,
+        Err(e) => return Outcome::Failed(path.to_path_buf(), e.to_string()),
+    };
+    if !parent.starts_with(root) {
+        return Outcome::Refused;
+    }
+    let path = parent.join(name);
+    match std::fs::symlink_metadata(&path) {
+        Ok(meta) if meta.is_dir() => Outcome::Refused,
+        Ok(_) => match std::fs::remove_file(&path) {
+            Ok(()) => Outcome::Deleted(path),
+            Err(e) => Outcome::Failed(path, e.to_string()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Outcome::Missing,
+        Err(e) => Outcome::Failed(path, e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_path() {
+        let root = Path::new("/data");
+        let map =
+            |aria2_root: &str, path: &str| map_path(root, Path::new(aria2_root), Path::new(path));
+        assert_eq!(map("/data/", "/data/a/b"), Some(PathBuf::from("/data/a/b")));
+        assert_eq!(
+            map("/downloads", "/downloads/a"),
+            Some(PathBuf::from("/data/a"))
+        );
+        assert_eq!(map("/downloads", "/data/a"), None);
+        assert_eq!(map("/data", "/data"), None);
+        assert_eq!(map("/data", "/data2/a"), None);
+        assert_eq!(map("/data", "/data/../etc/passwd"), None);
+        assert_eq!(map("/data", "data/a"), None);
+    }
+
+    // Creating symlinks needs extra privileges on Windows
+    #[cfg(unix)]
+    #[test]
+    fn test_delete_files() {
+        let base = std::env::temp_dir().join(format!("telearia2-{}", uuid::Uuid::new_v4()));
+        let root = base.join("root");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(root.join("show/s1")).unwrap();
+        std::fs::create_dir_all(root.join("kept")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        for file in [
+            "show/s1/e1",
+            "show/s1/e1.aria2",
+            "show/s1/e2",
+            "kept/a",
+            "kept/b",
+        ] {
+            std::fs::write(root.join(file), b"x").unwrap();
+        }
+        std::fs::write(outside.join("secret"), b"x").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+        let config = LocalFilesConfig {
+            root: root.to_string_lossy().into_owned(),
+            aria2_root: Some("/downloads".to_string()),
+        };
+        let paths = [
+            "/downloads/show/s1/e1",
+            "/downloads/show/s1/e2",
+            "/downloads/show/s1/e3",
+            "/downloads/kept/a",
+            "/downloads/link/secret",
+            "/downloads/show",
+            "/etc/passwd",
+            "",
+        ];
+        let report = delete_files(&config, paths).unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+        let deleted = ["show/s1/e1", "show/s1/e1.aria2", "show/s1/e2", "kept/a"];
+        assert_eq!(report.deleted, deleted.map(|file| root.join(file)).to_vec());
+        assert_eq!(report.missing, 1);
+        assert_eq!(
+            report.refused,
+            ["/downloads/link/secret", "/downloads/show", "/etc/passwd"]
+        );
+        assert!(report.failed.is_empty());
+        // show/s1 and show, kept still has a file
+        assert_eq!(report.dirs, 2);
+        assert!(!root.join("show").exists());
+        assert!(root.join("kept/b").exists());
+        assert!(outside.join("secret").exists());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_file() {
+        let base = std::env::temp_dir().join(format!("telearia2-{}", uuid::Uuid::new_v4()));
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::write(root.join("dir/a"), b"abc").unwrap();
+        std::fs::write(base.join("secret"), b"x").unwrap();
+        std::os::unix::fs::symlink(root.join("dir/a"), root.join("inside")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret"), root.join("outside")).unwrap();
+
+        let config = LocalFilesConfig {
+            root: root.to_string_lossy().into_owned(),
+            aria2_root: None,
+        };
+        let resolve = |path: &Path| resolve_file(&config, path.to_str().unwrap());
+        let canonical = std::fs::canonicalize(root.join("dir/a")).unwrap();
+        assert_eq!(
+            resolve(&root.join("dir/a")).unwrap(),
+            (canonical.clone(), 3)
+        );
+        assert_eq!(resolve(&root.join("inside")).unwrap(), (canonical, 3));
+        assert!(resolve(&root.join("outside")).is_err());
+        assert!(resolve(&root.join("dir")).is_err());
+        assert!(resolve(&root.join("missing")).is_err());
+        assert!(resolve(&base.join("secret")).is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
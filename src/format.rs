@@ -1,15 +1,24 @@
 use std::{
     fmt::{Error, Formatter},
     sync::Arc,
+    time::Duration,
 };
 
 use aria2_rs::status::{BittorrentStatus, Status, TaskStatus};
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
 
+use crate::aria2::{error_code_info, LimitKind, SpeedLimits};
 use crate::config::DirConfig;
-use crate::constants::MAX_BRIEF_NAME_LEN;
+use crate::constants::{
+    BRIEF_PROGRESS_BAR_WIDTH, MAX_BRIEF_NAME_LEN, PROGRESS_BAR_WIDTH, SPEED_LIMIT_PRESETS,
+};
+use crate::{
+    role::Role,
+    state::{BulkAction, TaskFilter},
+};
 
 pub const TASK_LIST_PAGE_SIZE: usize = 10;
+pub const FILE_LIST_PAGE_SIZE: usize = 8;
 
 pub trait MessageFmt {
     fn fmt_message<const DETAILED: bool>(&self, f: &mut Formatter<'_>) -> Result<(), Error>;
@@ -74,6 +83,22 @@ impl MessageFmt for Status {
             let dir = self.dir.as_deref().unwrap_or("Unknown");
             writeln!(f, "Dir: {dir}")?;
 
+            // Error
+            if self.status == Some(TaskStatus::Error) {
+                let code = self.error_code.as_deref().unwrap_or("Unknown");
+                let info = error_code_info(code);
+                match info {
+                    Some((explanation, _)) => writeln!(f, "Error: {explanation} (code {code})")?,
+                    None => writeln!(f, "Error: code {code}")?,
+                }
+                if let Some(message) = self.error_message.as_deref() {
+                    writeln!(f, "Message: {message}")?;
+                }
+                if let Some((_, remedy)) = info {
+                    writeln!(f, "Hint: {remedy}")?;
+                }
+            }
+
             if self.status == Some(TaskStatus::Active) {
                 // Seeder count
                 if let (Some(seed_cnt), Some(conn_cnt)) = (self.num_seeders, self.connections) {
@@ -99,6 +124,11 @@ impl MessageFmt for Status {
                 SizeFormatter(progress_size.0),
                 SizeFormatter(progress_size.1)
             )?;
+            writeln!(
+                f,
+                "[{}]",
+                ProgressBar::<PROGRESS_BAR_WIDTH>(self.progress())
+            )?;
         } else {
             let status = match &self.status {
                 Some(TaskStatus::Active) => "⏬",
@@ -115,7 +145,8 @@ impl MessageFmt for Status {
             ) {
                 write!(
                     f,
-                    "{status}|{:.3}%|{}/{}|{}",
+                    "{status}|{}|{:.3}%|{}/{}|{}",
+                    ProgressBar::<BRIEF_PROGRESS_BAR_WIDTH>(self.progress()),
                     self.progress() * 100.,
                     SizeFormatter(progress_size.0),
                     SizeFormatter(progress_size.1),
@@ -143,6 +174,39 @@ impl MessageFmt for Status {
     }
 }
 
+/// A task with what the cache knows about it beyond aria2 status.
+pub struct TaskDetail<'a> {
+    pub status: &'a Status,
+    pub limits: Option<&'a SpeedLimits>,
+    // Estimated time remaining, from smoothed download speed
+    pub eta: Option<Duration>,
+    // Average download speed, for completed tasks
+    pub average_speed: Option<u64>,
+}
+
+impl MessageFmt for TaskDetail<'_> {
+    fn fmt_message<const DETAILED: bool>(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        self.status.fmt_message::<DETAILED>(f)?;
+        if DETAILED {
+            if let Some(eta) = self.eta {
+                writeln!(f, "ETA: {}", DurationFormatter(eta))?;
+            }
+            if let Some(speed) = self.average_speed {
+                writeln!(f, "Average Speed: {}/s", SizeFormatter(speed))?;
+            }
+            if let Some(limits) = self.limits {
+                writeln!(
+                    f,
+                    "Speed Limit: ⬆ {} | ⬇ {}",
+                    LimitFormatter(limits.upload),
+                    LimitFormatter(limits.download)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub trait TaskExt {
     fn name(&self) -> &str;
     fn progress(&self) -> f64;
@@ -212,6 +276,50 @@ impl std::fmt::Display for SizeFormatter {
     }
 }
 
+/// Coarse human readable duration, e.g. `2h 5m`.
+struct DurationFormatter(Duration);
+impl std::fmt::Display for DurationFormatter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let secs = self.0.as_secs();
+        let (days, hours, minutes, seconds) =
+            (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+        if days > 0 {
+            write!(f, "{days}d {hours}h")
+        } else if hours > 0 {
+            write!(f, "{hours}h {minutes}m")
+        } else if minutes > 0 {
+            write!(f, "{minutes}m {seconds}s")
+        } else {
+            write!(f, "{seconds}s")
+        }
+    }
+}
+
+/// Unicode progress bar of `N` cells for a progress between 0 and 1.
+struct ProgressBar<const N: usize>(f64);
+impl<const N: usize> std::fmt::Display for ProgressBar<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let filled = ((self.0.clamp(0.0, 1.0) * N as f64).round() as usize).min(N);
+        for _ in 0..filled {
+            f.write_str("█")?;
+        }
+        for _ in filled..N {
+            f.write_str("░")?;
+        }
+        Ok(())
+    }
+}
+
+struct LimitFormatter(u64);
+impl std::fmt::Display for LimitFormatter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self.0 {
+            0 => f.write_str("Unlimited"),
+            speed => write!(f, "{}/s", SizeFormatter(speed)),
+        }
+    }
+}
+
 pub fn task_list_page_count(total_tasks: usize, page_size: usize) -> usize {
     total_tasks.max(1).div_ceil(page_size)
 }
@@ -220,6 +328,8 @@ pub fn make_tasks_keyboard(
     tasks: Vec<(String, String)>,
     page: usize,
     page_size: usize,
+    filter: &TaskFilter,
+    role: Role,
 ) -> InlineKeyboardMarkup {
     let total_pages = task_list_page_count(tasks.len(), page_size);
     let page = page.min(total_pages.saturating_sub(1));
@@ -233,29 +343,129 @@ pub fn make_tasks_keyboard(
         .collect();
 
     if total_pages > 1 {
-        keyboard.push(vec![
-            InlineKeyboardButton::callback("⬅️", format!("task_page|{}", page.saturating_sub(1))),
-            InlineKeyboardButton::callback(
-                format!("{}/{}", page + 1, total_pages),
-                "task_page_info",
-            ),
-            InlineKeyboardButton::callback(
-                "➡️",
-                format!("task_page|{}", (page + 1).min(total_pages - 1)),
-            ),
-        ]);
+        let filter = filter.to_callback();
+        keyboard.push(make_page_row(page, total_pages, |p| {
+            format!("task_page|{p}|{filter}")
+        }));
+    }
+    keyboard.push(make_filter_row(filter));
+    let bulk_row = make_bulk_row(filter, role);
+    if !bulk_row.is_empty() {
+        keyboard.push(bulk_row);
     }
 
     InlineKeyboardMarkup::new(keyboard)
 }
 
-pub fn make_refresh_list_keyboard(page: usize) -> InlineKeyboardMarkup {
+fn make_bulk_row(filter: &TaskFilter, role: Role) -> Vec<InlineKeyboardButton> {
+    let actions = match filter {
+        TaskFilter::Error => [
+            ("🗑 Remove all", BulkAction::RemoveErrored),
+            ("🔁 Retry all", BulkAction::RetryErrored),
+        ],
+        _ => [
+            ("⏸ Pause all", BulkAction::PauseAll),
+            ("▶️ Resume all", BulkAction::ResumeAll),
+        ],
+    };
+    actions
+        .into_iter()
+        .filter(|(_, action)| role >= action.required_role())
+        .map(|(text, action)| {
+            InlineKeyboardButton::callback(text, format!("bulk|{}", action.to_callback()))
+        })
+        .collect()
+}
+
+fn make_filter_row(current: &TaskFilter) -> Vec<InlineKeyboardButton> {
+    [
+        ("All", TaskFilter::All),
+        ("Active", TaskFilter::Active),
+        ("Error", TaskFilter::Error),
+        ("Complete", TaskFilter::Complete),
+    ]
+    .into_iter()
+    .map(|(name, filter)| {
+        let text = if &filter == current {
+            format!("🔹{name}")
+        } else {
+            name.to_string()
+        };
+        InlineKeyboardButton::callback(text, format!("tfilter|{}", filter.to_callback()))
+    })
+    .collect()
+}
+
+fn make_page_row(
+    page: usize,
+    total_pages: usize,
+    goto: impl Fn(usize) -> String,
+) -> Vec<InlineKeyboardButton> {
+    vec![
+        InlineKeyboardButton::callback("⬅️", goto(page.saturating_sub(1))),
+        InlineKeyboardButton::callback(format!("{}/{}", page + 1, total_pages), "task_page_info"),
+        InlineKeyboardButton::callback("➡️", goto((page + 1).min(total_pages - 1))),
+    ]
+}
+
+/// A file shown in the file selection keyboard.
+pub struct FileEntry<'a> {
+    pub path: &'a str,
+    pub length: u64,
+    pub selected: bool,
+}
+
+/// Paginated file toggles, `toggle` and `goto` build callbacks from the file
+/// position and page number.
+pub fn make_files_keyboard(
+    files: &[FileEntry<'_>],
+    page: usize,
+    toggle: impl Fn(usize) -> String,
+    goto: impl Fn(usize) -> String,
+    done: (&str, String),
+) -> InlineKeyboardMarkup {
+    let total_pages = task_list_page_count(files.len(), FILE_LIST_PAGE_SIZE);
+    let page = page.min(total_pages.saturating_sub(1));
+    let start = page.saturating_mul(FILE_LIST_PAGE_SIZE);
+    let end = (start + FILE_LIST_PAGE_SIZE).min(files.len());
+
+    let mut keyboard: Vec<Vec<InlineKeyboardButton>> = files[start..end]
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let name = file.path.rsplit('/').next().unwrap_or(file.path);
+            let desc = format!(
+                "{}|{}|{}",
+                if file.selected { "✅" } else { "⬜" },
+                SizeFormatter(file.length),
+                name.chars().take(MAX_BRIEF_NAME_LEN).collect::<String>()
+            );
+            vec![InlineKeyboardButton::callback(desc, toggle(start + i))]
+        })
+        .collect();
+
+    if total_pages > 1 {
+        keyboard.push(make_page_row(page, total_pages, goto));
+    }
+    keyboard.push(vec![InlineKeyboardButton::callback(done.0, done.1)]);
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+pub fn make_refresh_list_keyboard(page: usize, filter: &TaskFilter) -> InlineKeyboardMarkup {
     InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
         "🔄 Refresh",
-        format!("rlist|{page}"),
+        format!("rlist|{page}|{}", filter.to_callback()),
     )]])
 }
 
+pub fn make_confirm_keyboard(confirm_callback: String) -> InlineKeyboardMarkup {
+    let cancel_callback = format!("cancel|{confirm_callback}");
+    InlineKeyboardMarkup::new(vec![vec![
+        InlineKeyboardButton::callback("✅ Confirm", confirm_callback),
+        InlineKeyboardButton::callback("✖️ Cancel", cancel_callback),
+    ]])
+}
+
 pub fn make_retry_keyboard(callback_data: String) -> InlineKeyboardMarkup {
     InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
         "🔄 Retry",
@@ -270,13 +480,26 @@ pub fn make_refresh_task_keyboard(gid: &str) -> InlineKeyboardMarkup {
     )]])
 }
 
+pub fn make_refresh_stats_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback(
+        "🔄 Refresh",
+        "stats|",
+    )]])
+}
+
+/// Server buttons, unhealthy servers are marked with a warning sign.
 pub fn make_switch_server_keyboard<'a>(
-    servers: impl Iterator<Item = &'a str>,
+    servers: impl Iterator<Item = (&'a str, bool)>,
 ) -> InlineKeyboardMarkup {
     let keyboard: Vec<_> = servers
-        .map(|server| {
+        .map(|(server, healthy)| {
+            let text = if healthy {
+                server.to_string()
+            } else {
+                format!("⚠️ {server}")
+            };
             vec![InlineKeyboardButton::callback(
-                server,
+                text,
                 format!("switch|{server}"),
             )]
         })
@@ -284,28 +507,102 @@ pub fn make_switch_server_keyboard<'a>(
     InlineKeyboardMarkup::new(keyboard)
 }
 
-pub fn make_single_task_keyboard(gid: &str, status: TaskStatus) -> InlineKeyboardMarkup {
+/// Actions on a task, leaving out the ones the role may not use.
+pub fn make_single_task_keyboard(
+    gid: &str,
+    status: TaskStatus,
+    role: Role,
+    // whether removing can also delete the downloaded files
+    delete_files: bool,
+    // whether completed files can be sent to the chat
+    upload_files: bool,
+) -> InlineKeyboardMarkup {
     const RESUME: &str = "▶️ Resume";
     const PAUSE: &str = "⏸ Pause";
     const REMOVE: &str = "⏹ Remove";
-
-    let bs = match status {
-        TaskStatus::Active | TaskStatus::Waiting => vec![
-            InlineKeyboardButton::callback(PAUSE, format!("pause|{gid}")),
-            InlineKeyboardButton::callback(REMOVE, format!("remove|{gid}")),
-        ],
-        TaskStatus::Paused => vec![
-            InlineKeyboardButton::callback(RESUME, format!("resume|{gid}")),
-            InlineKeyboardButton::callback(REMOVE, format!("remove|{gid}")),
-        ],
-        TaskStatus::Error | TaskStatus::Complete => vec![InlineKeyboardButton::callback(
+    const REMOVE_FILES: &str = "🗑 Remove + files";
+    const UPLOAD: &str = "📤 Send to chat";
+    const FILES: &str = "📂 Files";
+    const LIMIT: &str = "🚦 Speed limit";
+    const RETRY: &str = "🔁 Retry";
+
+    let action = match status {
+        TaskStatus::Active | TaskStatus::Waiting => Some((PAUSE, "pause")),
+        TaskStatus::Paused => Some((RESUME, "resume")),
+        TaskStatus::Error => Some((RETRY, "retry")),
+        TaskStatus::Complete | TaskStatus::Removed => None,
+    };
+    let mut bs = vec![];
+    if let Some((text, action)) = action.filter(|_| role >= Role::Operator) {
+        bs.push(InlineKeyboardButton::callback(
+            text,
+            format!("{action}|{gid}"),
+        ));
+    }
+    if status != TaskStatus::Removed && role >= Role::Admin {
+        bs.push(InlineKeyboardButton::callback(
             REMOVE,
             format!("remove|{gid}"),
-        )],
-        TaskStatus::Removed => vec![],
+        ));
+    }
+    if delete_files && role >= Role::Admin {
+        bs.push(InlineKeyboardButton::callback(
+            REMOVE_FILES,
+            format!("rmfiles|{gid}"),
+        ));
+    }
+
+    let mut keyboard = vec![bs];
+    if role >= Role::Admin
+        && matches!(
+            status,
+            TaskStatus::Active | TaskStatus::Waiting | TaskStatus::Paused
+        )
+    {
+        keyboard.push(vec![
+            InlineKeyboardButton::callback(FILES, format!("files|{gid}")),
+            InlineKeyboardButton::callback(LIMIT, format!("limit|{gid}")),
+        ]);
+    }
+    if upload_files && status == TaskStatus::Complete && role >= Role::Operator {
+        keyboard.push(vec![InlineKeyboardButton::callback(
+            UPLOAD,
+            format!("upload|{gid}"),
+        )]);
+    }
+    InlineKeyboardMarkup::new(keyboard)
+}
+
+pub fn speed_limit_callback(target: &str, kind: LimitKind, speed: u64) -> String {
+    let kind = match kind {
+        LimitKind::Download => "d",
+        LimitKind::Upload => "u",
     };
+    format!("slimit|{target}|{kind}|{speed}")
+}
 
-    InlineKeyboardMarkup::new(vec![bs])
+/// Speed limit presets of a task, or of the whole server when target is `*`.
+pub fn make_speed_limit_keyboard(target: &str, limits: &SpeedLimits) -> InlineKeyboardMarkup {
+    let mut keyboard: Vec<Vec<InlineKeyboardButton>> = vec![];
+    for (kind, icon, current) in [
+        (LimitKind::Download, "⬇", limits.download),
+        (LimitKind::Upload, "⬆", limits.upload),
+    ] {
+        for presets in SPEED_LIMIT_PRESETS.chunks(3) {
+            let row = presets
+                .iter()
+                .map(|&speed| {
+                    let mark = if speed == current { "✅" } else { icon };
+                    InlineKeyboardButton::callback(
+                        format!("{mark} {}", LimitFormatter(speed)),
+                        speed_limit_callback(target, kind, speed),
+                    )
+                })
+                .collect();
+            keyboard.push(row);
+        }
+    }
+    InlineKeyboardMarkup::new(keyboard)
 }
 
 pub fn make_download_confirm_keyboard<F>(
@@ -333,6 +630,8 @@ where
 }
 
 pub mod msg {
+    use crate::constants::{MAX_AUDIT_DETAIL_LEN, MAX_MESSAGE_LEN};
+    use std::collections::BTreeMap;
     use std::fmt::Display;
     pub struct MsgStart;
 
@@ -342,14 +641,24 @@ pub mod msg {
         }
     }
 
-    pub struct MsgTaskList {
+    pub struct MsgTaskList<'a> {
         pub page: usize,
         pub total_pages: usize,
+        pub filter: &'a crate::state::TaskFilter,
     }
 
-    impl From<MsgTaskList> for String {
-        fn from(msg: MsgTaskList) -> Self {
-            format!("Task List (page {}/{}):", msg.page, msg.total_pages)
+    impl<'a> From<MsgTaskList<'a>> for String {
+        fn from(msg: MsgTaskList<'a>) -> Self {
+            use crate::state::TaskFilter;
+
+            let title = match msg.filter {
+                TaskFilter::All => "Task List".to_string(),
+                TaskFilter::Active => "Active Tasks".to_string(),
+                TaskFilter::Error => "Errored Tasks".to_string(),
+                TaskFilter::Complete => "Completed Tasks".to_string(),
+                TaskFilter::Search(search) => format!("Tasks matching \"{search}\""),
+            };
+            format!("{title} (page {}/{}):", msg.page, msg.total_pages)
         }
     }
 
@@ -383,14 +692,35 @@ pub mod msg {
 
     pub struct MsgSwitchPrompt<'a> {
         pub current_server_name: Option<&'a str>,
+        pub servers: &'a [(&'a str, crate::aria2::Health)],
     }
 
     impl From<MsgSwitchPrompt<'_>> for String {
         fn from(prompt: MsgSwitchPrompt<'_>) -> Self {
+            let mut text = String::new();
+            for (name, health) in prompt.servers {
+                let status = match (health.healthy(), health.latency, &health.last_error) {
+                    (true, Some(latency), _) => format!("✅ {name}: {} ms", latency.as_millis()),
+                    (true, None, _) => format!("✅ {name}"),
+                    (false, _, Some((at, error))) => format!(
+                        "⚠️ {name}: {error} ({} ago)",
+                        super::DurationFormatter(at.elapsed())
+                    ),
+                    (false, _, None) => format!("⚠️ {name}: connecting"),
+                };
+                text.push_str(&status);
+                text.push('\n');
+            }
+            if !text.is_empty() {
+                text.push('\n');
+            }
             match prompt.current_server_name {
-                Some(name) => format!("Current server: {name}. Please select server:"),
-                None => "No server selected. Please select server:".into(),
+                Some(name) => {
+                    text.push_str(&format!("Current server: {name}. Please select server:"))
+                }
+                None => text.push_str("No server selected. Please select server:"),
             }
+            text
         }
     }
 
@@ -401,12 +731,167 @@ pub mod msg {
     impl From<MsgUnauthorized> for String {
         fn from(cmd: MsgUnauthorized) -> Self {
             format!(
-                "User or group({}) are not authorized to use this command!",
+                "User or group({}) are not authorized to use this command!\nIf you have an invite code, send /start <code> to use it.",
                 cmd.user_id
             )
         }
     }
 
+    pub struct MsgUsers<'a> {
+        // server name -> user id -> (role, granted at runtime)
+        pub users: &'a BTreeMap<String, BTreeMap<i64, (crate::role::Role, bool)>>,
+    }
+
+    impl From<MsgUsers<'_>> for String {
+        fn from(msg: MsgUsers<'_>) -> Self {
+            let mut text = String::from("Users:");
+            let multi = msg.users.len() > 1;
+            for (server, users) in msg.users.iter() {
+                if multi {
+                    text.push_str(&format!("\n\n[{server}]"));
+                }
+                if users.is_empty() {
+                    text.push_str("\nNo user.");
+                }
+                for (user, (role, granted)) in users.iter() {
+                    let granted = if *granted { " (granted)" } else { "" };
+                    text.push_str(&format!("\n{user}: {role}{granted}"));
+                }
+            }
+            text
+        }
+    }
+
+    pub struct MsgInvite<'a> {
+        pub code: &'a str,
+        // deep link redeeming the code
+        pub link: Option<String>,
+        pub role: crate::role::Role,
+        // empty when there is only one server
+        pub servers: &'a [String],
+        pub expire: std::time::Duration,
+    }
+
+    impl From<MsgInvite<'_>> for String {
+        fn from(msg: MsgInvite<'_>) -> Self {
+            let mut text = format!("Invite code for {}", msg.role);
+            if !msg.servers.is_empty() {
+                text.push_str(&format!(" on {}", msg.servers.join(", ")));
+            }
+            text.push_str(&format!(
+                ", usable once within {}:\n/start {}",
+                super::DurationFormatter(msg.expire),
+                msg.code
+            ));
+            if let Some(link) = msg.link {
+                text.push_str(&format!("\n{link}"));
+            }
+            text
+        }
+    }
+
+    pub struct MsgAudit<'a> {
+        // None when the audit log is not configured
+        pub entries: Option<&'a [crate::audit::AuditEntry]>,
+    }
+
+    impl From<MsgAudit<'_>> for String {
+        fn from(msg: MsgAudit<'_>) -> Self {
+            let Some(entries) = msg.entries else {
+                return "Audit log is not configured.".to_string();
+            };
+            if entries.is_empty() {
+                return "No audit entry.".to_string();
+            }
+            // Details like added uris can be long
+            let brief = |text: &str| match text.char_indices().nth(MAX_AUDIT_DETAIL_LEN) {
+                Some((end, _)) => format!("{}…", &text[..end]),
+                None => text.to_string(),
+            };
+            let header = "Audit log:";
+            let omitted_note = |n: usize| format!("\n…{n} older entries left out.");
+            let utf16_len = |text: &str| text.encode_utf16().count();
+
+            // Newest entries are kept when all of them don't fit in a message
+            let mut len = utf16_len(header);
+            let mut lines = Vec::with_capacity(entries.len());
+            for entry in entries.iter().rev() {
+                let mut line = format!("\n{}", entry.time.format("%Y-%m-%d %H:%M:%S"));
+                match entry.username.as_deref() {
+                    Some(username) => line.push_str(&format!(" @{username}({})", entry.user_id)),
+                    None => line.push_str(&format!(" {}", entry.user_id)),
+                }
+                line.push_str(&format!(" {}", entry.action));
+                if let Some(gid) = entry.gid.as_deref() {
+                    line.push_str(&format!(" {gid}"));
+                }
+                if let Some(detail) = entry.detail.as_deref() {
+                    line.push_str(&format!(" {}", brief(detail)));
+                }
+                line.push_str(&format!(": {}", brief(&entry.result)));
+                if len + utf16_len(&line) > MAX_MESSAGE_LEN {
+                    break;
+                }
+                len += utf16_len(&line);
+                lines.push(line);
+            }
+            let mut omitted = entries.len() - lines.len();
+            while omitted > 0 && len + utf16_len(&omitted_note(omitted)) > MAX_MESSAGE_LEN {
+                let line = lines.pop().expect("the note fits in an empty message");
+                len -= utf16_len(&line);
+                omitted += 1;
+            }
+
+            let mut text = String::from(header);
+            if omitted > 0 {
+                text.push_str(&omitted_note(omitted));
+            }
+            text.extend(lines.into_iter().rev());
+            text
+        }
+    }
+
+    pub struct MsgInviteRedeemed<'a> {
+        // server -> role the user ends up with
+        pub roles: &'a BTreeMap<String, crate::role::Role>,
+        // false when there is only one server
+        pub show_servers: bool,
+    }
+
+    impl From<MsgInviteRedeemed<'_>> for String {
+        fn from(msg: MsgInviteRedeemed<'_>) -> Self {
+            if msg.roles.is_empty() {
+                return "None of the invited servers exist any more.".to_string();
+            }
+            let roles: Vec<String> = msg
+                .roles
+                .iter()
+                .map(|(server, role)| match msg.show_servers {
+                    true => format!("a {role} on {server}"),
+                    false => format!("a {role}"),
+                })
+                .collect();
+            format!(
+                "Welcome! You are now {}.\nUse /task to get task list.",
+                roles.join(", ")
+            )
+        }
+    }
+
+    pub struct MsgForbidden {
+        pub role: crate::role::Role,
+        pub required: crate::role::Role,
+    }
+
+    impl From<MsgForbidden> for String {
+        fn from(msg: MsgForbidden) -> Self {
+            format!(
+                "You are a {} on this server, this action needs {}.",
+                msg.role, msg.required
+            )
+        }
+    }
+
     pub enum MsgCatchError<E> {
         InvalidCommand,
         Error { error: E },
@@ -450,14 +935,305 @@ pub mod msg {
     }
 
     pub struct MsgDownloadTorrentConfirm<'a> {
-        pub document: &'a teloxide::types::Document,
+        pub meta: &'a crate::torrent::TorrentMeta,
+        // number of selected files when files can be selected
+        pub selected: Option<usize>,
     }
 
     impl<'a> From<MsgDownloadTorrentConfirm<'a>> for String {
         fn from(msg: MsgDownloadTorrentConfirm<'a>) -> Self {
-            match &msg.document.file_name {
-                Some(name) => format!("Confirm download torrent file {name}?"),
-                None => format!("Confirm download torrent file_{}?", msg.document.file.id),
+            use crate::constants::TORRENT_TOP_FILES;
+            use std::fmt::Write;
+
+            let meta = msg.meta;
+            let mut text = format!(
+                "Confirm download torrent?\n\n📦 {}\n🔑 Info hash: {}\n💾 Size: {} in {} file(s)\n🧩 Piece size: {}",
+                meta.name,
+                meta.info_hash,
+                super::SizeFormatter(meta.total_length()),
+                meta.files.len(),
+                super::SizeFormatter(meta.piece_length),
+            );
+            if meta.files.len() > 1 {
+                let top = meta.largest_files(TORRENT_TOP_FILES);
+                let _ = write!(text, "\n\nLargest files:");
+                for pos in top.iter().copied() {
+                    let file = &meta.files[pos];
+                    let _ = write!(
+                        text,
+                        "\n{} {}",
+                        super::SizeFormatter(file.length),
+                        file.path
+                    );
+                }
+                if meta.files.len() > top.len() {
+                    let _ = write!(text, "\n... and {} more", meta.files.len() - top.len());
+                }
+            }
+            if let Some(selected) = msg.selected {
+                let _ = write!(text, "\n\n{selected}/{} files selected", meta.files.len());
+            }
+            text
+        }
+    }
+
+    pub struct MsgInvalidTorrent<'a> {
+        pub name: &'a str,
+        pub error: &'a anyhow::Error,
+    }
+
+    impl<'a> From<MsgInvalidTorrent<'a>> for String {
+        fn from(msg: MsgInvalidTorrent<'a>) -> Self {
+            format!("{} is not a valid torrent file: {}", msg.name, msg.error)
+        }
+    }
+
+    pub struct MsgFileSelection<'a> {
+        pub files: &'a [super::FileEntry<'a>],
+    }
+
+    impl<'a> From<MsgFileSelection<'a>> for String {
+        fn from(msg: MsgFileSelection<'a>) -> Self {
+            let (count, size) = msg
+                .files
+                .iter()
+                .filter(|f| f.selected)
+                .fold((0, 0), |(count, size), f| (count + 1, size + f.length));
+            format!(
+                "Select files to download ({count}/{} files, {}).\nTap a file to toggle it:",
+                msg.files.len(),
+                super::SizeFormatter(size)
+            )
+        }
+    }
+
+    pub struct MsgTaskEvent<'a> {
+        pub gid: &'a str,
+        pub name: &'a str,
+        pub event: crate::state::TaskEvent,
+        pub bittorrent: bool,
+    }
+
+    impl<'a> From<MsgTaskEvent<'a>> for String {
+        fn from(msg: MsgTaskEvent<'a>) -> Self {
+            use crate::state::TaskEvent;
+            let event = match (msg.event, msg.bittorrent) {
+                (TaskEvent::Seeding, _) => "✅ Download complete, now seeding",
+                (TaskEvent::Complete, true) => "🏁 Seeding finished",
+                (TaskEvent::Complete, false) => "✅ Download complete",
+                (TaskEvent::Error, _) => "❌ Download failed",
+            };
+            format!("{event}: {}\nGID: {}", msg.name, msg.gid)
+        }
+    }
+
+    pub struct MsgSpeedLimits<'a> {
+        // None for the server wide limits
+        pub gid: Option<&'a str>,
+        pub limits: &'a crate::aria2::SpeedLimits,
+    }
+
+    impl<'a> From<MsgSpeedLimits<'a>> for String {
+        fn from(msg: MsgSpeedLimits<'a>) -> Self {
+            let (target, usage) = match msg.gid {
+                Some(gid) => (
+                    format!("task {gid}"),
+                    format!("/limit task {gid} <download> [upload]"),
+                ),
+                None => (
+                    "the server".to_string(),
+                    "/limit <download> [upload]".to_string(),
+                ),
+            };
+            format!(
+                "Speed limits of {target}:\n⬇ Download: {}\n⬆ Upload: {}\n\nTap a preset or send {usage} to set a custom limit, e.g. 2M 512K, 0 for unlimited.",
+                super::LimitFormatter(msg.limits.download),
+                super::LimitFormatter(msg.limits.upload),
+            )
+        }
+    }
+
+    pub struct MsgServerStats<'a> {
+        pub stat: &'a aria2_rs::status::Stat,
+        pub version: Option<&'a crate::aria2::Version>,
+    }
+
+    impl<'a> From<MsgServerStats<'a>> for String {
+        fn from(msg: MsgServerStats<'a>) -> Self {
+            use super::SizeFormatter;
+
+            let stat = msg.stat;
+            let mut text = format!(
+                "📊 Server Stats\nSpeed: ⬆ {}/s | ⬇ {}/s\nActive: {} | Waiting: {} | Stopped: {}",
+                SizeFormatter(stat.upload_speed.unwrap_or(0)),
+                SizeFormatter(stat.download_speed.unwrap_or(0)),
+                stat.num_active.unwrap_or(0),
+                stat.num_waiting.unwrap_or(0),
+                stat.num_stopped.unwrap_or(0),
+            );
+            if let Some(total) = stat.num_stopped_total {
+                text.push_str(&format!(" (total {total})"));
+            }
+            if let Some(version) = msg.version {
+                text.push_str(&format!("\n\naria2 {}", version.version));
+                if !version.enabled_features.is_empty() {
+                    text.push_str(&format!(
+                        "\nFeatures: {}",
+                        version.enabled_features.join(", ")
+                    ));
+                }
+            }
+            text
+        }
+    }
+
+    pub struct MsgBulkConfirm {
+        pub action: crate::state::BulkAction,
+        // number of affected tasks for per task actions
+        pub count: usize,
+    }
+
+    impl From<MsgBulkConfirm> for String {
+        fn from(msg: MsgBulkConfirm) -> Self {
+            use crate::state::BulkAction;
+            match msg.action {
+                BulkAction::PauseAll => "Pause all tasks?".into(),
+                BulkAction::ResumeAll => "Resume all tasks?".into(),
+                BulkAction::RemoveErrored => format!("Remove {} errored tasks?", msg.count),
+                BulkAction::RetryErrored => format!("Retry {} errored tasks?", msg.count),
+            }
+        }
+    }
+
+    pub struct MsgConfirm<'a> {
+        pub action: &'a crate::state::ConfirmAction,
+    }
+
+    impl From<MsgConfirm<'_>> for String {
+        fn from(msg: MsgConfirm<'_>) -> Self {
+            use crate::state::ConfirmAction;
+            match msg.action {
+                ConfirmAction::Remove { gid } => {
+                    format!("Remove task {gid}? It can not be resumed afterwards.")
+                }
+                ConfirmAction::RemoveFiles { gid } => format!(
+                    "Remove task {gid} and delete its downloaded files? They can not be recovered."
+                ),
+                ConfirmAction::Purge => "Purge all completed, errored and removed results?".into(),
+            }
+        }
+    }
+
+    pub struct MsgRemoveFiles<'a, E> {
+        pub gid: &'a str,
+        pub res: &'a Result<crate::files::DeleteReport, E>,
+    }
+
+    impl<E: Display> From<MsgRemoveFiles<'_, E>> for String {
+        fn from(msg: MsgRemoveFiles<'_, E>) -> Self {
+            use crate::constants::MAX_BULK_RESULT_LINES;
+
+            let report = match msg.res {
+                Ok(report) => report,
+                Err(e) => return format!("Failed to remove task {} with its files: {e}", msg.gid),
+            };
+            let mut text = format!(
+                "Task {} removed, deleted {} files and {} empty directories.",
+                msg.gid,
+                report.deleted.len(),
+                report.dirs
+            );
+            for path in report.deleted.iter().take(MAX_BULK_RESULT_LINES) {
+                text.push_str(&format!("\n🗑 {}", path.display()));
+            }
+            if report.deleted.len() > MAX_BULK_RESULT_LINES {
+                text.push_str(&format!(
+                    "\n... and {} more",
+                    report.deleted.len() - MAX_BULK_RESULT_LINES
+                ));
+            }
+            if report.missing > 0 {
+                text.push_str(&format!("\n{} files were already gone.", report.missing));
+            }
+            for path in report.refused.iter() {
+                text.push_str(&format!("\n⚠️ Refused, outside of the root: {path}"));
+            }
+            for (path, e) in report.failed.iter() {
+                text.push_str(&format!("\n❌ {}: {e}", path.display()));
+            }
+            text
+        }
+    }
+
+    pub struct MsgUploadProgress<'a> {
+        // files sent or skipped so far
+        pub done: usize,
+        pub total: usize,
+        // name, bytes sent, size of the file being sent
+        pub current: Option<(&'a str, u64, u64)>,
+    }
+
+    impl From<MsgUploadProgress<'_>> for String {
+        fn from(msg: MsgUploadProgress<'_>) -> Self {
+            let mut text = format!("Sending files: {}/{}", msg.done, msg.total);
+            if let Some((name, sent, size)) = msg.current {
+                let percent = (sent * 100).checked_div(size).unwrap_or(100);
+                text.push_str(&format!(
+                    "\n{name}: {} / {} ({percent}%)",
+                    super::SizeFormatter(sent),
+                    super::SizeFormatter(size)
+                ));
+            }
+            text
+        }
+    }
+
+    pub enum UploadFailure {
+        TooLarge { size: u64, limit: u64 },
+        Error(String),
+    }
+
+    pub struct MsgUploadResult<'a> {
+        pub sent: usize,
+        // file name, why it was not sent
+        pub failed: &'a [(String, UploadFailure)],
+    }
+
+    impl From<MsgUploadResult<'_>> for String {
+        fn from(msg: MsgUploadResult<'_>) -> Self {
+            let mut text = if msg.failed.is_empty() {
+                format!("Sent {} files.", msg.sent)
+            } else {
+                format!(
+                    "Sent {} of {} files.",
+                    msg.sent,
+                    msg.sent + msg.failed.len()
+                )
+            };
+            for (name, failure) in msg.failed.iter() {
+                match failure {
+                    UploadFailure::TooLarge { size, limit } => text.push_str(&format!(
+                        "\n⚠️ {name}: {} is over the {} limit",
+                        super::SizeFormatter(*size),
+                        super::SizeFormatter(*limit)
+                    )),
+                    UploadFailure::Error(e) => text.push_str(&format!("\n❌ {name}: {e}")),
+                }
+            }
+            text
+        }
+    }
+
+    pub struct MsgConfirmSetting {
+        pub confirms: bool,
+    }
+
+    impl From<MsgConfirmSetting> for String {
+        fn from(msg: MsgConfirmSetting) -> Self {
+            if msg.confirms {
+                "Remove and purge ask for confirmation, /confirm off to skip it.".into()
+            } else {
+                "Remove and purge run without confirmation, /confirm on to ask again.".into()
             }
         }
     }
@@ -477,6 +1253,40 @@ pub mod msg {
         Resume(&'a str, &'a Result<T, E>),
         Remove(&'a str, &'a Result<T, E>),
         Purge(&'a Result<T, E>),
+        // old GID, new GID
+        Retry(&'a str, &'a Result<smol_str::SmolStr, E>),
+        PauseAll(&'a Result<T, E>),
+        ResumeAll(&'a Result<T, E>),
+        RemoveErrored(&'a [(smol_str::SmolStr, Result<T, E>)]),
+        // old GID -> new GID
+        RetryErrored(&'a [(smol_str::SmolStr, Result<smol_str::SmolStr, E>)]),
+    }
+
+    fn fmt_bulk_results<T, E: Display>(
+        action: &str,
+        results: &[(smol_str::SmolStr, Result<T, E>)],
+        fmt_ok: impl Fn(&T) -> String,
+    ) -> String {
+        use crate::constants::MAX_BULK_RESULT_LINES;
+
+        let succeeded = results.iter().filter(|(_, r)| r.is_ok()).count();
+        let mut text = format!(
+            "{action}: {succeeded} succeeded, {} failed",
+            results.len() - succeeded
+        );
+        for (gid, result) in results.iter().take(MAX_BULK_RESULT_LINES) {
+            match result {
+                Ok(ok) => text.push_str(&format!("\n✅ {gid}{}", fmt_ok(ok))),
+                Err(error) => text.push_str(&format!("\n❌ {gid}: {error}")),
+            }
+        }
+        if results.len() > MAX_BULK_RESULT_LINES {
+            text.push_str(&format!(
+                "\n... and {} more",
+                results.len() - MAX_BULK_RESULT_LINES
+            ));
+        }
+        text
     }
 
     impl<'a, E: Display> From<MsgTaskActionResult<'a, E>> for String {
@@ -488,6 +1298,34 @@ pub mod msg {
                         Err(error) => format!("Purge downloaded results failed: {error}"),
                     }
                 }
+                MsgTaskActionResult::Retry(gid, result) => {
+                    return match result {
+                        Ok(new_gid) => {
+                            format!("Retry task {gid} successfully!\nNew GID: {new_gid}")
+                        }
+                        Err(error) => format!("Retry task {gid} failed: {error}"),
+                    }
+                }
+                MsgTaskActionResult::PauseAll(result) => {
+                    return match result {
+                        Ok(_) => "Pause all tasks successfully!".into(),
+                        Err(error) => format!("Pause all tasks failed: {error}"),
+                    }
+                }
+                MsgTaskActionResult::ResumeAll(result) => {
+                    return match result {
+                        Ok(_) => "Resume all tasks successfully!".into(),
+                        Err(error) => format!("Resume all tasks failed: {error}"),
+                    }
+                }
+                MsgTaskActionResult::RemoveErrored(results) => {
+                    return fmt_bulk_results("Remove errored tasks", results, |_| String::new())
+                }
+                MsgTaskActionResult::RetryErrored(results) => {
+                    return fmt_bulk_results("Retry errored tasks", results, |gid| {
+                        format!(" → {gid}")
+                    })
+                }
                 MsgTaskActionResult::Pause(gid, result) => ("Pause", gid, result),
                 MsgTaskActionResult::Resume(gid, result) => ("Resume", gid, result),
                 MsgTaskActionResult::Remove(gid, result) => ("Remove", gid, result),
@@ -586,9 +1424,248 @@ mod tests {
         assert_eq!(status.progress_size(), (500, 1000));
     }
 
+    #[test]
+    fn test_bulk_result() {
+        let results = vec![
+            ("a".into(), Ok("c".into())),
+            ("b".into(), Err("no uri to retry from")),
+        ];
+        let text: String = msg::MsgTaskActionResult::<_, ()>::RetryErrored(&results).into();
+        assert_eq!(
+            text,
+            "Retry errored tasks: 1 succeeded, 1 failed\n✅ a → c\n❌ b: no uri to retry from"
+        );
+    }
+
     #[test]
     fn test_progress_size_none() {
         let status = make_status(None, None);
         assert_eq!(status.progress_size(), (0, 0));
     }
+
+    #[test]
+    fn test_error_detail() {
+        let mut status = make_status(Some(0), Some(1000));
+        status.status = Some(TaskStatus::Error);
+        status.error_code = Some("9".into());
+        status.error_message = Some("No space left on device".into());
+        let text = MessageFmtDetailed(&status).to_string();
+        assert!(text.contains("Error: Not enough disk space (code 9)\n"));
+        assert!(text.contains("Message: No space left on device\n"));
+        assert!(text.contains("Hint: "));
+
+        status.error_code = Some("99".into());
+        let text = MessageFmtDetailed(&status).to_string();
+        assert!(text.contains("Error: code 99\n"));
+        assert!(!text.contains("Hint: "));
+    }
+
+    #[test]
+    fn test_duration_formatter() {
+        assert_eq!(
+            DurationFormatter(Duration::from_secs(42)).to_string(),
+            "42s"
+        );
+        assert_eq!(
+            DurationFormatter(Duration::from_secs(125)).to_string(),
+            "2m 5s"
+        );
+        assert_eq!(
+            DurationFormatter(Duration::from_secs(7500)).to_string(),
+            "2h 5m"
+        );
+        assert_eq!(
+            DurationFormatter(Duration::from_secs(90000)).to_string(),
+            "1d 1h"
+        );
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        assert_eq!(ProgressBar::<5>(0.0).to_string(), "░░░░░");
+        assert_eq!(ProgressBar::<5>(0.5).to_string(), "███░░");
+        assert_eq!(ProgressBar::<5>(0.39).to_string(), "██░░░");
+        assert_eq!(ProgressBar::<5>(1.0).to_string(), "█████");
+        assert_eq!(ProgressBar::<5>(1.5).to_string(), "█████");
+    }
+
+    #[test]
+    fn test_single_task_keyboard_roles() {
+        use teloxide::types::InlineKeyboardButtonKind;
+
+        let callbacks = |role, delete_files| {
+            make_single_task_keyboard("gid", TaskStatus::Paused, role, delete_files, true)
+                .inline_keyboard
+                .into_iter()
+                .flatten()
+                .filter_map(|b| match b.kind {
+                    InlineKeyboardButtonKind::CallbackData(data) => Some(data),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(callbacks(Role::Viewer, false).is_empty());
+        assert_eq!(callbacks(Role::Operator, false), ["resume|gid"]);
+        assert_eq!(
+            callbacks(Role::Admin, false),
+            ["resume|gid", "remove|gid", "files|gid", "limit|gid"]
+        );
+        // Deleting files is opt-in and admin only
+        assert_eq!(callbacks(Role::Operator, true), ["resume|gid"]);
+        assert_eq!(
+            callbacks(Role::Admin, true),
+            [
+                "resume|gid",
+                "remove|gid",
+                "rmfiles|gid",
+                "files|gid",
+                "limit|gid"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upload_messages() {
+        use msg::{MsgUploadProgress, MsgUploadResult, UploadFailure};
+
+        let text: String = MsgUploadProgress {
+            done: 1,
+            total: 3,
+            current: Some(("a.pdf", 2 * 1024 * 1024, 4 * 1024 * 1024)),
+        }
+        .into();
+        assert_eq!(text, "Sending files: 1/3\na.pdf: 2.00 MiB / 4.00 MiB (50%)");
+
+        let failed = [
+            (
+                "b.iso".to_string(),
+                UploadFailure::TooLarge {
+                    size: 100 * 1024 * 1024,
+                    limit: 50 * 1024 * 1024,
+                },
+            ),
+            ("c".to_string(), UploadFailure::Error("not a file".into())),
+        ];
+        let text: String = MsgUploadResult {
+            sent: 1,
+            failed: &failed,
+        }
+        .into();
+        assert_eq!(
+            text,
+            "Sent 1 of 3 files.\n⚠️ b.iso: 100.00 MiB is over the 50.00 MiB limit\n❌ c: not a file"
+        );
+
+        let keyboard =
+            make_single_task_keyboard("gid", TaskStatus::Complete, Role::Operator, false, true);
+        assert_eq!(keyboard.inline_keyboard[1][0].text, "📤 Send to chat");
+        let keyboard =
+            make_single_task_keyboard("gid", TaskStatus::Complete, Role::Viewer, false, true);
+        assert!(keyboard.inline_keyboard.concat().is_empty());
+    }
+
+    #[test]
+    fn test_search_callback_len() {
+        use teloxide::types::InlineKeyboardButtonKind;
+
+        let filter = TaskFilter::from_args(&"İ".repeat(40));
+        let tasks = (0..100_000)
+            .map(|i| (format!("task {i}"), format!("{i:016x}")))
+            .collect();
+        let keyboard = make_tasks_keyboard(tasks, 99_999, 10, &filter, Role::Admin);
+        let refresh = make_refresh_list_keyboard(99_999, &filter);
+        let callbacks: Vec<_> = keyboard
+            .inline_keyboard
+            .into_iter()
+            .chain(refresh.inline_keyboard)
+            .flatten()
+            .filter_map(|b| match b.kind {
+                InlineKeyboardButtonKind::CallbackData(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert!(callbacks
+            .iter()
+            .any(|data| data.starts_with("task_page|9999|s:")));
+        // Telegram rejects callback data over 64 bytes
+        for data in callbacks {
+            assert!(data.len() <= 64, "{data} is too long");
+        }
+    }
+
+    #[test]
+    fn test_bulk_row_roles() {
+        assert!(make_bulk_row(&TaskFilter::Error, Role::Viewer).is_empty());
+        let row = make_bulk_row(&TaskFilter::Error, Role::Operator);
+        assert_eq!(row.len(), 1);
+        assert_eq!(row[0].text, "🔁 Retry all");
+        assert_eq!(make_bulk_row(&TaskFilter::Error, Role::Admin).len(), 2);
+    }
+
+    #[test]
+    fn test_users_message() {
+        use std::collections::BTreeMap;
+
+        let users = BTreeMap::from([(
+            "__default__".to_string(),
+            BTreeMap::from([(1, (Role::Admin, false)), (2, (Role::Viewer, true))]),
+        )]);
+        let text: String = msg::MsgUsers { users: &users }.into();
+        assert_eq!(text, "Users:\n1: admin\n2: viewer (granted)");
+    }
+
+    #[test]
+    fn test_audit_message() {
+        use crate::audit::AuditEntry;
+        use chrono::TimeZone;
+
+        let text: String = msg::MsgAudit { entries: None }.into();
+        assert_eq!(text, "Audit log is not configured.");
+
+        let entry = AuditEntry {
+            time: chrono::Utc.with_ymd_and_hms(2024, 5, 1, 8, 30, 0).unwrap(),
+            user_id: 1,
+            username: Some("alice".to_string()),
+            server: "__default__".to_string(),
+            gid: Some("2089b05ecca3d829".into()),
+            action: "set_speed_limit".to_string(),
+            detail: Some("download 1048576".to_string()),
+            result: "ok".to_string(),
+        };
+        let purge = AuditEntry {
+            username: None,
+            gid: None,
+            action: "purge".to_string(),
+            detail: None,
+            result: "timeout".to_string(),
+            ..entry.clone()
+        };
+        let text: String = msg::MsgAudit {
+            entries: Some(&[entry.clone(), purge]),
+        }
+        .into();
+        assert_eq!(
+            text,
+            "Audit log:\n\
+             2024-05-01 08:30:00 @alice(1) set_speed_limit 2089b05ecca3d829 download 1048576: ok\n\
+             2024-05-01 08:30:00 1 purge: timeout"
+        );
+
+        // Long details are cut and older entries left out past the message length
+        let add = AuditEntry {
+            action: "add_uri".to_string(),
+            detail: Some(format!("magnet:?xt=urn:btih:{}", "a".repeat(1000))),
+            ..entry
+        };
+        let entries = vec![add; 50];
+        let text: String = msg::MsgAudit {
+            entries: Some(&entries),
+        }
+        .into();
+        assert!(text.encode_utf16().count() <= crate::constants::MAX_MESSAGE_LEN);
+        let shown = text.matches("add_uri").count();
+        assert!(shown > 0 && shown < 50);
+        assert!(text.contains(&format!("\n…{} older entries left out.\n", 50 - shown)));
+        assert!(!text.contains(&"a".repeat(100)));
+    }
 }
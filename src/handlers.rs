@@ -5,35 +5,53 @@
 
 use std::ops::ControlFlow;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use aria2_rs::status::TaskStatus;
+use aria2_rs::status::{File, Status, TaskStatus};
 use aria2_rs::SmallVec;
 use bytes::Bytes;
 use smol_str::SmolStr;
 use teloxide::{
     payloads::SendMessageSetters,
     prelude::*,
-    types::{MaybeInaccessibleMessage, Me, MessageId, ParseMode, ReplyParameters},
+    types::{
+        InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MaybeInaccessibleMessage, Me,
+        MessageId, ParseMode, ReplyParameters, ThreadId,
+    },
     utils::command::BotCommands,
     Bot,
 };
 
-use crate::aria2::AddUrisResult;
-use crate::constants::{ARIA2_OP_TIMEOUT, MAX_TORRENT_SIZE};
+use crate::aria2::{parse_speed, AddUrisResult, LimitKind};
+use crate::audit::Auditor;
+use crate::constants::{
+    ARIA2_OP_TIMEOUT, AUDIT_DEFAULT_ENTRIES, AUDIT_MAX_ENTRIES, INVITE_EXPIRE,
+    REMOVE_POLL_INTERVAL, REMOVE_STOP_TIMEOUT, UPLOAD_PROGRESS_INTERVAL,
+};
+use crate::files::{DeleteReport, ProgressReader};
 use crate::format::{
-    make_download_confirm_keyboard, make_refresh_list_keyboard, make_refresh_task_keyboard,
-    make_retry_keyboard, make_single_task_keyboard, make_switch_server_keyboard,
-    make_tasks_keyboard,
+    make_confirm_keyboard, make_download_confirm_keyboard, make_files_keyboard,
+    make_refresh_list_keyboard, make_refresh_stats_keyboard, make_refresh_task_keyboard,
+    make_retry_keyboard, make_single_task_keyboard, make_speed_limit_keyboard,
+    make_switch_server_keyboard, make_tasks_keyboard,
     msg::{
-        MsgCatchError, MsgDownloadLinkConfirm, MsgDownloadMagnetConfirm, MsgDownloadTorrentConfirm,
-        MsgStart, MsgSwitchPrompt, MsgSwitchResult, MsgTaskActionResult, MsgTaskList,
-        MsgTaskNotFound, MsgUnauthorized,
+        MsgAudit, MsgBulkConfirm, MsgCatchError, MsgConfirm, MsgConfirmSetting,
+        MsgDownloadLinkConfirm, MsgDownloadMagnetConfirm, MsgDownloadTorrentConfirm,
+        MsgFileSelection, MsgForbidden, MsgInvalidTorrent, MsgInvite, MsgInviteRedeemed,
+        MsgRemoveFiles, MsgSpeedLimits, MsgStart, MsgSwitchPrompt, MsgSwitchResult,
+        MsgTaskActionResult, MsgTaskList, MsgTaskNotFound, MsgUnauthorized, MsgUploadProgress,
+        MsgUploadResult, MsgUsers, UploadFailure,
     },
-    task_list_page_count, TASK_LIST_PAGE_SIZE,
+    speed_limit_callback, task_list_page_count, FileEntry, TASK_LIST_PAGE_SIZE,
 };
-use crate::state::{State, TasksCache};
-use crate::utils::SendMessageSettersExt;
+use crate::role::Role;
+use crate::state::{
+    BulkAction, ConfirmAction, ConfirmResult, ServerState, State, TaskFilter, TasksCache,
+    TorrentSelection, TorrentSource,
+};
+use crate::torrent::TorrentMeta;
+use crate::utils::{topic_of, SendMessageSettersExt};
 use crate::{Command, UserData, HTTP_RE, MAGNET_RE};
 
 /// Handle incoming messages from Telegram.
@@ -45,6 +63,7 @@ pub async fn message_handler(
     me: Me,
     state: Arc<State>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let user_id = state.principal(&msg.chat, msg.from.as_ref());
     // Try to parse the message as a command.
     if let Some(cmd) = msg
         .text()
@@ -54,70 +73,234 @@ pub async fn message_handler(
             Command::Help => {
                 // Just send the description of all commands.
                 bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .reply_to(&msg)
                     .await?;
                 return Ok(());
             }
-            Command::Start => {
-                bot.send_message(msg.chat.id, MsgStart).await?;
+            Command::Start(code) if !code.trim().is_empty() => {
+                handle_redeem_invite(&bot, &msg, &state, code.trim()).await?;
+                return Ok(());
+            }
+            Command::Start(_) => {
+                bot.send_message(msg.chat.id, MsgStart)
+                    .message_thread_id_opt(topic_of(&msg))
+                    .await?;
                 return Ok(());
             }
             Command::Id => {
-                bot.send_message(msg.chat.id, format!("`{}`", msg.chat.id))
+                // Members of allowed groups need their own id
+                let text = match msg.from.as_ref().filter(|_| !msg.chat.is_private()) {
+                    Some(user) => format!("Chat: `{}`\nUser: `{}`", msg.chat.id, user.id),
+                    None => format!("`{}`", msg.chat.id),
+                };
+                bot.send_message(msg.chat.id, text)
                     .parse_mode(ParseMode::MarkdownV2)
-                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .reply_to(&msg)
                     .await?;
                 return Ok(());
             }
             Command::Switch => {
-                select_or_unauthorized(&bot, msg.chat.id, Some(msg.id), &state).await?;
+                select_or_unauthorized(&bot, &msg, user_id, &state).await?;
+                return Ok(());
+            }
+            Command::Grant(_) | Command::Revoke(_) | Command::Users => {
+                handle_owner_command(&bot, &msg, &state, cmd).await?;
+                return Ok(());
+            }
+            Command::Invite(args) => {
+                handle_invite_command(&bot, &msg, &me, &state, user_id, &args).await?;
                 return Ok(());
             }
             _ => (),
         }
 
-        let Some(server_selected) = state.selected(msg.chat.id.0) else {
-            select_or_unauthorized(&bot, msg.chat.id, Some(msg.id), &state).await?;
+        let Some(server_selected) = state.selected(msg.chat.id.0, user_id) else {
+            select_or_unauthorized(&bot, &msg, user_id, &state).await?;
+            return Ok(());
+        };
+        // Auth checked for the remaining commands.
+        let Some(role) = state.role(user_id, &server_selected) else {
+            bot.send_message(msg.chat.id, MsgUnauthorized { user_id })
+                .reply_to(&msg)
+                .await?;
+            return Ok(());
+        };
+        let sender = sender_id(&msg).unwrap_or(msg.chat.id.0);
+        let audit = Auditor::new(
+            &state.audit,
+            sender,
+            msg.from.as_ref().and_then(|user| user.username.as_deref()),
+            &server_selected.name,
+        );
+        let Some(required) = cmd.required_role() else {
             return Ok(());
         };
-        // Auth checked for Task and Purge command.
+        if role < required {
+            bot.send_message(msg.chat.id, MsgForbidden { role, required })
+                .reply_to(&msg)
+                .await?;
+            return Ok(());
+        }
         match cmd {
-            Command::Task => {
+            Command::Task(args) => {
+                let filter = TaskFilter::from_args(&args);
                 if let Err(e) =
                     TasksCache::refresh(&server_selected.tasks_cache, &server_selected.client).await
                 {
                     bot.send_message(msg.chat.id, format!("Failed to fetch tasks: {e}"))
-                        .reply_parameters(ReplyParameters::new(msg.id))
+                        .reply_to(&msg)
                         .await?;
                     return Ok(());
                 }
-                let tasks = server_selected.tasks_cache.read().fmt_tasks();
+                let tasks = server_selected.tasks_cache.read().fmt_tasks(&filter);
                 let total_pages = task_list_page_count(tasks.len(), TASK_LIST_PAGE_SIZE);
-                let keyboard = make_tasks_keyboard(tasks, 0, TASK_LIST_PAGE_SIZE);
+                let keyboard = make_tasks_keyboard(tasks, 0, TASK_LIST_PAGE_SIZE, &filter, role);
                 let reply = bot
                     .send_message(
                         msg.chat.id,
                         MsgTaskList {
                             page: 1,
                             total_pages,
+                            filter: &filter,
                         },
                     )
                     .reply_markup(keyboard)
-                    .reply_parameters(ReplyParameters::new(msg.id))
+                    .reply_to(&msg)
+                    .await?;
+                server_selected.tasks_cache.write().add_list_subscriber(
+                    reply.chat.id,
+                    reply.id,
+                    0,
+                    filter,
+                    role,
+                );
+            }
+            Command::Purge => {
+                let action = ConfirmAction::Purge;
+                if state.confirms(sender) {
+                    let (chat_id, thread_id) = (msg.chat.id, topic_of(&msg));
+                    send_confirm_prompt(
+                        &bot,
+                        &state,
+                        &server_selected,
+                        chat_id,
+                        msg.id,
+                        thread_id,
+                        action,
+                    )
+                    .await?;
+                } else {
+                    let text = run_confirm_action(&server_selected, &audit, &action).await;
+                    bot.send_message(msg.chat.id, text).reply_to(&msg).await?;
+                }
+            }
+            Command::Confirm(args) => {
+                let text = match args.trim() {
+                    "" => MsgConfirmSetting {
+                        confirms: state.confirms(sender),
+                    }
+                    .into(),
+                    arg @ ("on" | "off") => {
+                        state.set_confirms(sender, arg == "on");
+                        MsgConfirmSetting {
+                            confirms: arg == "on",
+                        }
+                        .into()
+                    }
+                    _ => "Usage: /confirm [on|off]".to_string(),
+                };
+                bot.send_message(msg.chat.id, text).reply_to(&msg).await?;
+            }
+            Command::Stats => {
+                if let Err(e) =
+                    TasksCache::refresh_stats(&server_selected.tasks_cache, &server_selected.client)
+                        .await
+                {
+                    bot.send_message(msg.chat.id, format!("Failed to fetch stats: {e}"))
+                        .reply_markup(make_refresh_stats_keyboard())
+                        .reply_to(&msg)
+                        .await?;
+                    return Ok(());
+                }
+                let text = server_selected.tasks_cache.read().fmt_stats();
+                let reply = bot
+                    .send_message(msg.chat.id, text.unwrap_or_default())
+                    .reply_to(&msg)
                     .await?;
                 server_selected
                     .tasks_cache
                     .write()
-                    .add_list_subscriber(reply.chat.id, reply.id, 0);
+                    .add_stats_subscriber(reply.chat.id, reply.id);
             }
-            Command::Purge => {
-                bot.send_message(
+            Command::PauseAll => {
+                send_bulk_confirm(
+                    &bot,
+                    msg.chat.id,
+                    msg.id,
+                    topic_of(&msg),
+                    &server_selected,
+                    BulkAction::PauseAll,
+                )
+                .await?;
+            }
+            Command::ResumeAll => {
+                send_bulk_confirm(
+                    &bot,
+                    msg.chat.id,
+                    msg.id,
+                    topic_of(&msg),
+                    &server_selected,
+                    BulkAction::ResumeAll,
+                )
+                .await?;
+            }
+            Command::RemoveErrored => {
+                send_bulk_confirm(
+                    &bot,
+                    msg.chat.id,
+                    msg.id,
+                    topic_of(&msg),
+                    &server_selected,
+                    BulkAction::RemoveErrored,
+                )
+                .await?;
+            }
+            Command::RetryErrored => {
+                send_bulk_confirm(
+                    &bot,
                     msg.chat.id,
-                    MsgTaskActionResult::Purge(&server_selected.client.purge_downloaded().await),
+                    msg.id,
+                    topic_of(&msg),
+                    &server_selected,
+                    BulkAction::RetryErrored,
                 )
-                .reply_parameters(ReplyParameters::new(msg.id))
                 .await?;
             }
+            Command::Limit(args) => {
+                handle_limit_command(&bot, &msg, &server_selected, &audit, &args).await?;
+            }
+            Command::Audit(args) => {
+                let count = match args.trim() {
+                    "" => AUDIT_DEFAULT_ENTRIES,
+                    count => match count.parse::<usize>() {
+                        Ok(count) if count > 0 => count.min(AUDIT_MAX_ENTRIES),
+                        _ => {
+                            bot.send_message(msg.chat.id, "Usage: /audit [count]")
+                                .reply_to(&msg)
+                                .await?;
+                            return Ok(());
+                        }
+                    },
+                };
+                let text = match state.audit.recent(count, &server_selected.name).await {
+                    Ok(entries) => MsgAudit {
+                        entries: state.audit.enabled().then_some(&entries[..]),
+                    }
+                    .into(),
+                    Err(e) => format!("Failed to read audit log: {e}"),
+                };
+                bot.send_message(msg.chat.id, text).reply_to(&msg).await?;
+            }
             _ => unreachable!(),
         }
         return Ok(());
@@ -129,7 +312,9 @@ pub async fn message_handler(
         Ok(ControlFlow::Continue(_)) => MsgCatchError::InvalidCommand,
         Err(e) => MsgCatchError::Error { error: e },
     };
-    bot.send_message(msg.chat.id, text).await?;
+    bot.send_message(msg.chat.id, text)
+        .message_thread_id_opt(topic_of(&msg))
+        .await?;
 
     Ok(())
 }
@@ -140,10 +325,30 @@ async fn handle_message_content(
     msg: &Message,
     state: Arc<State>,
 ) -> anyhow::Result<ControlFlow<()>> {
-    let Some(server_selected) = state.selected(msg.chat.id.0) else {
-        select_or_unauthorized(bot, msg.chat.id, Some(msg.id), &state).await?;
+    let user_id = state.principal(&msg.chat, msg.from.as_ref());
+    let Some(server_selected) = state.selected(msg.chat.id.0, user_id) else {
+        select_or_unauthorized(bot, msg, user_id, &state).await?;
+        return Ok(ControlFlow::Break(()));
+    };
+    // Every content adds tasks
+    let Some(role) = state.role(user_id, &server_selected) else {
+        bot.send_message(msg.chat.id, MsgUnauthorized { user_id })
+            .reply_to(msg)
+            .await?;
         return Ok(ControlFlow::Break(()));
     };
+    if role < Role::Operator {
+        bot.send_message(
+            msg.chat.id,
+            MsgForbidden {
+                role,
+                required: Role::Operator,
+            },
+        )
+        .reply_to(msg)
+        .await?;
+        return Ok(ControlFlow::Break(()));
+    }
 
     // extract all magnet links with regexp to Vec<String>.
     // TODO: extract and pass more query parameters.
@@ -169,11 +374,12 @@ async fn handle_message_content(
         }
     }
 
+    let download_config = server_selected.download_config();
     if !magnets.is_empty() {
         let text: String = MsgDownloadMagnetConfirm { magnets: &magnets }.into();
         let keyboard = make_download_confirm_keyboard(
-            &server_selected.download_config.magnet_dirs,
-            &server_selected.download_config.default_dir,
+            &download_config.magnet_dirs,
+            &download_config.default_dir,
             |dir| {
                 let uuid = uuid::Uuid::new_v4().simple().to_string();
                 let callback = format!("uri|{uuid}");
@@ -187,7 +393,7 @@ async fn handle_message_content(
 
         bot.send_message(msg.chat.id, text)
             .reply_markup(keyboard)
-            .reply_parameters(ReplyParameters::new(msg.id))
+            .reply_to(msg)
             .await?;
         return Ok(ControlFlow::Break(()));
     }
@@ -202,8 +408,8 @@ async fn handle_message_content(
     if !http_links.is_empty() {
         let text: String = MsgDownloadLinkConfirm { links: &http_links }.into();
         let keyboard = make_download_confirm_keyboard(
-            &server_selected.download_config.link_dirs,
-            &server_selected.download_config.default_dir,
+            &download_config.link_dirs,
+            &download_config.default_dir,
             |dir| {
                 let uuid = uuid::Uuid::new_v4().simple().to_string();
                 let callback = format!("uri|{uuid}");
@@ -217,37 +423,87 @@ async fn handle_message_content(
 
         bot.send_message(msg.chat.id, text)
             .reply_markup(keyboard)
-            .reply_parameters(ReplyParameters::new(msg.id))
+            .reply_to(msg)
             .await?;
         return Ok(ControlFlow::Break(()));
     }
 
     // extract all torrent files.
     if let Some(document) = msg.document() {
-        if document.file.size > MAX_TORRENT_SIZE {
+        if document.file.size > state.torrent_size_limit() {
             bot.send_message(msg.chat.id, "File size too large!")
+                .message_thread_id_opt(topic_of(msg))
                 .await?;
             return Ok(ControlFlow::Break(()));
         }
         let file_id = document.file.id.to_string();
-        let text = MsgDownloadTorrentConfirm { document };
-        let keyboard = make_download_confirm_keyboard(
-            &server_selected.download_config.torrent_dirs,
-            &server_selected.download_config.default_dir,
-            |dir| {
-                let uuid = uuid::Uuid::new_v4().simple().to_string();
-                let callback = format!("t|{uuid}");
-                state
-                    .file_cache
-                    .lock()
-                    .insert(uuid, (dir.into(), file_id.clone()));
-                callback
-            },
+        let name = document
+            .file_name
+            .clone()
+            .unwrap_or_else(|| format!("file_{}", document.file.id));
+
+        // Parse the torrent to validate it and let files be selected before adding it.
+        let data =
+            match tokio::time::timeout(ARIA2_OP_TIMEOUT, get_torrent_file(bot, &file_id, &state))
+                .await
+            {
+                Ok(Ok(data)) => data,
+                Ok(Err(e)) => {
+                    tracing::warn!("Unable to download torrent {name}: {e}");
+                    bot.send_message(msg.chat.id, format!("Unable to download {name}: {e}"))
+                        .reply_to(msg)
+                        .await?;
+                    return Ok(ControlFlow::Break(()));
+                }
+                Err(_) => {
+                    tracing::warn!("Download torrent {name} timeout");
+                    bot.send_message(msg.chat.id, format!("Download {name} timeout!"))
+                        .reply_to(msg)
+                        .await?;
+                    return Ok(ControlFlow::Break(()));
+                }
+            };
+        let meta = match TorrentMeta::parse(&data) {
+            Ok(meta) => meta,
+            Err(error) => {
+                bot.send_message(
+                    msg.chat.id,
+                    MsgInvalidTorrent {
+                        name: &name,
+                        error: &error,
+                    },
+                )
+                .reply_to(msg)
+                .await?;
+                return Ok(ControlFlow::Break(()));
+            }
+        };
+
+        let text: String = MsgDownloadTorrentConfirm {
+            meta: &meta,
+            selected: (meta.files.len() > 1).then_some(meta.files.len()),
+        }
+        .into();
+        let selection_uuid = if meta.files.len() > 1 {
+            let uuid = uuid::Uuid::new_v4().simple().to_string();
+            state
+                .torrent_selection
+                .lock()
+                .insert(uuid.clone(), TorrentSelection::new(file_id.clone(), meta));
+            Some(uuid)
+        } else {
+            None
+        };
+        let keyboard = make_torrent_confirm_keyboard(
+            &state,
+            &server_selected,
+            &file_id,
+            selection_uuid.as_deref(),
         );
 
         bot.send_message(msg.chat.id, text)
             .reply_markup(keyboard)
-            .reply_parameters(ReplyParameters::new(msg.id))
+            .reply_to(msg)
             .await?;
         return Ok(ControlFlow::Break(()));
     }
@@ -262,15 +518,27 @@ pub async fn callback_handler(
     state: Arc<State>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let qid = q.id.clone();
+    let from = q.from;
     let (Some(user_data), Some(MaybeInaccessibleMessage::Regular(q))) = (q.data, q.message) else {
         return Ok(());
     };
+    let thread_id = topic_of(&q);
     let id = q.id;
     let chat = q.chat;
 
+    // Members of allowed groups press as themselves, groups authorized as a whole act as one
+    let user_id = state.principal(&chat, Some(&from));
+    if state.authorized(user_id).is_none() {
+        bot.answer_callback_query(qid)
+            .text(MsgUnauthorized { user_id })
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+
     let user_data = UserData::from_str(&user_data)?;
     if let UserData::SwitchServer(server_name) = user_data {
-        let msg = match state.try_select(chat.id.0, &server_name) {
+        let msg = match state.try_select(chat.id.0, user_id, &server_name) {
             crate::state::SelectResult::Success => MsgSwitchResult::Success {
                 server_name: &server_name,
             },
@@ -281,47 +549,205 @@ pub async fn callback_handler(
         return Ok(());
     };
 
-    let Some(server_selected) = state.selected(chat.id.0) else {
-        select_or_unauthorized(&bot, chat.id, None, &state).await?;
+    let Some(server_selected) = state.selected(chat.id.0, user_id) else {
+        send_switch_prompt(&bot, chat.id, user_id, None, thread_id, &state).await?;
+        return Ok(());
+    };
+    let Some(role) = state.role(user_id, &server_selected) else {
+        bot.answer_callback_query(qid)
+            .text(MsgUnauthorized { user_id })
+            .show_alert(true)
+            .await?;
         return Ok(());
     };
+    let audit = Auditor::new(
+        &state.audit,
+        from.id.0 as i64,
+        from.username.as_deref(),
+        &server_selected.name,
+    );
+    let required = user_data.required_role();
+    if role < required {
+        bot.answer_callback_query(qid)
+            .text(MsgForbidden { role, required })
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
 
     match user_data {
         UserData::Task(gid) => {
-            handle_task_view(&bot, &server_selected, chat.id, id, &gid).await?;
+            handle_task_view(&bot, &server_selected, chat.id, id, thread_id, &gid, role).await?;
         }
-        UserData::TaskPage(page) => {
-            handle_task_page(&bot, &server_selected, chat.id, id, qid, page).await?;
+        UserData::TaskPage(page, filter) => {
+            let changed =
+                handle_task_page(&bot, &server_selected, chat.id, id, page, filter, role).await?;
+            if !changed {
+                bot.answer_callback_query(qid).await?;
+            }
+        }
+        UserData::FilterTasks(filter) => {
+            let changed =
+                handle_task_page(&bot, &server_selected, chat.id, id, 0, filter, role).await?;
+            if !changed {
+                bot.answer_callback_query(qid).await?;
+            }
         }
         UserData::TaskPageInfo => {
             bot.answer_callback_query(qid).await?;
         }
         UserData::PauseTask(gid) => {
             let res = server_selected.client.pause(&gid).await;
+            audit.record("pause", Some(&gid), None, &res).await;
             bot.edit_message_text(chat.id, id, MsgTaskActionResult::Pause(&gid, &res))
                 .await?;
         }
         UserData::ResumeTask(gid) => {
             let res = server_selected.client.resume(&gid).await;
+            audit.record("resume", Some(&gid), None, &res).await;
             bot.edit_message_text(chat.id, id, MsgTaskActionResult::Resume(&gid, &res))
                 .await?;
         }
-        UserData::RemoveTask(gid) => {
-            let res = server_selected.client.remove(&gid).await;
-            bot.edit_message_text(chat.id, id, MsgTaskActionResult::Remove(&gid, &res))
+        UserData::RetryTask(gid) => {
+            handle_retry_task(&bot, &state, &server_selected, &audit, chat.id, id, &gid).await?;
+        }
+        UserData::RemoveTask(ref gid) | UserData::RemoveTaskFiles(ref gid) => {
+            let gid = gid.clone();
+            let action = match user_data {
+                UserData::RemoveTaskFiles(_) => ConfirmAction::RemoveFiles { gid },
+                _ => ConfirmAction::Remove { gid },
+            };
+            if state.confirms(from.id.0 as i64) {
+                // Reply instead of editing, the task message keeps updating
+                send_confirm_prompt(
+                    &bot,
+                    &state,
+                    &server_selected,
+                    chat.id,
+                    id,
+                    thread_id,
+                    action,
+                )
                 .await?;
+            } else {
+                let text = run_confirm_action(&server_selected, &audit, &action).await;
+                bot.edit_message_text(chat.id, id, text).await?;
+            }
+        }
+        UserData::UploadTask(gid) => {
+            if !state.start_upload(&server_selected.name, &gid) {
+                bot.answer_callback_query(qid)
+                    .text("Already sending files of this task.")
+                    .await?;
+                return Ok(());
+            }
+            // Sending may take long, the status message reports the progress
+            bot.answer_callback_query(qid).await?;
+            let (state, server) = (state.clone(), server_selected.clone());
+            tokio::spawn(async move {
+                let res =
+                    handle_upload_task(&bot, &state, &server, chat.id, id, thread_id, &gid).await;
+                state.finish_upload(&server.name, &gid);
+                if let Err(e) = res {
+                    tracing::error!("Failed to send files of task {gid}: {e}");
+                    let _ = bot
+                        .send_message(chat.id, format!("Failed to send files: {e}"))
+                        .reply_parameters(ReplyParameters::new(id))
+                        .message_thread_id_opt(thread_id)
+                        .await;
+                }
+            });
+        }
+        UserData::Confirm(uuid) => {
+            let text = match state.take_confirm(&uuid, &server_selected.name) {
+                ConfirmResult::Run(action) => {
+                    run_confirm_action(&server_selected, &audit, &action).await
+                }
+                ConfirmResult::OtherServer(server) => {
+                    // Keep the buttons to confirm after switching back
+                    bot.answer_callback_query(qid)
+                        .text(format!(
+                            "This was asked on server {server}, switch back to it and try again."
+                        ))
+                        .show_alert(true)
+                        .await?;
+                    return Ok(());
+                }
+                ConfirmResult::Expired => "This confirmation has expired.".to_string(),
+            };
+            bot.edit_message_text(chat.id, id, text).await?;
         }
         UserData::AddUri(uuid) => {
-            handle_add_uri(&bot, &state, &server_selected, chat.id, id, uuid).await?;
+            handle_add_uri(&bot, &state, &server_selected, &audit, chat.id, id, uuid).await?;
         }
         UserData::AddTorrent(uuid) => {
-            handle_add_torrent(&bot, &state, &server_selected, chat.id, id, uuid).await?;
+            handle_add_torrent(&bot, &state, &server_selected, &audit, chat.id, id, uuid).await?;
         }
-        UserData::RefreshList(page) => {
-            handle_refresh_list(&bot, &server_selected, chat.id, id, page).await?;
+        UserData::RefreshList(page, filter) => {
+            handle_refresh_list(&bot, &server_selected, chat.id, id, page, filter, role).await?;
         }
         UserData::RefreshTask(gid) => {
-            handle_refresh_task(&bot, &server_selected, chat.id, id, &gid).await?;
+            handle_refresh_task(&bot, &server_selected, chat.id, id, &gid, role).await?;
+        }
+        UserData::BulkConfirm(action) => {
+            send_bulk_confirm(&bot, chat.id, id, thread_id, &server_selected, action).await?;
+        }
+        UserData::BulkRun(action) => {
+            handle_bulk_action(&bot, &state, &server_selected, &audit, chat.id, id, action).await?;
+        }
+        UserData::Cancel(confirm) => {
+            if let Some(UserData::Confirm(uuid)) = confirm.as_deref() {
+                state.cancel_confirm(uuid);
+            }
+            bot.edit_message_text(chat.id, id, "Cancelled.").await?;
+        }
+        UserData::RefreshStats => {
+            handle_refresh_stats(&bot, &server_selected, chat.id, id).await?;
+        }
+        UserData::TaskFiles(gid) => {
+            handle_task_files(&bot, &server_selected, chat.id, id, thread_id, &gid, None).await?;
+        }
+        UserData::TaskFilesPage(gid, page) => {
+            let page = Some(page);
+            handle_task_files(&bot, &server_selected, chat.id, id, thread_id, &gid, page).await?;
+        }
+        UserData::ToggleTaskFile(gid, pos, page) => {
+            let server = &server_selected;
+            handle_toggle_task_file(&bot, server, &audit, chat.id, id, &gid, pos, page).await?;
+        }
+        UserData::TorrentFiles(uuid, page) => {
+            handle_torrent_files(&bot, &state, chat.id, id, &uuid, page).await?;
+        }
+        UserData::ToggleTorrentFile(uuid, pos, page) => {
+            if let Some(selection) = state.torrent_selection.lock().get_mut(&uuid) {
+                selection.toggle(pos);
+            }
+            handle_torrent_files(&bot, &state, chat.id, id, &uuid, page).await?;
+        }
+        UserData::TorrentFilesDone(uuid) => {
+            handle_torrent_files_done(&bot, &state, &server_selected, chat.id, id, &uuid).await?;
+        }
+        UserData::SpeedLimit(gid) => {
+            let (text, keyboard) = render_speed_limits(&server_selected, gid.as_deref()).await;
+            let mut req = bot
+                .send_message(chat.id, text)
+                .reply_parameters(ReplyParameters::new(id))
+                .message_thread_id_opt(thread_id);
+            req.reply_markup = keyboard.map(Into::into);
+            req.await?;
+        }
+        UserData::SetSpeedLimit(gid, kind, speed) => {
+            handle_set_speed_limit(
+                &bot,
+                &server_selected,
+                &audit,
+                chat.id,
+                id,
+                gid.as_deref(),
+                kind,
+                speed,
+            )
+            .await?;
         }
         _ => (),
     }
@@ -335,8 +761,16 @@ async fn handle_task_view(
     server: &crate::state::ServerState,
     chat_id: ChatId,
     msg_id: MessageId,
+    thread_id: Option<ThreadId>,
     gid: &str,
+    role: Role,
 ) -> anyhow::Result<()> {
+    // Limits are not part of the task status, fetch them for the detailed view
+    if let Ok(Ok(limits)) =
+        tokio::time::timeout(ARIA2_OP_TIMEOUT, server.client.get_speed_limits(Some(gid))).await
+    {
+        server.tasks_cache.write().set_limits(gid.into(), limits);
+    }
     let Some((task_desc, task_status)) =
         server
             .tasks_cache
@@ -346,20 +780,30 @@ async fn handle_task_view(
                 (task_desc, task_status.status.unwrap_or(TaskStatus::Removed))
             })
     else {
-        bot.send_message(chat_id, MsgTaskNotFound { gid }).await?;
+        bot.send_message(chat_id, MsgTaskNotFound { gid })
+            .message_thread_id_opt(thread_id)
+            .await?;
         return Ok(());
     };
 
-    let keyboard = make_single_task_keyboard(gid, task_status);
+    let download_config = server.download_config();
+    let keyboard = make_single_task_keyboard(
+        gid,
+        task_status,
+        role,
+        download_config.delete_files.is_some(),
+        download_config.upload_files.is_some(),
+    );
     let msg = bot
         .send_message(chat_id, task_desc)
         .reply_markup(keyboard)
         .reply_parameters(ReplyParameters::new(msg_id))
+        .message_thread_id_opt(thread_id)
         .await?;
     server
         .tasks_cache
         .write()
-        .add_task_subscriber(gid.into(), chat_id, msg.id);
+        .add_task_subscriber(gid.into(), chat_id, msg.id, role);
     Ok(())
 }
 
@@ -368,6 +812,7 @@ async fn handle_add_uri(
     bot: &Bot,
     state: &State,
     server: &crate::state::ServerState,
+    audit: &Auditor<'_>,
     chat_id: ChatId,
     msg_id: MessageId,
     uuid: String,
@@ -387,6 +832,14 @@ async fn handle_add_uri(
     let AddUrisResult { gids, error } = match add_result {
         Ok(result) => result,
         Err(_) => {
+            audit
+                .record(
+                    "add_uri",
+                    None,
+                    Some(uris.join(" ")),
+                    &Err::<(), _>("timeout"),
+                )
+                .await;
             let retry_uuid = uuid::Uuid::new_v4().simple().to_string();
             let keyboard = make_retry_keyboard(format!("uri|{retry_uuid}"));
             state.uri_cache.lock().insert(retry_uuid, (dir, uris));
@@ -397,6 +850,17 @@ async fn handle_add_uri(
         }
     };
 
+    for (uri, gid) in uris.iter().zip(gids.iter()) {
+        audit
+            .record_ok("add_uri", Some(gid), Some(uri.clone()))
+            .await;
+    }
+    if let (Some(e), Some(uri)) = (error.as_ref(), uris.get(gids.len())) {
+        audit
+            .record("add_uri", None, Some(uri.clone()), &Err::<(), _>(e))
+            .await;
+    }
+
     let mut text = if gids.is_empty() {
         String::new()
     } else {
@@ -405,6 +869,14 @@ async fn handle_add_uri(
     for (uri, gid) in uris.iter().zip(gids.iter()) {
         text.push_str(&format!("{uri}: {gid}\n"));
     }
+    {
+        let mut tasks_cache = server.tasks_cache.write();
+        for gid in gids.iter() {
+            tasks_cache.track_task(gid.clone(), chat_id);
+        }
+        // Queued tasks emit no notification until started
+        tasks_cache.invalidate();
+    }
 
     if let Some(e) = error {
         if !gids.is_empty() {
@@ -436,20 +908,28 @@ async fn handle_add_torrent(
     bot: &Bot,
     state: &State,
     server: &crate::state::ServerState,
+    audit: &Auditor<'_>,
     chat_id: ChatId,
     msg_id: MessageId,
     uuid: String,
 ) -> anyhow::Result<()> {
-    let Some((dir, file_id)) = state.file_cache.lock().remove(&uuid) else {
+    let Some((dir, file_id, selection)) = state.file_cache.lock().remove(&uuid) else {
         bot.edit_message_text(chat_id, msg_id, format!("File cache {uuid} not found!"))
             .await?;
         return Ok(());
     };
+    let selected_files = selection.as_ref().and_then(|selection| {
+        state
+            .torrent_selection
+            .lock()
+            .get(selection)
+            .and_then(|s| s.selected_indexes())
+    });
 
-    // Download the torrent file from Telegram
+    // Usually cached since it was parsed on upload
     let file = match tokio::time::timeout(
         ARIA2_OP_TIMEOUT,
-        get_telegram_file(bot, file_id.as_str(), &state.http_client),
+        get_torrent_file(bot, file_id.as_str(), state),
     )
     .await
     {
@@ -461,8 +941,7 @@ async fn handle_add_torrent(
                 chat_id,
                 msg_id,
                 &format!("Download torrent file failed: {e}"),
-                dir,
-                file_id,
+                (dir, file_id, selection),
             )
             .await?;
             return Ok(());
@@ -474,8 +953,7 @@ async fn handle_add_torrent(
                 chat_id,
                 msg_id,
                 "Download torrent file timeout",
-                dir,
-                file_id,
+                (dir, file_id, selection),
             )
             .await?;
             return Ok(());
@@ -485,9 +963,33 @@ async fn handle_add_torrent(
     // Add torrent to aria2
     let res = tokio::time::timeout(
         ARIA2_OP_TIMEOUT,
-        server.client.add_torrent(&file, Some(dir.clone())),
+        server
+            .client
+            .add_torrent(&file, Some(dir.clone()), selected_files.as_deref()),
     )
     .await;
+    match &res {
+        Ok(res) => {
+            audit
+                .record(
+                    "add_torrent",
+                    res.as_deref().ok(),
+                    Some(dir.to_string()),
+                    res,
+                )
+                .await
+        }
+        Err(_) => {
+            audit
+                .record(
+                    "add_torrent",
+                    None,
+                    Some(dir.to_string()),
+                    &Err::<(), _>("timeout"),
+                )
+                .await
+        }
+    }
 
     let gid = match res {
         Ok(Ok(gid)) => gid,
@@ -498,8 +1000,7 @@ async fn handle_add_torrent(
                 chat_id,
                 msg_id,
                 &format!("Push add torrent task failed: {e}"),
-                dir,
-                file_id,
+                (dir, file_id, selection),
             )
             .await?;
             return Ok(());
@@ -511,14 +1012,26 @@ async fn handle_add_torrent(
                 chat_id,
                 msg_id,
                 "Add torrent task timeout",
-                dir,
-                file_id,
+                (dir, file_id, selection),
             )
             .await?;
             return Ok(());
         }
     };
 
+    {
+        let mut tasks_cache = server.tasks_cache.write();
+        tasks_cache.track_task(gid.clone(), chat_id);
+        tasks_cache.track_torrent(
+            gid.clone(),
+            TorrentSource {
+                file_id,
+                selected_files,
+            },
+        );
+        tasks_cache.invalidate();
+    }
+
     let text = format!(
         "Add download torrent task to {dir} successfully:\nGID: {gid}\n\nUse /task to list all tasks."
     );
@@ -534,18 +1047,360 @@ async fn store_file_and_show_retry(
     chat_id: ChatId,
     msg_id: MessageId,
     error_msg: &str,
-    dir: SmolStr,
-    file_id: String,
+    pending: (SmolStr, String, Option<String>),
 ) -> anyhow::Result<()> {
     let retry_uuid = uuid::Uuid::new_v4().simple().to_string();
     let keyboard = make_retry_keyboard(format!("t|{retry_uuid}"));
-    state.file_cache.lock().insert(retry_uuid, (dir, file_id));
+    state.file_cache.lock().insert(retry_uuid, pending);
     bot.edit_message_text(chat_id, msg_id, error_msg)
         .reply_markup(keyboard)
         .await?;
     Ok(())
 }
 
+/// Build the directory keyboard for a torrent, with a file selection button
+/// when the torrent has multiple files.
+fn make_torrent_confirm_keyboard(
+    state: &State,
+    server: &ServerState,
+    file_id: &str,
+    selection: Option<&str>,
+) -> InlineKeyboardMarkup {
+    let download_config = server.download_config();
+    let keyboard = make_download_confirm_keyboard(
+        &download_config.torrent_dirs,
+        &download_config.default_dir,
+        |dir| {
+            let uuid = uuid::Uuid::new_v4().simple().to_string();
+            let callback = format!("t|{uuid}");
+            state.file_cache.lock().insert(
+                uuid,
+                (dir.into(), file_id.to_string(), selection.map(Into::into)),
+            );
+            callback
+        },
+    );
+    match selection {
+        Some(selection) => keyboard.append_row([InlineKeyboardButton::callback(
+            "📂 Select files",
+            format!("tsel|{selection}|0"),
+        )]),
+        None => keyboard,
+    }
+}
+
+/// Handle showing a page of files of an uploaded torrent.
+async fn handle_torrent_files(
+    bot: &Bot,
+    state: &State,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    uuid: &str,
+    page: usize,
+) -> anyhow::Result<()> {
+    let Some(selection) = state.torrent_selection.lock().get(uuid).cloned() else {
+        bot.edit_message_text(chat_id, msg_id, format!("Torrent {uuid} not found!"))
+            .await?;
+        return Ok(());
+    };
+    let entries = selection.entries();
+    let keyboard = make_files_keyboard(
+        &entries,
+        page,
+        |pos| format!("tselt|{uuid}|{pos}|{page}"),
+        |page| format!("tsel|{uuid}|{page}"),
+        ("✅ Done", format!("tseld|{uuid}")),
+    );
+    if let Err(e) = bot
+        .edit_message_text(chat_id, msg_id, MsgFileSelection { files: &entries })
+        .reply_markup(keyboard)
+        .await
+    {
+        if !matches!(
+            e,
+            teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
+        ) {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Handle going back from file selection to the torrent confirm message.
+async fn handle_torrent_files_done(
+    bot: &Bot,
+    state: &State,
+    server: &ServerState,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    uuid: &str,
+) -> anyhow::Result<()> {
+    let Some(selection) = state.torrent_selection.lock().get(uuid).cloned() else {
+        bot.edit_message_text(chat_id, msg_id, format!("Torrent {uuid} not found!"))
+            .await?;
+        return Ok(());
+    };
+    let text = MsgDownloadTorrentConfirm {
+        meta: &selection.meta,
+        selected: Some(selection.selected_count()),
+    };
+    let keyboard = make_torrent_confirm_keyboard(state, server, &selection.file_id, Some(uuid));
+    bot.edit_message_text(chat_id, msg_id, text)
+        .reply_markup(keyboard)
+        .await?;
+    Ok(())
+}
+
+/// Render a page of files of an aria2 task.
+fn render_task_files(gid: &str, files: &[File], page: usize) -> (String, InlineKeyboardMarkup) {
+    let entries: Vec<_> = files
+        .iter()
+        .map(|f| FileEntry {
+            path: &f.path,
+            length: f.length,
+            selected: f.selected,
+        })
+        .collect();
+    let keyboard = make_files_keyboard(
+        &entries,
+        page,
+        |pos| format!("fsel|{gid}|{pos}|{page}"),
+        |page| format!("fpage|{gid}|{page}"),
+        ("🔄 Refresh", format!("fpage|{gid}|{page}")),
+    );
+    (MsgFileSelection { files: &entries }.into(), keyboard)
+}
+
+/// Handle showing files of a task, as a new message or by editing the current one.
+async fn handle_task_files(
+    bot: &Bot,
+    server: &ServerState,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    thread_id: Option<ThreadId>,
+    gid: &str,
+    page: Option<usize>,
+) -> anyhow::Result<()> {
+    let (text, keyboard) = match server.client.get_files(gid).await {
+        Ok(files) => {
+            let (text, keyboard) = render_task_files(gid, &files, page.unwrap_or(0));
+            (text, Some(keyboard))
+        }
+        Err(e) => (format!("Failed to fetch files of task {gid}: {e}"), None),
+    };
+    match page {
+        None => {
+            let mut req = bot
+                .send_message(chat_id, text)
+                .reply_parameters(ReplyParameters::new(msg_id))
+                .message_thread_id_opt(thread_id);
+            req.reply_markup = keyboard.map(Into::into);
+            req.await?;
+        }
+        Some(_) => {
+            let mut req = bot.edit_message_text(chat_id, msg_id, text);
+            req.reply_markup = keyboard;
+            if let Err(e) = req.await {
+                if !matches!(
+                    e,
+                    teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
+                ) {
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handle toggling a file of a task.
+#[allow(clippy::too_many_arguments)]
+async fn handle_toggle_task_file(
+    bot: &Bot,
+    server: &ServerState,
+    audit: &Auditor<'_>,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    gid: &str,
+    pos: usize,
+    page: usize,
+) -> anyhow::Result<()> {
+    let mut files = match server.client.get_files(gid).await {
+        Ok(files) => files,
+        Err(e) => {
+            bot.edit_message_text(
+                chat_id,
+                msg_id,
+                format!("Failed to fetch files of task {gid}: {e}"),
+            )
+            .reply_markup(make_retry_keyboard(format!("fpage|{gid}|{page}")))
+            .await?;
+            return Ok(());
+        }
+    };
+    let Some(file) = files.get_mut(pos) else {
+        return handle_task_files(bot, server, chat_id, msg_id, None, gid, Some(page)).await;
+    };
+    file.selected = !file.selected;
+    let indexes: Vec<u64> = files
+        .iter()
+        .filter(|f| f.selected)
+        .map(|f| f.index)
+        .collect();
+    // At least one file must stay selected
+    if !indexes.is_empty() {
+        let res = server.client.select_files(gid, &indexes).await;
+        let detail = indexes
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        audit
+            .record("select_files", Some(gid), Some(detail), &res)
+            .await;
+        if let Err(e) = res {
+            bot.edit_message_text(chat_id, msg_id, format!("Select files failed: {e}"))
+                .reply_markup(make_retry_keyboard(format!("fpage|{gid}|{page}")))
+                .await?;
+            return Ok(());
+        }
+    }
+    handle_task_files(bot, server, chat_id, msg_id, None, gid, Some(page)).await
+}
+
+/// Fetch speed limits of a task, or of the server without gid, with preset buttons.
+async fn render_speed_limits(
+    server: &ServerState,
+    gid: Option<&str>,
+) -> (String, Option<InlineKeyboardMarkup>) {
+    match server.client.get_speed_limits(gid).await {
+        Ok(limits) => {
+            if let Some(gid) = gid {
+                server.tasks_cache.write().set_limits(gid.into(), limits);
+            }
+            let keyboard = make_speed_limit_keyboard(gid.unwrap_or("*"), &limits);
+            (
+                MsgSpeedLimits {
+                    gid,
+                    limits: &limits,
+                }
+                .into(),
+                Some(keyboard),
+            )
+        }
+        Err(e) => (format!("Failed to fetch speed limits: {e}"), None),
+    }
+}
+
+/// Handle `/limit [task <gid>] [download] [upload]`.
+async fn handle_limit_command(
+    bot: &Bot,
+    msg: &Message,
+    server: &ServerState,
+    audit: &Auditor<'_>,
+    args: &str,
+) -> anyhow::Result<()> {
+    const USAGE: &str = "Usage: /limit [task <gid>] [download] [upload]";
+    let mut args = args.split_whitespace().peekable();
+    // The keyword keeps gids apart from speeds, which may look alike
+    let gid = match args.next_if_eq(&"task") {
+        Some(_) => match args.next() {
+            Some(gid) => Some(gid),
+            None => {
+                bot.send_message(msg.chat.id, USAGE).reply_to(msg).await?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let mut speeds = Vec::new();
+    for arg in args {
+        let Some(speed) = parse_speed(arg) else {
+            bot.send_message(
+                msg.chat.id,
+                format!("Invalid speed {arg}, use e.g. 2M, 512K or 0 for unlimited."),
+            )
+            .reply_to(msg)
+            .await?;
+            return Ok(());
+        };
+        speeds.push(speed);
+    }
+    if speeds.len() > 2 {
+        bot.send_message(msg.chat.id, USAGE).reply_to(msg).await?;
+        return Ok(());
+    }
+
+    for (kind, speed) in [LimitKind::Download, LimitKind::Upload]
+        .into_iter()
+        .zip(speeds)
+    {
+        let res = server.client.set_speed_limit(gid, kind, speed).await;
+        audit
+            .record(
+                "set_speed_limit",
+                gid,
+                Some(limit_detail(kind, speed)),
+                &res,
+            )
+            .await;
+        if let Err(e) = res {
+            bot.send_message(msg.chat.id, format!("Set speed limit failed: {e}"))
+                .reply_to(msg)
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let (text, keyboard) = render_speed_limits(server, gid).await;
+    let mut req = bot.send_message(msg.chat.id, text).reply_to(msg);
+    req.reply_markup = keyboard.map(Into::into);
+    req.await?;
+    Ok(())
+}
+
+/// Handle setting a speed limit preset.
+#[allow(clippy::too_many_arguments)]
+async fn handle_set_speed_limit(
+    bot: &Bot,
+    server: &ServerState,
+    audit: &Auditor<'_>,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    gid: Option<&str>,
+    kind: LimitKind,
+    speed: u64,
+) -> anyhow::Result<()> {
+    let res = server.client.set_speed_limit(gid, kind, speed).await;
+    audit
+        .record(
+            "set_speed_limit",
+            gid,
+            Some(limit_detail(kind, speed)),
+            &res,
+        )
+        .await;
+    if let Err(e) = res {
+        let retry = speed_limit_callback(gid.unwrap_or("*"), kind, speed);
+        bot.edit_message_text(chat_id, msg_id, format!("Set speed limit failed: {e}"))
+            .reply_markup(make_retry_keyboard(retry))
+            .await?;
+        return Ok(());
+    }
+    let (text, keyboard) = render_speed_limits(server, gid).await;
+    let mut req = bot.edit_message_text(chat_id, msg_id, text);
+    req.reply_markup = keyboard;
+    if let Err(e) = req.await {
+        if !matches!(
+            e,
+            teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
+        ) {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
 /// Handle refreshing the task list.
 async fn handle_refresh_list(
     bot: &Bot,
@@ -553,23 +1408,26 @@ async fn handle_refresh_list(
     chat_id: ChatId,
     msg_id: MessageId,
     page: usize,
+    filter: TaskFilter,
+    role: Role,
 ) -> anyhow::Result<()> {
     if let Err(e) = TasksCache::refresh(&server.tasks_cache, &server.client).await {
         bot.edit_message_text(chat_id, msg_id, format!("Failed to fetch tasks: {e}"))
-            .reply_markup(make_refresh_list_keyboard(page))
+            .reply_markup(make_refresh_list_keyboard(page, &filter))
             .await?;
         return Ok(());
     }
-    let tasks = server.tasks_cache.read().fmt_tasks();
+    let tasks = server.tasks_cache.read().fmt_tasks(&filter);
     let total_pages = task_list_page_count(tasks.len(), TASK_LIST_PAGE_SIZE);
     let page = page.min(total_pages.saturating_sub(1));
-    let keyboard = make_tasks_keyboard(tasks, page, TASK_LIST_PAGE_SIZE);
+    let keyboard = make_tasks_keyboard(tasks, page, TASK_LIST_PAGE_SIZE, &filter, role);
     bot.edit_message_text(
         chat_id,
         msg_id,
         MsgTaskList {
             page: page + 1,
             total_pages,
+            filter: &filter,
         },
     )
     .reply_markup(keyboard)
@@ -577,45 +1435,480 @@ async fn handle_refresh_list(
     server
         .tasks_cache
         .write()
-        .add_list_subscriber(chat_id, msg_id, page);
+        .add_list_subscriber(chat_id, msg_id, page, filter, role);
     Ok(())
 }
 
+/// Handle switching the page or the filter of the task list, returns false if
+/// the list already shows them.
 async fn handle_task_page(
     bot: &Bot,
     server: &crate::state::ServerState,
     chat_id: ChatId,
     msg_id: MessageId,
-    qid: teloxide::types::CallbackQueryId,
     page: usize,
-) -> anyhow::Result<()> {
-    let current_page = server
+    filter: TaskFilter,
+    role: Role,
+) -> anyhow::Result<bool> {
+    let unchanged = server
         .tasks_cache
         .read()
-        .list_subscriber_page(chat_id, msg_id);
-    if current_page == Some(page) {
-        bot.answer_callback_query(qid).await?;
-        return Ok(());
+        .list_subscriber_view(chat_id, msg_id)
+        .is_some_and(|(current_page, current_filter)| {
+            current_page == page && current_filter == &filter
+        });
+    if unchanged {
+        return Ok(false);
     }
 
     server
         .tasks_cache
         .write()
-        .update_list_subscriber_page(chat_id, msg_id, page);
+        .update_list_subscriber(chat_id, msg_id, page, filter.clone(), role);
 
     TasksCache::refresh(&server.tasks_cache, &server.client).await?;
-    let tasks = server.tasks_cache.read().fmt_tasks();
+    let tasks = server.tasks_cache.read().fmt_tasks(&filter);
     let total_pages = task_list_page_count(tasks.len(), TASK_LIST_PAGE_SIZE);
     let page = page.min(total_pages.saturating_sub(1));
-    let keyboard = make_tasks_keyboard(tasks, page, TASK_LIST_PAGE_SIZE);
+    let keyboard = make_tasks_keyboard(tasks, page, TASK_LIST_PAGE_SIZE, &filter, role);
     let text: String = MsgTaskList {
         page: page + 1,
         total_pages,
+        filter: &filter,
     }
     .into();
     bot.edit_message_text(chat_id, msg_id, text)
         .reply_markup(keyboard)
         .await?;
+    Ok(true)
+}
+
+/// Re-add a stopped task, from its uploaded torrent when known, and forget the stale one.
+async fn retry_task(
+    bot: &Bot,
+    state: &State,
+    server: &ServerState,
+    chat_id: ChatId,
+    task: &Status,
+) -> anyhow::Result<SmolStr> {
+    let gid = task
+        .gid
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("task has no gid"))?;
+    let torrent = server.tasks_cache.read().torrent_source(gid).cloned();
+    let new_gid = match torrent {
+        Some(TorrentSource {
+            file_id,
+            selected_files,
+        }) => {
+            let data =
+                tokio::time::timeout(ARIA2_OP_TIMEOUT, get_torrent_file(bot, &file_id, state))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("Download torrent file timeout"))??;
+            let new_gid = server
+                .client
+                .add_torrent(
+                    &data,
+                    task.dir.as_deref().map(Into::into),
+                    selected_files.as_deref(),
+                )
+                .await?;
+            if let Err(e) = server.client.remove_download_result(gid).await {
+                tracing::warn!("Unable to remove download result of retried task {gid}: {e}");
+            }
+            new_gid
+        }
+        None => server.client.retry_task(task).await?,
+    };
+    server
+        .tasks_cache
+        .write()
+        .replace_task(gid, new_gid.clone(), chat_id);
+    Ok(new_gid)
+}
+
+/// Handle retrying an errored task.
+async fn handle_retry_task(
+    bot: &Bot,
+    state: &State,
+    server: &ServerState,
+    audit: &Auditor<'_>,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    gid: &str,
+) -> anyhow::Result<()> {
+    if let Err(e) = TasksCache::refresh(&server.tasks_cache, &server.client).await {
+        bot.edit_message_text(chat_id, msg_id, format!("Failed to fetch tasks: {e}"))
+            .reply_markup(make_retry_keyboard(format!("retry|{gid}")))
+            .await?;
+        return Ok(());
+    }
+    let task = server.tasks_cache.read().get_task(gid);
+    let Some(task) = task else {
+        bot.edit_message_text(chat_id, msg_id, MsgTaskNotFound { gid })
+            .await?;
+        return Ok(());
+    };
+    if task.status != Some(TaskStatus::Error) {
+        bot.edit_message_text(chat_id, msg_id, format!("Task {gid} has not failed."))
+            .await?;
+        return Ok(());
+    }
+    let res = retry_task(bot, state, server, chat_id, &task).await;
+    audit
+        .record("retry", Some(gid), retried_detail(&res), &res)
+        .await;
+    // aria2 sends no notification for removed results
+    server.tasks_cache.write().invalidate();
+    bot.edit_message_text(chat_id, msg_id, MsgTaskActionResult::Retry(gid, &res))
+        .await?;
+    Ok(())
+}
+
+/// Ask for confirmation before a bulk action.
+async fn send_bulk_confirm(
+    bot: &Bot,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    thread_id: Option<ThreadId>,
+    server: &ServerState,
+    action: BulkAction,
+) -> anyhow::Result<()> {
+    let mut count = 0;
+    if action.per_task() {
+        if let Err(e) = TasksCache::refresh(&server.tasks_cache, &server.client).await {
+            bot.send_message(chat_id, format!("Failed to fetch tasks: {e}"))
+                .reply_parameters(ReplyParameters::new(reply_to))
+                .message_thread_id_opt(thread_id)
+                .await?;
+            return Ok(());
+        }
+        count = server.tasks_cache.read().tasks_in(TaskStatus::Error).len();
+        if count == 0 {
+            bot.send_message(chat_id, "There is no errored task.")
+                .reply_parameters(ReplyParameters::new(reply_to))
+                .message_thread_id_opt(thread_id)
+                .await?;
+            return Ok(());
+        }
+    }
+    bot.send_message(chat_id, MsgBulkConfirm { action, count })
+        .reply_markup(make_confirm_keyboard(format!(
+            "bulkrun|{}",
+            action.to_callback()
+        )))
+        .reply_parameters(ReplyParameters::new(reply_to))
+        .message_thread_id_opt(thread_id)
+        .await?;
+    Ok(())
+}
+
+/// Ask to confirm a remove or purge before running it.
+async fn send_confirm_prompt(
+    bot: &Bot,
+    state: &State,
+    server: &ServerState,
+    chat_id: ChatId,
+    reply_to: MessageId,
+    thread_id: Option<ThreadId>,
+    action: ConfirmAction,
+) -> anyhow::Result<()> {
+    let text = String::from(MsgConfirm { action: &action });
+    let uuid = state.request_confirm(action, &server.name);
+    bot.send_message(chat_id, text)
+        .reply_markup(make_confirm_keyboard(format!("confirm|{uuid}")))
+        .reply_parameters(ReplyParameters::new(reply_to))
+        .message_thread_id_opt(thread_id)
+        .await?;
+    Ok(())
+}
+
+/// Run a remove or purge, confirmed or not asked to be, and describe the outcome.
+async fn run_confirm_action(
+    server: &ServerState,
+    audit: &Auditor<'_>,
+    action: &ConfirmAction,
+) -> String {
+    match action {
+        ConfirmAction::Remove { gid } => {
+            let res = server.client.remove(gid).await;
+            audit.record("remove", Some(gid), None, &res).await;
+            MsgTaskActionResult::Remove(gid, &res).into()
+        }
+        ConfirmAction::RemoveFiles { gid } => {
+            let res = remove_task_files(server, gid).await;
+            let detail = res.as_ref().ok().map(|report| {
+                format!(
+                    "deleted {} files, refused {}",
+                    report.deleted.len(),
+                    report.refused.len()
+                )
+            });
+            audit.record("remove_files", Some(gid), detail, &res).await;
+            MsgRemoveFiles { gid, res: &res }.into()
+        }
+        ConfirmAction::Purge => {
+            let res = server.client.purge_downloaded().await;
+            audit.record("purge", None, None, &res).await;
+            // aria2 sends no notification for purged results
+            server.tasks_cache.write().invalidate();
+            MsgTaskActionResult::Purge(&res).into()
+        }
+    }
+}
+
+/// Send the completed files of a task to the chat, with progress in a status message.
+async fn handle_upload_task(
+    bot: &Bot,
+    state: &State,
+    server: &ServerState,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    thread_id: Option<ThreadId>,
+    gid: &str,
+) -> anyhow::Result<()> {
+    let reply = |text: String| {
+        bot.send_message(chat_id, text)
+            .reply_parameters(ReplyParameters::new(msg_id))
+            .message_thread_id_opt(thread_id)
+    };
+    let Some(config) = server.download_config().upload_files.clone() else {
+        reply("Sending files is not enabled on this server.".into()).await?;
+        return Ok(());
+    };
+    let task = match server.client.get_task(gid).await {
+        Ok(task) if task.status == Some(TaskStatus::Complete) => task,
+        Ok(_) => {
+            reply("Only completed tasks can be sent.".into()).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            reply(format!("Failed to fetch task {gid}: {e}")).await?;
+            return Ok(());
+        }
+    };
+    let paths: Vec<String> = task
+        .files
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|file| file.selected && !file.path.is_empty())
+        .map(|file| file.path)
+        .collect();
+    let (total, limit) = (paths.len(), state.upload_limit());
+    let progress = MsgUploadProgress {
+        done: 0,
+        total,
+        current: None,
+    };
+    let status = reply(progress.into()).await?;
+
+    let mut sent = 0;
+    let mut failed = Vec::new();
+    for (done, path) in paths.into_iter().enumerate() {
+        let name = std::path::Path::new(&path)
+            .file_name()
+            .map_or_else(|| path.clone(), |name| name.to_string_lossy().into_owned());
+        let config = config.clone();
+        let resolved =
+            tokio::task::spawn_blocking(move || crate::files::resolve_file(&config, &path)).await;
+        let (local, size) = match resolved.map_err(anyhow::Error::from).and_then(|res| res) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                failed.push((name, UploadFailure::Error(e.to_string())));
+                continue;
+            }
+        };
+        if size > limit {
+            failed.push((name, UploadFailure::TooLarge { size, limit }));
+            continue;
+        }
+        let file = match tokio::fs::File::open(&local).await {
+            Ok(file) => file,
+            Err(e) => {
+                failed.push((name, UploadFailure::Error(e.to_string())));
+                continue;
+            }
+        };
+
+        let (reader, read) = ProgressReader::new(file);
+        let mut req = state
+            .upload_bot
+            .send_document(chat_id, InputFile::read(reader).file_name(name.clone()))
+            .reply_parameters(ReplyParameters::new(msg_id));
+        req.message_thread_id = thread_id;
+        let upload = req.send();
+        tokio::pin!(upload);
+        let start = tokio::time::Instant::now() + UPLOAD_PROGRESS_INTERVAL;
+        let mut ticker = tokio::time::interval_at(start, UPLOAD_PROGRESS_INTERVAL);
+        let res = loop {
+            tokio::select! {
+                res = &mut upload => break res,
+                _ = ticker.tick() => {
+                    let progress = MsgUploadProgress {
+                        done,
+                        total,
+                        current: Some((&name, read.load(Ordering::Relaxed), size)),
+                    };
+                    if let Err(e) = bot.edit_message_text(chat_id, status.id, progress).await {
+                        tracing::debug!("Failed to update upload progress: {e}");
+                    }
+                }
+            }
+        };
+        match res {
+            Ok(_) => sent += 1,
+            Err(e) => failed.push((name, UploadFailure::Error(e.to_string()))),
+        }
+    }
+
+    bot.edit_message_text(
+        chat_id,
+        status.id,
+        MsgUploadResult {
+            sent,
+            failed: &failed,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+/// Remove the task, then delete its files under the configured root.
+async fn remove_task_files(server: &ServerState, gid: &str) -> anyhow::Result<DeleteReport> {
+    let Some(config) = server.download_config().delete_files.clone() else {
+        anyhow::bail!("deleting files is not enabled on this server");
+    };
+    let task = server.client.get_task(gid).await?;
+    let stopped = matches!(
+        task.status,
+        Some(TaskStatus::Complete | TaskStatus::Error | TaskStatus::Removed)
+    );
+    if !stopped {
+        server.client.remove(gid).await?;
+        // aria2 stops downloads asynchronously, keep the files until it is done writing
+        let wait = async {
+            loop {
+                tokio::time::sleep(REMOVE_POLL_INTERVAL).await;
+                if let Ok(task) = server.client.get_task(gid).await {
+                    if task.status == Some(TaskStatus::Removed) {
+                        break;
+                    }
+                }
+            }
+        };
+        if tokio::time::timeout(REMOVE_STOP_TIMEOUT, wait)
+            .await
+            .is_err()
+        {
+            anyhow::bail!("task removed but did not stop in time, files are kept");
+        }
+    }
+    if let Err(e) = server.client.remove_download_result(gid).await {
+        tracing::warn!("Unable to remove download result of {gid}: {e}");
+    }
+    // aria2 sends no notification for removed results
+    server.tasks_cache.write().invalidate();
+
+    let paths: Vec<String> = task
+        .files
+        .unwrap_or_default()
+        .into_iter()
+        .map(|file| file.path)
+        .collect();
+    tokio::task::spawn_blocking(move || {
+        crate::files::delete_files(&config, paths.iter().map(String::as_str))
+    })
+    .await?
+}
+
+/// Handle a confirmed bulk action, reporting the outcome of every task.
+async fn handle_bulk_action(
+    bot: &Bot,
+    state: &State,
+    server: &ServerState,
+    audit: &Auditor<'_>,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    action: BulkAction,
+) -> anyhow::Result<()> {
+    let text: String = match action {
+        BulkAction::PauseAll => {
+            let res = server.client.pause_all().await;
+            audit.record("pause_all", None, None, &res).await;
+            MsgTaskActionResult::PauseAll(&res).into()
+        }
+        BulkAction::ResumeAll => {
+            let res = server.client.resume_all().await;
+            audit.record("resume_all", None, None, &res).await;
+            MsgTaskActionResult::ResumeAll(&res).into()
+        }
+        BulkAction::RemoveErrored | BulkAction::RetryErrored => {
+            if let Err(e) = TasksCache::refresh(&server.tasks_cache, &server.client).await {
+                bot.edit_message_text(chat_id, msg_id, format!("Failed to fetch tasks: {e}"))
+                    .reply_markup(make_retry_keyboard(format!(
+                        "bulkrun|{}",
+                        action.to_callback()
+                    )))
+                    .await?;
+                return Ok(());
+            }
+            let tasks = server.tasks_cache.read().tasks_in(TaskStatus::Error);
+            let text = if action == BulkAction::RemoveErrored {
+                let mut results = Vec::with_capacity(tasks.len());
+                for task in tasks.iter() {
+                    let gid: SmolStr = task.gid.as_deref().unwrap_or_default().into();
+                    let res = server.client.remove_download_result(&gid).await;
+                    audit.record("remove_result", Some(&gid), None, &res).await;
+                    results.push((gid, res));
+                }
+                MsgTaskActionResult::RemoveErrored(&results).into()
+            } else {
+                let mut results = Vec::with_capacity(tasks.len());
+                for task in tasks.iter() {
+                    let gid: SmolStr = task.gid.as_deref().unwrap_or_default().into();
+                    let res = retry_task(bot, state, server, chat_id, task).await;
+                    audit
+                        .record("retry", Some(&gid), retried_detail(&res), &res)
+                        .await;
+                    results.push((gid, res));
+                }
+                MsgTaskActionResult::<_, ()>::RetryErrored(&results).into()
+            };
+            // aria2 sends no notification for removed results
+            server.tasks_cache.write().invalidate();
+            text
+        }
+    };
+    bot.edit_message_text(chat_id, msg_id, text).await?;
+    Ok(())
+}
+
+/// Handle refreshing the server stats.
+async fn handle_refresh_stats(
+    bot: &Bot,
+    server: &ServerState,
+    chat_id: ChatId,
+    msg_id: MessageId,
+) -> anyhow::Result<()> {
+    if let Err(e) = TasksCache::refresh_stats(&server.tasks_cache, &server.client).await {
+        bot.edit_message_text(chat_id, msg_id, format!("Failed to fetch stats: {e}"))
+            .reply_markup(make_refresh_stats_keyboard())
+            .await?;
+        return Ok(());
+    }
+    let text = server.tasks_cache.read().fmt_stats().unwrap_or_default();
+    if let Err(e) = bot.edit_message_text(chat_id, msg_id, text).await {
+        if !matches!(
+            e,
+            teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
+        ) {
+            return Err(e.into());
+        }
+    }
+    server
+        .tasks_cache
+        .write()
+        .add_stats_subscriber(chat_id, msg_id);
     Ok(())
 }
 
@@ -626,6 +1919,7 @@ async fn handle_refresh_task(
     chat_id: ChatId,
     msg_id: MessageId,
     gid: &str,
+    role: Role,
 ) -> anyhow::Result<()> {
     if let Err(e) = TasksCache::refresh(&server.tasks_cache, &server.client).await {
         bot.edit_message_text(chat_id, msg_id, format!("Failed to fetch tasks: {e}"))
@@ -646,29 +1940,232 @@ async fn handle_refresh_task(
             .await?;
         return Ok(());
     };
-    let keyboard = make_single_task_keyboard(gid, task_status);
+    let download_config = server.download_config();
+    let keyboard = make_single_task_keyboard(
+        gid,
+        task_status,
+        role,
+        download_config.delete_files.is_some(),
+        download_config.upload_files.is_some(),
+    );
     bot.edit_message_text(chat_id, msg_id, task_desc)
         .reply_markup(keyboard)
         .await?;
     server
         .tasks_cache
         .write()
-        .add_task_subscriber(gid.into(), chat_id, msg_id);
+        .add_task_subscriber(gid.into(), chat_id, msg_id, role);
     Ok(())
 }
 
-/// Download a file from Telegram servers.
-async fn get_telegram_file(
+/// Handle user management commands of owners.
+async fn handle_owner_command(
     bot: &Bot,
-    file_id: &str,
-    http_client: &reqwest::Client,
-) -> anyhow::Result<Bytes> {
+    msg: &Message,
+    state: &State,
+    cmd: Command,
+) -> anyhow::Result<()> {
+    // Owners act as themselves, even in groups authorized as a whole
+    if !sender_id(msg).is_some_and(|user| state.is_owner(user)) {
+        bot.send_message(msg.chat.id, "Only owners can manage users.")
+            .reply_to(msg)
+            .await?;
+        return Ok(());
+    }
+    // Grants without auth_path are lost on restart
+    let memory_only = if state.persists_auth() {
+        ""
+    } else {
+        "\nOnly kept in memory, set auth_path to keep it across restarts."
+    };
+    let text = match cmd {
+        Command::Grant(args) => {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let parsed = match args.as_slice() {
+                [user, server, role] => Some((*user, Some(server.to_string()), *role)),
+                [user, role] => Some((*user, state.single_server(), *role)),
+                _ => None,
+            };
+            match parsed.and_then(|(user, server, role)| {
+                Some((user.parse::<i64>().ok()?, server, Role::from_name(role)?))
+            }) {
+                Some((user, Some(server), role)) => match state.grant(user, &server, role).await {
+                    Ok(_) => format!("Granted {role} on {server} to {user}.{memory_only}"),
+                    Err(e) => format!("Grant failed: {e}"),
+                },
+                Some((_, None, _)) => "Specify the server: /grant <user_id> <server> <role>".into(),
+                None => "Usage: /grant <user_id> [server] <viewer|operator|admin>".into(),
+            }
+        }
+        Command::Revoke(args) => {
+            let mut args = args.split_whitespace();
+            match (
+                args.next().and_then(|user| user.parse::<i64>().ok()),
+                args.next(),
+            ) {
+                (Some(user), server) => match state.revoke(user, server).await {
+                    Ok(0) => format!("No granted role of {user} to revoke."),
+                    Ok(n) => format!("Revoked {n} granted role(s) of {user}.{memory_only}"),
+                    Err(e) => format!("Revoke failed: {e}"),
+                },
+                (None, _) => "Usage: /revoke <user_id> [server]".into(),
+            }
+        }
+        Command::Users => MsgUsers {
+            users: &state.users(),
+        }
+        .into(),
+        _ => unreachable!(),
+    };
+    bot.send_message(msg.chat.id, text).reply_to(msg).await?;
+    Ok(())
+}
+
+/// Handle creating an invite code for the servers the user administers.
+async fn handle_invite_command(
+    bot: &Bot,
+    msg: &Message,
+    me: &Me,
+    state: &State,
+    user_id: i64,
+    args: &str,
+) -> anyhow::Result<()> {
+    let mut args = args.split_whitespace();
+    let Some(role) = args.next().and_then(Role::from_name) else {
+        bot.send_message(
+            msg.chat.id,
+            "Usage: /invite <viewer|operator|admin> [server ...]",
+        )
+        .reply_to(msg)
+        .await?;
+        return Ok(());
+    };
+    // Owners act as themselves, like for the user management commands
+    let inviter = sender_id(msg)
+        .filter(|user| state.is_owner(*user))
+        .unwrap_or(user_id);
+    let invitable = state.invitable_servers(inviter);
+    let requested: Vec<String> = args.map(String::from).collect();
+    let servers = if requested.is_empty() {
+        invitable
+    } else if let Some(server) = requested.iter().find(|s| !invitable.contains(s)) {
+        bot.send_message(
+            msg.chat.id,
+            format!("You can not invite to server {server}."),
+        )
+        .reply_to(msg)
+        .await?;
+        return Ok(());
+    } else {
+        requested
+    };
+    if servers.is_empty() {
+        bot.send_message(msg.chat.id, "Only admins can create invite codes.")
+            .reply_to(msg)
+            .await?;
+        return Ok(());
+    }
+
+    let text: String = match state.create_invite(role, servers.clone()).await {
+        Ok(code) => MsgInvite {
+            link: me
+                .username
+                .as_ref()
+                .map(|username| format!("https://t.me/{username}?start={code}")),
+            code: &code,
+            role,
+            servers: shown_servers(state, &servers),
+            expire: INVITE_EXPIRE,
+        }
+        .into(),
+        Err(e) => format!("Unable to create invite code: {e}"),
+    };
+    bot.send_message(msg.chat.id, text).reply_to(msg).await?;
+    Ok(())
+}
+
+/// Handle redeeming an invite code sent with /start.
+async fn handle_redeem_invite(
+    bot: &Bot,
+    msg: &Message,
+    state: &State,
+    code: &str,
+) -> anyhow::Result<()> {
+    let Some(user_id) = sender_id(msg) else {
+        return Ok(());
+    };
+    let text: String = match state.redeem_invite(user_id, code).await {
+        Ok(Some(roles)) => MsgInviteRedeemed {
+            roles: &roles,
+            show_servers: state.single_server().is_none(),
+        }
+        .into(),
+        Ok(None) => "Invalid or expired invite code.".into(),
+        Err(e) => format!("Unable to redeem invite code: {e}"),
+    };
+    bot.send_message(msg.chat.id, text).reply_to(msg).await?;
+    Ok(())
+}
+
+/// Server names worth showing, none when there is only one server.
+fn shown_servers<'a>(state: &State, servers: &'a [String]) -> &'a [String] {
+    if state.single_server().is_some() {
+        &[]
+    } else {
+        servers
+    }
+}
+
+/// Audit detail of a speed limit change.
+fn limit_detail(kind: LimitKind, speed: u64) -> String {
+    let kind = match kind {
+        LimitKind::Download => "download",
+        LimitKind::Upload => "upload",
+    };
+    format!("{kind} {speed}")
+}
+
+/// Audit detail of a retried task, the gid replacing it.
+fn retried_detail(res: &anyhow::Result<SmolStr>) -> Option<String> {
+    res.as_ref().ok().map(|gid| format!("new gid {gid}"))
+}
+
+/// User who sent the message, rather than the chat it was sent in.
+fn sender_id(msg: &Message) -> Option<i64> {
+    msg.from.as_ref().map(|user| user.id.0 as i64)
+}
+
+/// Get an uploaded torrent file, downloading it from Telegram unless still cached.
+async fn get_torrent_file(bot: &Bot, file_id: &str, state: &State) -> anyhow::Result<Bytes> {
+    if let Some(data) = state.torrent_data.lock().get(file_id).cloned() {
+        return Ok(data);
+    }
+    let data = get_telegram_file(bot, file_id, state).await?;
+    state
+        .torrent_data
+        .lock()
+        .insert(file_id.to_string(), data.clone());
+    Ok(data)
+}
+
+/// Download a file from Telegram servers.
+///
+/// A local Bot API server returns absolute paths on its own disk instead,
+/// which telearia2 reads directly when running next to it.
+async fn get_telegram_file(bot: &Bot, file_id: &str, state: &State) -> anyhow::Result<Bytes> {
     let file = bot.get_file(file_id.to_owned().into()).await?;
+    if state.local_api() && std::path::Path::new(&file.path).is_absolute() {
+        let data = tokio::fs::read(&file.path)
+            .await
+            .map_err(|e| anyhow::anyhow!("unable to read {}: {e}", file.path))?;
+        return Ok(data.into());
+    }
     let url = bot
         .api_url()
         .join(&format!("file/bot{}/{}", bot.token(), file.path))
         .expect("failed to format file url");
-    let data = http_client
+    let data = state
+        .http_client
         .get(url)
         .send()
         .await?
@@ -678,37 +2175,66 @@ async fn get_telegram_file(
     Ok(data)
 }
 
-/// Show server selection or unauthorized message.
+/// Show server selection or unauthorized message in reply to a message.
 async fn select_or_unauthorized(
+    bot: &Bot,
+    msg: &Message,
+    user_id: i64,
+    state: &State,
+) -> anyhow::Result<()> {
+    send_switch_prompt(
+        bot,
+        msg.chat.id,
+        user_id,
+        Some(msg.id),
+        topic_of(msg),
+        state,
+    )
+    .await
+}
+
+/// Show server selection or unauthorized message.
+async fn send_switch_prompt(
     bot: &Bot,
     chat_id: ChatId,
+    user_id: i64,
     msg_id: Option<MessageId>,
+    thread_id: Option<ThreadId>,
     state: &State,
 ) -> anyhow::Result<()> {
-    if let Some(authorized) = state.authorized(chat_id.0) {
-        if authorized.size_hint().1 == Some(1) {
+    if let Some(authorized) = state.authorized(user_id) {
+        if authorized.len() == 1 {
             bot.send_message(
                 chat_id,
                 "No need to switch server, there is only one server.",
             )
             .reply_to_message_id_opt(msg_id)
+            .message_thread_id_opt(thread_id)
             .await?;
             return Ok(());
         }
-        let keyboard = make_switch_server_keyboard(authorized);
-        let selected = state.selected(chat_id.0);
+        let servers: Vec<_> = authorized
+            .iter()
+            .map(|s| (s.name.as_str(), s.client.health()))
+            .collect();
+        let keyboard =
+            make_switch_server_keyboard(servers.iter().map(|(name, h)| (*name, h.healthy())));
+        let selected = state.selected(chat_id.0, user_id);
         bot.send_message(
             chat_id,
             MsgSwitchPrompt {
                 current_server_name: selected.as_ref().map(|s| s.name.as_str()),
+                servers: &servers,
             },
         )
         .reply_markup(keyboard)
         .reply_to_message_id_opt(msg_id)
+        .message_thread_id_opt(thread_id)
         .await?;
     } else {
-        bot.send_message(chat_id, MsgUnauthorized { user_id: chat_id.0 })
+        bot.send_message(chat_id, MsgUnauthorized { user_id })
             .reply_to_message_id_opt(msg_id)
+            .message_thread_id_opt(thread_id)
             .await?;
     }
     Ok(())
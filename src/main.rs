@@ -1,14 +1,25 @@
 mod aria2;
+mod audit;
+mod auth;
 mod config;
 mod constants;
+mod files;
 mod format;
 mod handlers;
+mod metrics;
+mod role;
 mod state;
+mod store;
+mod torrent;
 mod utils;
 
+use aria2::LimitKind;
 use clap::Parser;
 use config::Config;
+use constants::{CONFIG_WATCH_INTERVAL, STATE_SAVE_INTERVAL};
+use role::Role;
 use smol_str::SmolStr;
+use state::{BulkAction, TaskFilter};
 use std::{error::Error, str::FromStr, sync::Arc, sync::LazyLock};
 use teloxide::{prelude::*, utils::command::BotCommands};
 
@@ -26,31 +37,97 @@ static HTTP_RE: LazyLock<regex::Regex> =
 enum Command {
     /// Display this text
     Help,
-    /// Start
-    Start,
+    /// Start, or redeem an invite code: /start <code>
+    Start(String),
     /// Id
     Id,
     /// Switch server
     Switch,
-    /// Task list
-    Task,
+    /// Task list, filter with /task active, error, complete or a name
+    Task(String),
     /// Purge all downloaded results
     Purge,
+    /// Show or change confirmation of remove and purge: /confirm [on|off]
+    Confirm(String),
+    /// Server statistics
+    Stats,
+    /// Pause all tasks
+    PauseAll,
+    /// Resume all tasks
+    ResumeAll,
+    /// Remove all errored tasks
+    RemoveErrored,
+    /// Retry all errored tasks
+    RetryErrored,
+    /// Show or set speed limits: /limit [task <gid>] [download] [upload], e.g. /limit 2M 512K
+    Limit(String),
+    /// Grant a role, owners only: /grant <user_id> [server] <viewer|operator|admin>
+    Grant(String),
+    /// Revoke granted roles, owners only: /revoke <user_id> [server]
+    Revoke(String),
+    /// List users and their roles, owners only
+    Users,
+    /// Create an invite code: /invite <viewer|operator|admin> [server ...]
+    Invite(String),
+    /// Recent changes on the server, admins only: /audit [count]
+    Audit(String),
+}
+
+impl Command {
+    /// Least role allowed to run the command on the selected server.
+    ///
+    /// None for commands that don't act on the selected server.
+    pub fn required_role(&self) -> Option<Role> {
+        let role = match self {
+            Command::Help | Command::Start(_) | Command::Id | Command::Switch => return None,
+            // Checked against every server involved by their own handlers
+            Command::Grant(_) | Command::Revoke(_) | Command::Users | Command::Invite(_) => {
+                return None
+            }
+            Command::Task(_) | Command::Stats | Command::Confirm(_) => Role::Viewer,
+            Command::PauseAll => BulkAction::PauseAll.required_role(),
+            Command::ResumeAll => BulkAction::ResumeAll.required_role(),
+            Command::RemoveErrored => BulkAction::RemoveErrored.required_role(),
+            Command::RetryErrored => BulkAction::RetryErrored.required_role(),
+            Command::Purge | Command::Limit(_) | Command::Audit(_) => Role::Admin,
+        };
+        Some(role)
+    }
 }
 
 #[derive(Debug)]
 pub enum UserData {
     Task(SmolStr),
-    TaskPage(usize),
+    TaskPage(usize, TaskFilter),
     TaskPageInfo,
     PauseTask(SmolStr),
     ResumeTask(SmolStr),
     RemoveTask(SmolStr),
+    RemoveTaskFiles(SmolStr),
+    UploadTask(SmolStr),
+    RetryTask(SmolStr),
     AddUri(String),
     AddTorrent(String),
     SwitchServer(SmolStr),
-    RefreshList(usize),
+    RefreshList(usize, TaskFilter),
+    FilterTasks(TaskFilter),
     RefreshTask(SmolStr),
+    RefreshStats,
+    TaskFiles(SmolStr),
+    TaskFilesPage(SmolStr, usize),
+    ToggleTaskFile(SmolStr, usize, usize),
+    TorrentFiles(String, usize),
+    ToggleTorrentFile(String, usize, usize),
+    TorrentFilesDone(String),
+    BulkConfirm(BulkAction),
+    BulkRun(BulkAction),
+    // the confirm button of the prompt, None for prompts sent before it was kept
+    Cancel(Option<Box<UserData>>),
+    // uuid of the pending remove or purge
+    Confirm(String),
+    // None targets the server wide limits
+    SpeedLimit(Option<SmolStr>),
+    SetSpeedLimit(Option<SmolStr>, LimitKind, u64),
 }
 
 #[derive(Debug)]
@@ -69,24 +146,103 @@ impl FromStr for UserData {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s == "rlist" {
-            return Ok(UserData::RefreshList(0));
+            return Ok(UserData::RefreshList(0, TaskFilter::All));
         }
         let Some((action, data)) = s.split_once('|') else {
             return Err(UserDataError);
         };
         match action {
             "task" => Ok(UserData::Task(data.into())),
-            "task_page" => Ok(UserData::TaskPage(data.parse().map_err(|_| UserDataError)?)),
+            "task_page" => {
+                let (page, filter) = parse_page_filter(data)?;
+                Ok(UserData::TaskPage(page, filter))
+            }
             "task_page_info" => Ok(UserData::TaskPageInfo),
             "pause" => Ok(UserData::PauseTask(data.into())),
             "resume" => Ok(UserData::ResumeTask(data.into())),
             "remove" => Ok(UserData::RemoveTask(data.into())),
+            "rmfiles" => Ok(UserData::RemoveTaskFiles(data.into())),
+            "upload" => Ok(UserData::UploadTask(data.into())),
+            "retry" => Ok(UserData::RetryTask(data.into())),
             "uri" => Ok(UserData::AddUri(data.into())),
             "t" => Ok(UserData::AddTorrent(data.into())),
-            "rlist" => Ok(UserData::RefreshList(
-                data.parse().map_err(|_| UserDataError)?,
+            "rlist" => {
+                let (page, filter) = parse_page_filter(data)?;
+                Ok(UserData::RefreshList(page, filter))
+            }
+            "tfilter" => Ok(UserData::FilterTasks(
+                TaskFilter::from_callback(data).ok_or(UserDataError)?,
             )),
             "rtask" => Ok(UserData::RefreshTask(data.into())),
+            "stats" => Ok(UserData::RefreshStats),
+            "files" => Ok(UserData::TaskFiles(data.into())),
+            "fpage" => {
+                let (gid, page) = data.split_once('|').ok_or(UserDataError)?;
+                Ok(UserData::TaskFilesPage(
+                    gid.into(),
+                    page.parse().map_err(|_| UserDataError)?,
+                ))
+            }
+            "fsel" => {
+                let mut parts = data.split('|');
+                let gid = parts.next().ok_or(UserDataError)?;
+                let pos = parts.next().ok_or(UserDataError)?;
+                let page = parts.next().ok_or(UserDataError)?;
+                Ok(UserData::ToggleTaskFile(
+                    gid.into(),
+                    pos.parse().map_err(|_| UserDataError)?,
+                    page.parse().map_err(|_| UserDataError)?,
+                ))
+            }
+            "tsel" => {
+                let (uuid, page) = data.split_once('|').ok_or(UserDataError)?;
+                Ok(UserData::TorrentFiles(
+                    uuid.into(),
+                    page.parse().map_err(|_| UserDataError)?,
+                ))
+            }
+            "tselt" => {
+                let mut parts = data.split('|');
+                let uuid = parts.next().ok_or(UserDataError)?;
+                let pos = parts.next().ok_or(UserDataError)?;
+                let page = parts.next().ok_or(UserDataError)?;
+                Ok(UserData::ToggleTorrentFile(
+                    uuid.into(),
+                    pos.parse().map_err(|_| UserDataError)?,
+                    page.parse().map_err(|_| UserDataError)?,
+                ))
+            }
+            "tseld" => Ok(UserData::TorrentFilesDone(data.into())),
+            "bulk" => Ok(UserData::BulkConfirm(
+                BulkAction::from_callback(data).ok_or(UserDataError)?,
+            )),
+            "bulkrun" => Ok(UserData::BulkRun(
+                BulkAction::from_callback(data).ok_or(UserDataError)?,
+            )),
+            "cancel" if data.is_empty() => Ok(UserData::Cancel(None)),
+            "cancel" => match UserData::from_str(data)? {
+                confirm @ (UserData::Confirm(_) | UserData::BulkRun(_)) => {
+                    Ok(UserData::Cancel(Some(Box::new(confirm))))
+                }
+                _ => Err(UserDataError),
+            },
+            "confirm" => Ok(UserData::Confirm(data.into())),
+            "limit" => Ok(UserData::SpeedLimit(parse_limit_target(data))),
+            "slimit" => {
+                let mut parts = data.split('|');
+                let target = parts.next().ok_or(UserDataError)?;
+                let kind = match parts.next().ok_or(UserDataError)? {
+                    "d" => LimitKind::Download,
+                    "u" => LimitKind::Upload,
+                    _ => return Err(UserDataError),
+                };
+                let speed = parts.next().ok_or(UserDataError)?;
+                Ok(UserData::SetSpeedLimit(
+                    parse_limit_target(target),
+                    kind,
+                    speed.parse().map_err(|_| UserDataError)?,
+                ))
+            }
             "switch" => {
                 let mut parts = data.split('|');
                 let server = parts.next().ok_or(UserDataError)?;
@@ -97,6 +253,62 @@ impl FromStr for UserData {
     }
 }
 
+impl UserData {
+    /// Least role allowed to press the button on the selected server.
+    pub fn required_role(&self) -> Role {
+        match self {
+            UserData::Task(_)
+            | UserData::TaskPage(..)
+            | UserData::TaskPageInfo
+            | UserData::SwitchServer(_)
+            | UserData::RefreshList(..)
+            | UserData::FilterTasks(_)
+            | UserData::RefreshTask(_)
+            | UserData::RefreshStats => Role::Viewer,
+            // Cancelling needs the role of what is cancelled
+            UserData::Cancel(confirm) => confirm
+                .as_deref()
+                .map_or(Role::Admin, UserData::required_role),
+            UserData::PauseTask(_)
+            | UserData::ResumeTask(_)
+            | UserData::RetryTask(_)
+            | UserData::AddUri(_)
+            | UserData::AddTorrent(_)
+            | UserData::UploadTask(_)
+            | UserData::TorrentFiles(..)
+            | UserData::ToggleTorrentFile(..)
+            | UserData::TorrentFilesDone(_) => Role::Operator,
+            UserData::BulkConfirm(action) | UserData::BulkRun(action) => action.required_role(),
+            // Only remove and purge are confirmed
+            UserData::RemoveTask(_)
+            | UserData::RemoveTaskFiles(_)
+            | UserData::Confirm(_)
+            | UserData::TaskFiles(_)
+            | UserData::TaskFilesPage(..)
+            | UserData::ToggleTaskFile(..)
+            | UserData::SpeedLimit(_)
+            | UserData::SetSpeedLimit(..) => Role::Admin,
+        }
+    }
+}
+
+/// Parse `page` or `page|filter`, the filter defaults to all tasks.
+fn parse_page_filter(data: &str) -> Result<(usize, TaskFilter), UserDataError> {
+    let (page, filter) = match data.split_once('|') {
+        Some((page, filter)) => (
+            page,
+            TaskFilter::from_callback(filter).ok_or(UserDataError)?,
+        ),
+        None => (data, TaskFilter::All),
+    };
+    Ok((page.parse().map_err(|_| UserDataError)?, filter))
+}
+
+/// `*` targets the server wide limits, anything else is a task gid.
+fn parse_limit_target(target: &str) -> Option<SmolStr> {
+    (target != "*").then(|| target.into())
+}
+
 #[derive(Parser, Debug, Default, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
@@ -117,7 +329,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let config = Config::load_from(&config_file).expect("unable to load config");
     tracing::info!("Config file {config_file} load successfully");
 
-    let bot = Bot::new(&config.telegram.token);
+    let mut bot = Bot::new(&config.telegram.token);
+    if let Some(api_url) = config.telegram.api_url.as_deref() {
+        let api_url = api_url
+            .parse()
+            .map_err(|e| format!("invalid telegram api_url {api_url}: {e}"))?;
+        bot = bot.set_api_url(api_url);
+    }
     let state = Arc::new(state::State::new(&config, bot.clone()).await?);
 
     let handler = dptree::entry()
@@ -125,11 +343,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .branch(Update::filter_callback_query().endpoint(handlers::callback_handler));
 
     tracing::info!("Bot created and running");
-    let mut dispatcher = Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![state])
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![state.clone()])
         .enable_ctrlc_handler()
         .build();
 
+    if let Some(metrics_config) = config.metrics.as_ref() {
+        let (listen, state) = (metrics_config.listen, state.clone());
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listen, state).await {
+                tracing::error!("Metrics endpoint on {listen} failed: {e}");
+            }
+        });
+    }
+
+    // Reload the config when the file changes or on SIGHUP
+    let reload = Arc::new(tokio::sync::Notify::new());
+    #[cfg(unix)]
+    {
+        let reload = reload.clone();
+        tokio::spawn(async move {
+            let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .expect("failed to register SIGHUP handler");
+            while sighup.recv().await.is_some() {
+                tracing::info!("Received SIGHUP, reloading config...");
+                reload.notify_one();
+            }
+        });
+    }
+    tokio::spawn(watch_config(config_file, state.clone(), reload));
+
     #[cfg(unix)]
     {
         let shutdown_token = dispatcher.shutdown_token();
@@ -143,6 +386,57 @@ async fn main() -> Result<(), Box<dyn Error>> {
         });
     }
 
-    dispatcher.dispatch().await;
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STATE_SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                state.save().await;
+            }
+        });
+    }
+
+    match config.telegram.webhook.as_ref() {
+        Some(webhook) => {
+            let listener =
+                teloxide::update_listeners::webhooks::axum(bot, webhook.options()?).await?;
+            tracing::info!("Listening for webhook updates on {}", webhook.listen);
+            dispatcher
+                .dispatch_with_listener(
+                    listener,
+                    LoggingErrorHandler::with_custom_text("An error from the webhook listener"),
+                )
+                .await;
+        }
+        None => dispatcher.dispatch().await,
+    }
+    state.save().await;
     Ok(())
 }
+
+/// Reload the config file when it is modified or a reload is requested.
+async fn watch_config(path: String, state: Arc<state::State>, reload: Arc<tokio::sync::Notify>) {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    loop {
+        let forced = tokio::select! {
+            _ = interval.tick() => false,
+            _ = reload.notified() => true,
+        };
+        let current = modified(&path);
+        if !forced && current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        match Config::load_from(&path) {
+            Ok(config) => {
+                tracing::info!("Reloading config file {path}");
+                state.reload(&config).await;
+            }
+            Err(e) => tracing::error!("Unable to reload config file {path}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+//! Optional Prometheus metrics endpoint.
+//!
+//! Counters are recorded into a global registry, gauges are read from aria2
+//! and the task caches at scrape time. Rendered in the Prometheus text exposition format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use aria2_rs::status::Stat;
+use parking_lot::Mutex;
+
+use crate::{constants::REFRESH_TIMEOUT, state::State};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+#[derive(Default)]
+pub struct Metrics {
+    // aria2 operation -> call stats
+    calls: Mutex<BTreeMap<&'static str, CallStats>>,
+    edit_failures: AtomicU64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CallStats {
+    count: u64,
+    failures: u64,
+    duration_secs: f64,
+}
+
+/// Gauges of a server, collected at scrape time.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    // None when aria2 could not be reached
+    pub stat: Option<Stat>,
+    pub list_subscribers: usize,
+    pub task_subscribers: usize,
+    pub stats_subscribers: usize,
+}
+
+impl Metrics {
+    pub fn record_call(&self, op: &'static str, duration: Duration, ok: bool) {
+        let mut calls = self.calls.lock();
+        let stats = calls.entry(op).or_default();
+        stats.count += 1;
+        stats.duration_secs += duration.as_secs_f64();
+        if !ok {
+            stats.failures += 1;
+        }
+    }
+
+    pub fn record_edit_failure(&self) {
+        self.edit_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, servers: &[(String, bool, ServerMetrics)]) -> String {
+        let mut out = String::new();
+        macro_rules! header {
+            ($name: literal, $type: literal, $help: literal) => {
+                let _ = writeln!(
+                    out,
+                    "# HELP {} {}\n# TYPE {} {}",
+                    $name, $help, $name, $type
+                );
+            };
+        }
+
+        header!(
+            "telearia2_server_up",
+            "gauge",
+            "Whether the aria2 server is healthy."
+        );
+        for (server, up, _) in servers {
+            let _ = writeln!(
+                out,
+                "telearia2_server_up{{server=\"{}\"}} {}",
+                escape(server),
+                u8::from(*up)
+            );
+        }
+        header!(
+            "telearia2_tasks",
+            "gauge",
+            "Number of tasks by status, as reported by aria2."
+        );
+        for (server, _, metrics) in servers {
+            let Some(stat) = &metrics.stat else {
+                continue;
+            };
+            for (status, count) in [
+                ("active", stat.num_active),
+                ("waiting", stat.num_waiting),
+                ("stopped", stat.num_stopped),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "telearia2_tasks{{server=\"{}\",status=\"{status}\"}} {}",
+                    escape(server),
+                    count.unwrap_or(0)
+                );
+            }
+        }
+        header!(
+            "telearia2_download_speed_bytes",
+            "gauge",
+            "Global download speed of aria2 in bytes per second."
+        );
+        for (server, _, metrics) in servers {
+            let Some(stat) = &metrics.stat else {
+                continue;
+            };
+            let _ = writeln!(
+                out,
+                "telearia2_download_speed_bytes{{server=\"{}\"}} {}",
+                escape(server),
+                stat.download_speed.unwrap_or(0)
+            );
+        }
+        header!(
+            "telearia2_upload_speed_bytes",
+            "gauge",
+            "Global upload speed of aria2 in bytes per second."
+        );
+        for (server, _, metrics) in servers {
+            let Some(stat) = &metrics.stat else {
+                continue;
+            };
+            let _ = writeln!(
+                out,
+                "telearia2_upload_speed_bytes{{server=\"{}\"}} {}",
+                escape(server),
+                stat.upload_speed.unwrap_or(0)
+            );
+        }
+        header!(
+            "telearia2_subscribers",
+            "gauge",
+            "Number of live messages by kind."
+        );
+        for (server, _, metrics) in servers {
+            for (kind, count) in [
+                ("list", metrics.list_subscribers),
+                ("task", metrics.task_subscribers),
+                ("stats", metrics.stats_subscribers),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "telearia2_subscribers{{server=\"{}\",kind=\"{kind}\"}} {count}",
+                    escape(server)
+                );
+            }
+        }
+
+        let calls = self.calls.lock().clone();
+        header!(
+            "telearia2_aria2_calls_total",
+            "counter",
+            "Number of aria2 call attempts."
+        );
+        for (op, stats) in calls.iter() {
+            let _ = writeln!(
+                out,
+                "telearia2_aria2_calls_total{{op=\"{op}\"}} {}",
+                stats.count
+            );
+        }
+        header!(
+            "telearia2_aria2_call_failures_total",
+            "counter",
+            "Number of failed aria2 call attempts."
+        );
+        for (op, stats) in calls.iter() {
+            let _ = writeln!(
+                out,
+                "telearia2_aria2_call_failures_total{{op=\"{op}\"}} {}",
+                stats.failures
+            );
+        }
+        header!(
+            "telearia2_aria2_call_duration_seconds",
+            "summary",
+            "Latency of aria2 call attempts."
+        );
+        for (op, stats) in calls.iter() {
+            let _ = writeln!(
+                out,
+                "telearia2_aria2_call_duration_seconds_sum{{op=\"{op}\"}} {}\ntelearia2_aria2_call_duration_seconds_count{{op=\"{op}\"}} {}",
+                stats.duration_secs, stats.count
+            );
+        }
+        header!(
+            "telearia2_telegram_edit_failures_total",
+            "counter",
+            "Number of failed live message edits."
+        );
+        let _ = writeln!(
+            out,
+            "telearia2_telegram_edit_failures_total {}",
+            self.edit_failures.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `/metrics` until the process exits.
+pub async fn serve(listen: SocketAddr, state: Arc<State>) -> anyhow::Result<()> {
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || {
+            let state = state.clone();
+            async move {
+                let mut scrapes = tokio::task::JoinSet::new();
+                for (name, server) in state.servers() {
+                    scrapes.spawn(async move {
+                        let stat =
+                            tokio::time::timeout(REFRESH_TIMEOUT, server.client.get_global_stat())
+                                .await
+                                .ok()
+                                .and_then(Result::ok);
+                        let metrics = ServerMetrics {
+                            stat,
+                            ..server.tasks_cache.read().metrics()
+                        };
+                        (name, server.client.health().healthy(), metrics)
+                    });
+                }
+                let mut servers = scrapes.join_all().await;
+                servers.sort_by(|a, b| a.0.cmp(&b.0));
+                (
+                    [(
+                        axum::http::header::CONTENT_TYPE,
+                        "text/plain; version=0.0.4",
+                    )],
+                    METRICS.render(&servers),
+                )
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    tracing::info!("Serving metrics on {listen}");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_call("add_uris", Duration::from_millis(500), true);
+        metrics.record_call("add_uris", Duration::from_millis(250), false);
+        metrics.record_edit_failure();
+        let server = ServerMetrics {
+            stat: Some(Stat {
+                download_speed: Some(1024),
+                upload_speed: Some(0),
+                num_active: Some(2),
+                num_waiting: None,
+                num_stopped: Some(0),
+                num_stopped_total: Some(0),
+            }),
+            ..Default::default()
+        };
+        let text = metrics.render(&[
+            ("my \"server\"".to_string(), true, server),
+            ("down".to_string(), false, ServerMetrics::default()),
+        ]);
+
+        assert!(text.contains("telearia2_server_up{server=\"my \\\"server\\\"\"} 1\n"));
+        assert!(
+            text.contains("telearia2_tasks{server=\"my \\\"server\\\"\",status=\"active\"} 2\n")
+        );
+        assert!(
+            text.contains("telearia2_download_speed_bytes{server=\"my \\\"server\\\"\"} 1024\n")
+        );
+        assert!(
+            text.contains("telearia2_tasks{server=\"my \\\"server\\\"\",status=\"stopped\"} 0\n")
+        );
+        assert!(text.contains("telearia2_server_up{server=\"down\"} 0\n"));
+        assert!(!text.contains("telearia2_download_speed_bytes{server=\"down\"}"));
+        assert!(text.contains("telearia2_aria2_calls_total{op=\"add_uris\"} 2\n"));
+        assert!(text.contains("telearia2_aria2_call_failures_total{op=\"add_uris\"} 1\n"));
+        assert!(text.contains("telearia2_aria2_call_duration_seconds_sum{op=\"add_uris\"} 0.75\n"));
+        assert!(text.contains("telearia2_telegram_edit_failures_total 1\n"));
+    }
+}
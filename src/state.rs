@@ -1,28 +1,51 @@
 use crate::{
-    aria2::Aria2Client,
-    config::{Aria2ConfigGroup, DownloadConfig, Param, TelegramConfig},
+    aria2::{Aria2Client, Aria2Notification, SpeedLimits, Version},
+    audit::AuditLog,
+    auth::{AuthStore, Invite},
+    config::{Aria2Config, Aria2ConfigGroup, DownloadConfig, Param, TelegramConfig},
     constants::{
-        CACHE_EXPIRE, DEFAULT_SUBSCRIBER_EXPIRE, REFRESH_INTERVAL, REFRESH_TIMEOUT, URI_LRU_SIZE,
+        ARIA2_CONNECT_TIMEOUT, CACHE_EXPIRE, CONFIRM_EXPIRE, DEFAULT_SUBSCRIBER_EXPIRE,
+        DEFAULT_UPLOAD_LIMIT, FULL_SYNC_INTERVAL, HEALTH_CHECK_INTERVAL, INVITE_EXPIRE,
+        LOCAL_MAX_TORRENT_SIZE, LOCAL_UPLOAD_LIMIT, MAX_TORRENT_SIZE, REFRESH_INTERVAL,
+        REFRESH_TIMEOUT, SPEED_SAMPLE_COUNT, TORRENT_DATA_LRU_SIZE, UPLOAD_CONNECT_TIMEOUT,
+        URI_LRU_SIZE,
     },
     format::{
-        make_refresh_list_keyboard, make_refresh_task_keyboard, make_single_task_keyboard,
-        make_tasks_keyboard,
-        msg::{MsgTaskList, MsgTaskListExpired},
-        task_list_page_count, MessageFmtBrief, MessageFmtDetailed, TaskExt, TASK_LIST_PAGE_SIZE,
+        make_refresh_list_keyboard, make_refresh_stats_keyboard, make_refresh_task_keyboard,
+        make_single_task_keyboard, make_tasks_keyboard,
+        msg::{MsgServerStats, MsgTaskEvent, MsgTaskList, MsgTaskListExpired},
+        task_list_page_count, FileEntry, MessageFmtBrief, MessageFmtDetailed, TaskDetail, TaskExt,
+        TASK_LIST_PAGE_SIZE,
     },
+    metrics::{ServerMetrics, METRICS},
+    role::Role,
+    store::{
+        StoredListSubscriber, StoredServer, StoredState, StoredSubscriber, StoredTaskSubscriber,
+    },
+    torrent::TorrentMeta,
     utils::{ExpiredDeque, SingleMultiMap},
 };
 use aria2_rs::{
-    status::{Status, TaskStatus},
+    status::{Stat, Status, TaskStatus},
     SmallVec,
 };
+use bytes::Bytes;
 use hashlink::LruCache;
 use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use teloxide::{
     requests::Requester,
-    types::{ChatId, MessageId},
+    types::{Chat, ChatId, MessageId, User},
     Bot,
 };
 
@@ -50,6 +73,262 @@ impl TasksMap {
     pub fn values(&self) -> impl Iterator<Item = &Arc<Status>> {
         self.0.values()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SmolStr, &Arc<Status>)> {
+        self.0.iter()
+    }
+
+    pub fn insert(&mut self, task: Status) {
+        if let Some(gid) = task.gid.clone() {
+            self.0.insert(gid, Arc::new(task));
+        }
+    }
+}
+
+/// Notable task state transitions pushed to chats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskEvent {
+    /// BitTorrent download finished and the task started seeding.
+    Seeding,
+    /// Task completed (for BitTorrent: seeding finished).
+    Complete,
+    /// Task stopped with an error.
+    Error,
+}
+
+impl TaskEvent {
+    fn of(task: &Status) -> Option<Self> {
+        match task.status? {
+            TaskStatus::Complete => Some(Self::Complete),
+            TaskStatus::Error => Some(Self::Error),
+            TaskStatus::Active if task.seeder == Some(true) => Some(Self::Seeding),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn is_terminal(self) -> bool {
+        matches!(self, Self::Complete | Self::Error)
+    }
+}
+
+/// Compare two snapshots and collect tasks that reached a notable state.
+///
+/// A task only fires when its previous state is known (or it is tracked), so
+/// the first snapshot after startup does not flood chats with old results.
+pub fn detect_transitions<'a>(
+    old: &TasksMap,
+    new: &'a TasksMap,
+    tracked: &HashMap<SmolStr, ChatId>,
+) -> Vec<(&'a Arc<Status>, TaskEvent)> {
+    new.iter()
+        .filter_map(|(gid, task)| {
+            let event = TaskEvent::of(task)?;
+            match old.get(gid) {
+                Some(old_task) if TaskEvent::of(old_task) == Some(event) => None,
+                None if !tracked.contains_key(gid) => None,
+                _ => Some((task, event)),
+            }
+        })
+        .collect()
+}
+
+/// Which tasks a task list shows.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum TaskFilter {
+    #[default]
+    All,
+    /// Unfinished tasks: active, waiting or paused.
+    Active,
+    Error,
+    Complete,
+    /// Tasks whose name contains the lowercase text.
+    Search(SmolStr),
+}
+
+impl TaskFilter {
+    // Keep searches short enough for Telegram's 64 bytes callback data.
+    const MAX_SEARCH_LEN: usize = 40;
+
+    /// Parse `/task` arguments.
+    pub fn from_args(args: &str) -> Self {
+        let args = args.trim();
+        match args.to_ascii_lowercase().as_str() {
+            "" | "all" => Self::All,
+            "active" => Self::Active,
+            "error" => Self::Error,
+            "complete" => Self::Complete,
+            _ => {
+                // Lowercasing may lengthen non-ASCII text, so truncate afterwards
+                let search = args.to_lowercase();
+                let mut end = search.len().min(Self::MAX_SEARCH_LEN);
+                while !search.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Self::Search(search[..end].into())
+            }
+        }
+    }
+
+    /// Parse the filter part of callback data.
+    pub fn from_callback(data: &str) -> Option<Self> {
+        match data {
+            "all" => Some(Self::All),
+            "active" => Some(Self::Active),
+            "error" => Some(Self::Error),
+            "complete" => Some(Self::Complete),
+            _ => data.strip_prefix("s:").map(|s| Self::Search(s.into())),
+        }
+    }
+
+    pub fn to_callback(&self) -> String {
+        match self {
+            Self::All => "all".into(),
+            Self::Active => "active".into(),
+            Self::Error => "error".into(),
+            Self::Complete => "complete".into(),
+            Self::Search(search) => format!("s:{search}"),
+        }
+    }
+
+    pub fn matches(&self, task: &Status) -> bool {
+        match self {
+            Self::All => true,
+            Self::Active => matches!(
+                task.status,
+                Some(TaskStatus::Active | TaskStatus::Waiting | TaskStatus::Paused)
+            ),
+            Self::Error => task.status == Some(TaskStatus::Error),
+            Self::Complete => task.status == Some(TaskStatus::Complete),
+            Self::Search(search) => task.name().to_lowercase().contains(search.as_str()),
+        }
+    }
+}
+
+/// Uploaded torrent a task was added from, used to retry it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentSource {
+    pub file_id: String,
+    // 1-based indexes of selected files, None when all files are selected
+    pub selected_files: Option<Vec<u64>>,
+}
+
+/// Recent download speeds and timing of a task, for its ETA and average speed.
+#[derive(Debug, Clone, Default)]
+pub struct SpeedTracker {
+    // Latest download speeds, oldest first
+    samples: VecDeque<u64>,
+    // When the task was first seen downloading, and whether it had nothing downloaded then
+    started: Option<(Instant, bool)>,
+    // Average speed from start until completion, only known when seen from the start
+    average: Option<u64>,
+}
+
+impl SpeedTracker {
+    fn record(&mut self, task: &Status, now: Instant) {
+        let (completed, total) = task.progress_size();
+        match task.status {
+            // Seeding tasks stay active once the download is complete
+            Some(TaskStatus::Active) if total == 0 || completed < total => {
+                self.started.get_or_insert((now, completed == 0));
+                if self.samples.len() == SPEED_SAMPLE_COUNT {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(task.download_speed.unwrap_or(0));
+            }
+            Some(TaskStatus::Active) | Some(TaskStatus::Complete) => {
+                self.samples.clear();
+                // aria2 does not tell when a task started, a task first seen
+                // midway, e.g. before a restart, has no meaningful average
+                if let (None, Some((start, true))) = (self.average, self.started) {
+                    let secs = now.duration_since(start).as_secs_f64();
+                    if secs >= 1.0 {
+                        self.average = Some((completed as f64 / secs) as u64);
+                    }
+                }
+            }
+            _ => self.samples.clear(),
+        }
+    }
+
+    /// Estimated time until the task completes.
+    pub fn eta(&self, task: &Status) -> Option<Duration> {
+        let (completed, total) = task.progress_size();
+        let remaining = total.checked_sub(completed).filter(|r| *r > 0)?;
+        if self.samples.is_empty() {
+            return None;
+        }
+        let speed = self.samples.iter().sum::<u64>() / self.samples.len() as u64;
+        (speed > 0).then(|| Duration::from_secs(remaining.div_ceil(speed)))
+    }
+
+    /// Average download speed, known once a task seen from its start completed.
+    pub fn average_speed(&self) -> Option<u64> {
+        self.average
+    }
+}
+
+/// Actions applied to many tasks at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkAction {
+    PauseAll,
+    ResumeAll,
+    RemoveErrored,
+    RetryErrored,
+}
+
+impl BulkAction {
+    pub fn from_callback(data: &str) -> Option<Self> {
+        match data {
+            "pause" => Some(Self::PauseAll),
+            "resume" => Some(Self::ResumeAll),
+            "remove_error" => Some(Self::RemoveErrored),
+            "retry_error" => Some(Self::RetryErrored),
+            _ => None,
+        }
+    }
+
+    pub fn to_callback(self) -> &'static str {
+        match self {
+            Self::PauseAll => "pause",
+            Self::ResumeAll => "resume",
+            Self::RemoveErrored => "remove_error",
+            Self::RetryErrored => "retry_error",
+        }
+    }
+
+    /// Whether the action goes through tasks one by one instead of a single aria2 call.
+    pub fn per_task(self) -> bool {
+        matches!(self, Self::RemoveErrored | Self::RetryErrored)
+    }
+
+    pub fn required_role(self) -> Role {
+        match self {
+            Self::RemoveErrored => Role::Admin,
+            Self::PauseAll | Self::ResumeAll | Self::RetryErrored => Role::Operator,
+        }
+    }
+}
+
+/// Destructive actions asked to be confirmed before running.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+pub enum ConfirmAction {
+    Remove { gid: SmolStr },
+    // remove the task and delete its downloaded files
+    RemoveFiles { gid: SmolStr },
+    Purge,
+}
+
+/// An action waiting for the confirm button.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingConfirm {
+    #[serde(flatten)]
+    pub action: ConfirmAction,
+    // server the action was asked on
+    pub server: String,
+    // unix timestamp in seconds
+    pub expires_at: u64,
 }
 
 impl FromIterator<(SmolStr, Arc<Status>)> for TasksMap {
@@ -58,6 +337,16 @@ impl FromIterator<(SmolStr, Arc<Status>)> for TasksMap {
     }
 }
 
+impl FromIterator<Status> for TasksMap {
+    fn from_iter<I: IntoIterator<Item = Status>>(iter: I) -> Self {
+        let mut tasks = Self::new();
+        for task in iter {
+            tasks.insert(task);
+        }
+        tasks
+    }
+}
+
 impl PartialEq for TasksMap {
     fn eq(&self, other: &Self) -> bool {
         if self.0.len() != other.0.len() {
@@ -86,6 +375,7 @@ impl PartialEq for TasksMap {
 pub struct Subscribers {
     list_subscribers: ExpiredDeque<ListSubscriber>,
     task_subscribers: HashMap<SmolStr, ExpiredDeque<Subscriber>>,
+    stats_subscribers: ExpiredDeque<Subscriber>,
 }
 
 impl Subscribers {
@@ -93,48 +383,87 @@ impl Subscribers {
         Self {
             list_subscribers: ExpiredDeque::new(expire),
             task_subscribers: HashMap::new(),
+            stats_subscribers: ExpiredDeque::new(expire),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ListSubscriber {
     chat_id: ChatId,
     message_id: MessageId,
     page: usize,
+    filter: TaskFilter,
+    // role of the user who opened the list, decides the buttons shown
+    role: Role,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Subscriber {
     chat_id: ChatId,
     message_id: MessageId,
+    role: Role,
 }
 
 pub struct TasksCache {
     // GID -> Status
     tasks: TasksMap,
-    // Last refresh time
-    last_refresh: std::time::Instant,
+    // Last refresh time of active tasks
+    last_refresh: Instant,
+    // Last full sync time, None when never synced or invalidated
+    last_sync: Option<Instant>,
     // subscribers
     subscribers: Subscribers,
+    // GID -> chat which added the task
+    owners: HashMap<SmolStr, ChatId>,
+    // chat notified for every task event
+    notify_chat: Option<ChatId>,
+    // GID -> uploaded torrent of the task
+    torrents: HashMap<SmolStr, TorrentSource>,
+    // GIDs with an owner or torrent missing from the last full snapshot
+    unseen: HashSet<SmolStr>,
+    // GID -> speed limits, fetched when a task is viewed
+    limits: HashMap<SmolStr, SpeedLimits>,
+    // Download speed history of tasks, for ETA and average speed
+    speeds: HashMap<SmolStr, SpeedTracker>,
+    // global stat, fetched when someone watches it
+    stat: Option<Stat>,
+    // aria2 version, fetched once
+    version: Option<Version>,
+    // whether the server is configured to delete files of removed tasks
+    delete_files: bool,
+    // whether the server is configured to send completed files to chats
+    upload_files: bool,
     // telegram bot
     bot: Bot,
 }
 
 impl TasksCache {
-    pub fn new(expire: std::time::Duration, bot: Bot) -> Self {
+    pub fn new(expire: std::time::Duration, notify_chat: Option<ChatId>, bot: Bot) -> Self {
         Self {
             tasks: TasksMap::new(),
-            last_refresh: std::time::Instant::now(),
+            last_refresh: Instant::now(),
+            last_sync: None,
             subscribers: Subscribers::new(expire),
+            owners: HashMap::new(),
+            notify_chat,
+            torrents: HashMap::new(),
+            unseen: HashSet::new(),
+            limits: HashMap::new(),
+            speeds: HashMap::new(),
+            stat: None,
+            version: None,
+            delete_files: false,
+            upload_files: false,
             bot,
         }
     }
 
-    pub fn fmt_tasks(&self) -> Vec<(String, String)> {
+    pub fn fmt_tasks(&self, filter: &TaskFilter) -> Vec<(String, String)> {
         let mut tasks: Vec<_> = self
             .tasks
             .values()
+            .filter(|t| filter.matches(t))
             .cloned()
             .map(|t| ((t.progress() * 10000.0) as u16, t))
             .collect();
@@ -165,28 +494,79 @@ impl TasksCache {
     }
 
     pub fn fmt_task(&self, gid: &str) -> Option<(String, &Arc<Status>)> {
+        self.tasks.get(gid).map(|t| {
+            let speed = self.speeds.get(gid);
+            let detail = TaskDetail {
+                status: t,
+                limits: self.limits.get(gid),
+                eta: speed.and_then(|s| s.eta(t)),
+                average_speed: speed.and_then(SpeedTracker::average_speed),
+            };
+            (format!("{}", MessageFmtDetailed(detail)), t)
+        })
+    }
+
+    pub fn get_task(&self, gid: &str) -> Option<Arc<Status>> {
+        self.tasks.get(gid).cloned()
+    }
+
+    /// Tasks in the given status.
+    pub fn tasks_in(&self, status: TaskStatus) -> Vec<Arc<Status>> {
         self.tasks
-            .get(gid)
-            .map(|t| (format!("{}", MessageFmtDetailed(t)), t))
+            .values()
+            .filter(|t| t.status == Some(status))
+            .cloned()
+            .collect()
+    }
+
+    pub fn set_limits(&mut self, gid: SmolStr, limits: SpeedLimits) {
+        self.limits.insert(gid, limits);
     }
 
     pub fn expired(&self) -> bool {
-        self.last_refresh.elapsed() > CACHE_EXPIRE
+        self.last_sync.is_none() || self.last_refresh.elapsed() > CACHE_EXPIRE
+    }
+
+    /// Whether a full sync is due, in case some aria2 notifications were missed.
+    pub fn needs_sync(&self) -> bool {
+        self.last_sync
+            .is_none_or(|last_sync| last_sync.elapsed() > FULL_SYNC_INTERVAL)
+    }
+
+    /// Force a full sync on the next refresh.
+    pub fn invalidate(&mut self) {
+        self.last_sync = None;
+    }
+
+    /// Mark progress of active tasks as freshly fetched.
+    pub fn touch(&mut self) {
+        self.last_refresh = Instant::now();
     }
 
-    pub fn add_list_subscriber(&mut self, chat_id: ChatId, message_id: MessageId, page: usize) {
+    pub fn add_list_subscriber(
+        &mut self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        page: usize,
+        filter: TaskFilter,
+        role: Role,
+    ) {
         self.subscribers.list_subscribers.push_back(ListSubscriber {
             chat_id,
             message_id,
             page,
+            filter,
+            role,
         });
     }
 
-    pub fn update_list_subscriber_page(
+    pub fn update_list_subscriber(
         &mut self,
         chat_id: ChatId,
         message_id: MessageId,
         page: usize,
+        filter: TaskFilter,
+        role: Role,
     ) {
         self.subscribers.list_subscribers.clean();
         if let Some(subscriber) = self
@@ -196,18 +576,32 @@ impl TasksCache {
             .find(|sub| sub.chat_id == chat_id && sub.message_id == message_id)
         {
             subscriber.page = page;
+            subscriber.filter = filter;
+            subscriber.role = role;
+            return;
         }
+        self.add_list_subscriber(chat_id, message_id, page, filter, role);
     }
 
-    pub fn list_subscriber_page(&self, chat_id: ChatId, message_id: MessageId) -> Option<usize> {
+    pub fn list_subscriber_view(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Option<(usize, &TaskFilter)> {
         self.subscribers
             .list_subscribers
             .iter()
             .find(|sub| sub.chat_id == chat_id && sub.message_id == message_id)
-            .map(|sub| sub.page)
+            .map(|sub| (sub.page, &sub.filter))
     }
 
-    pub fn add_task_subscriber(&mut self, gid: SmolStr, chat_id: ChatId, message_id: MessageId) {
+    pub fn add_task_subscriber(
+        &mut self,
+        gid: SmolStr,
+        chat_id: ChatId,
+        message_id: MessageId,
+        role: Role,
+    ) {
         let subscribers = self
             .subscribers
             .task_subscribers
@@ -216,6 +610,7 @@ impl TasksCache {
         subscribers.push_back(Subscriber {
             chat_id,
             message_id,
+            role,
         });
     }
 
@@ -224,12 +619,337 @@ impl TasksCache {
             || !self.subscribers.task_subscribers.is_empty()
     }
 
+    pub fn add_stats_subscriber(&mut self, chat_id: ChatId, message_id: MessageId) {
+        self.subscribers.stats_subscribers.push_back(Subscriber {
+            chat_id,
+            message_id,
+            role: Role::Viewer,
+        });
+    }
+
+    pub fn has_stats_subscriber(&self) -> bool {
+        !self.subscribers.stats_subscribers.is_empty()
+    }
+
+    pub fn fmt_stats(&self) -> Option<String> {
+        self.stat.as_ref().map(|stat| {
+            MsgServerStats {
+                stat,
+                version: self.version.as_ref(),
+            }
+            .into()
+        })
+    }
+
+    /// Replace the cached global stat.
+    ///
+    /// Returns whether anything visible changed.
+    pub fn update_stats(&mut self, stat: Stat) -> bool {
+        let changed = self.stat.as_ref().is_none_or(|old| {
+            old.download_speed != stat.download_speed
+                || old.upload_speed != stat.upload_speed
+                || old.num_active != stat.num_active
+                || old.num_waiting != stat.num_waiting
+                || old.num_stopped != stat.num_stopped
+                || old.num_stopped_total != stat.num_stopped_total
+        });
+        self.stat = Some(stat);
+        changed
+    }
+
+    /// Export subscriptions and task owners for the state store.
+    pub fn snapshot(&self) -> StoredServer {
+        fn stored(sub: &Subscriber, remaining: Duration) -> StoredSubscriber {
+            StoredSubscriber {
+                chat_id: sub.chat_id.0,
+                message_id: sub.message_id.0,
+                expire_secs: remaining.as_secs(),
+                role: sub.role,
+            }
+        }
+        let subscribers = &self.subscribers;
+        StoredServer {
+            list_subscribers: subscribers
+                .list_subscribers
+                .iter_remaining()
+                .map(|(sub, remaining)| StoredListSubscriber {
+                    subscriber: stored(
+                        &Subscriber {
+                            chat_id: sub.chat_id,
+                            message_id: sub.message_id,
+                            role: sub.role,
+                        },
+                        remaining,
+                    ),
+                    page: sub.page,
+                    filter: sub.filter.to_callback(),
+                })
+                .collect(),
+            task_subscribers: subscribers
+                .task_subscribers
+                .iter()
+                .flat_map(|(gid, subs)| {
+                    subs.iter_remaining()
+                        .map(move |(sub, remaining)| StoredTaskSubscriber {
+                            subscriber: stored(sub, remaining),
+                            gid: gid.clone(),
+                        })
+                })
+                .collect(),
+            stats_subscribers: subscribers
+                .stats_subscribers
+                .iter_remaining()
+                .map(|(sub, remaining)| stored(sub, remaining))
+                .collect(),
+            owners: self
+                .owners
+                .iter()
+                .map(|(gid, chat_id)| (gid.clone(), chat_id.0))
+                .collect(),
+            torrents: self.torrents.clone(),
+        }
+    }
+
+    /// Restore what [`Self::snapshot`] exported, before any new subscription.
+    pub fn restore(&mut self, stored: StoredServer) {
+        let expire_in = |sub: &StoredSubscriber| Duration::from_secs(sub.expire_secs);
+        for list_sub in stored.list_subscribers {
+            let Some(filter) = TaskFilter::from_callback(&list_sub.filter) else {
+                continue;
+            };
+            self.subscribers.list_subscribers.push_back_expire_in(
+                ListSubscriber {
+                    chat_id: ChatId(list_sub.subscriber.chat_id),
+                    message_id: MessageId(list_sub.subscriber.message_id),
+                    page: list_sub.page,
+                    filter,
+                    role: list_sub.subscriber.role,
+                },
+                expire_in(&list_sub.subscriber),
+            );
+        }
+        for task_sub in stored.task_subscribers {
+            self.subscribers
+                .task_subscribers
+                .entry(task_sub.gid)
+                .or_insert_with(|| ExpiredDeque::new(DEFAULT_SUBSCRIBER_EXPIRE))
+                .push_back_expire_in(
+                    Subscriber {
+                        chat_id: ChatId(task_sub.subscriber.chat_id),
+                        message_id: MessageId(task_sub.subscriber.message_id),
+                        role: task_sub.subscriber.role,
+                    },
+                    expire_in(&task_sub.subscriber),
+                );
+        }
+        for sub in stored.stats_subscribers {
+            self.subscribers.stats_subscribers.push_back_expire_in(
+                Subscriber {
+                    chat_id: ChatId(sub.chat_id),
+                    message_id: MessageId(sub.message_id),
+                    role: sub.role,
+                },
+                expire_in(&sub),
+            );
+        }
+        self.owners.extend(
+            stored
+                .owners
+                .into_iter()
+                .map(|(gid, chat)| (gid, ChatId(chat))),
+        );
+        self.torrents.extend(stored.torrents);
+    }
+
+    /// Apply reloaded telegram settings, the expire only affects new subscriptions.
+    pub fn reconfigure(&mut self, expire: Duration, notify_chat: Option<ChatId>) {
+        self.subscribers.list_subscribers.set_expire(expire);
+        self.subscribers.stats_subscribers.set_expire(expire);
+        self.notify_chat = notify_chat;
+    }
+
+    /// Follow the download config, for buttons of live task messages.
+    pub fn set_file_actions(&mut self, delete_files: bool, upload_files: bool) {
+        self.delete_files = delete_files;
+        self.upload_files = upload_files;
+    }
+
+    /// Subscriber gauges for the metrics endpoint.
+    pub fn metrics(&self) -> ServerMetrics {
+        ServerMetrics {
+            list_subscribers: self.subscribers.list_subscribers.iter().count(),
+            task_subscribers: self
+                .subscribers
+                .task_subscribers
+                .values()
+                .map(|subs| subs.iter().count())
+                .sum(),
+            stats_subscribers: self.subscribers.stats_subscribers.iter().count(),
+            ..Default::default()
+        }
+    }
+
+    pub fn has_list_subscriber(&self) -> bool {
+        !self.subscribers.list_subscribers.is_empty()
+    }
+
+    /// GIDs with live subscribers whose progress may still change.
+    pub fn subscribed_active_gids(&self) -> Vec<SmolStr> {
+        self.subscribers
+            .task_subscribers
+            .keys()
+            .filter(|gid| {
+                self.tasks
+                    .get(gid)
+                    .is_none_or(|t| t.status == Some(TaskStatus::Active))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Remember the chat which added the task, so it can be notified later.
+    pub fn track_task(&mut self, gid: SmolStr, chat_id: ChatId) {
+        self.owners.insert(gid, chat_id);
+    }
+
+    /// Remember the uploaded torrent of a task, so it can be retried from it.
+    pub fn track_torrent(&mut self, gid: SmolStr, source: TorrentSource) {
+        self.torrents.insert(gid, source);
+    }
+
+    pub fn torrent_source(&self, gid: &str) -> Option<&TorrentSource> {
+        self.torrents.get(gid)
+    }
+
+    /// Hand the owner and torrent of a retried task over to its new GID.
+    pub fn replace_task(&mut self, old_gid: &str, new_gid: SmolStr, chat_id: ChatId) {
+        self.owners.remove(old_gid);
+        if let Some(source) = self.torrents.remove(old_gid) {
+            self.torrents.insert(new_gid.clone(), source);
+        }
+        self.owners.insert(new_gid, chat_id);
+    }
+
+    /// Whether task events have to be watched even without subscribers.
+    pub fn has_watcher(&self) -> bool {
+        self.notify_chat.is_some() || !self.owners.is_empty()
+    }
+
+    /// Replace the cached tasks with a full snapshot.
+    ///
+    /// Returns whether anything visible changed.
+    pub fn update_tasks(&mut self, tasks: TasksMap) -> bool {
+        let now = Instant::now();
+        self.last_refresh = now;
+        self.last_sync = Some(now);
+        for task in tasks.values() {
+            self.record_speed(task, now);
+        }
+        let changed = self.apply_tasks(tasks);
+        // A full snapshot lists every task aria2 knows, forget owners and torrents of the others.
+        // Tasks added while the snapshot was fetched are missing once, so wait for a second one.
+        let missing: HashSet<SmolStr> = self
+            .owners
+            .keys()
+            .chain(self.torrents.keys())
+            .filter(|gid| self.tasks.get(gid).is_none())
+            .cloned()
+            .collect();
+        let gone = |gid: &SmolStr| missing.contains(gid) && self.unseen.contains(gid);
+        self.owners.retain(|gid, _| !gone(gid));
+        self.torrents.retain(|gid, _| !gone(gid));
+        self.unseen = missing;
+        changed
+    }
+
+    /// Merge some updated tasks into the cache.
+    ///
+    /// Returns whether anything visible changed.
+    pub fn upsert_tasks(&mut self, tasks: impl IntoIterator<Item = Status>) -> bool {
+        let now = Instant::now();
+        let mut merged = self.tasks.clone();
+        for task in tasks {
+            self.record_speed(&task, now);
+            merged.insert(task);
+        }
+        self.apply_tasks(merged)
+    }
+
+    fn record_speed(&mut self, task: &Status, now: Instant) {
+        if let Some(gid) = task.gid.as_deref() {
+            self.speeds.entry(gid.into()).or_default().record(task, now);
+        }
+    }
+
+    /// Swap in new tasks, pushing task events to interested chats.
+    fn apply_tasks(&mut self, tasks: TasksMap) -> bool {
+        if self.tasks == tasks {
+            return false;
+        }
+        for (task, event) in detect_transitions(&self.tasks, &tasks, &self.owners) {
+            let Some(gid) = task.gid.as_ref() else {
+                continue;
+            };
+            let owner = if event.is_terminal() {
+                self.owners.remove(gid)
+            } else {
+                self.owners.get(gid).copied()
+            };
+            // Magnet metadata tasks are followed by the real download, pass the owner on.
+            if let Some(followed_by) = task.followed_by.as_ref().filter(|f| !f.is_empty()) {
+                if let Some(owner) = owner {
+                    for next in followed_by {
+                        self.owners.insert(next.into(), owner);
+                    }
+                }
+                continue;
+            }
+            let mut chats: SmallVec<ChatId> = owner.into_iter().collect();
+            if let Some(notify_chat) = self.notify_chat {
+                if !chats.contains(&notify_chat) {
+                    chats.push(notify_chat);
+                }
+            }
+            let text: String = MsgTaskEvent {
+                gid,
+                name: task.name(),
+                event,
+                bittorrent: task.bittorrent.is_some(),
+            }
+            .into();
+            for chat_id in chats {
+                let bot = self.bot.clone();
+                let text = text.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = bot.send_message(chat_id, text).await {
+                        tracing::warn!("Failed to send task notification: {e}");
+                    }
+                });
+            }
+        }
+        // Forget removed tasks, tasks gone from aria2 are pruned on full syncs
+        self.owners.retain(|gid, _| {
+            tasks
+                .get(gid)
+                .is_none_or(|t| t.status != Some(TaskStatus::Removed))
+        });
+        self.torrents.retain(|gid, _| {
+            tasks
+                .get(gid)
+                .is_none_or(|t| t.status != Some(TaskStatus::Removed))
+        });
+        self.limits.retain(|gid, _| tasks.get(gid).is_some());
+        self.speeds.retain(|gid, _| tasks.get(gid).is_some());
+        self.tasks = tasks;
+        true
+    }
+
     pub fn handle_expired_subscribers(&mut self) {
         // Handle expired list subscribers - show refresh button
         let expired_list = self.subscribers.list_subscribers.drain_expired();
         for list_sub in expired_list {
             let bot = self.bot.clone();
-            let keyboard = make_refresh_list_keyboard(list_sub.page);
+            let keyboard = make_refresh_list_keyboard(list_sub.page, &list_sub.filter);
             let text: String = MsgTaskListExpired.into();
             tokio::spawn(async move {
                 let mut rep = bot.edit_message_text(list_sub.chat_id, list_sub.message_id, text);
@@ -255,6 +975,41 @@ impl TasksCache {
                 let _ = rep.await;
             });
         }
+
+        // Handle expired stats subscribers - show refresh button
+        for stats_sub in self.subscribers.stats_subscribers.drain_expired() {
+            let bot = self.bot.clone();
+            tokio::spawn(async move {
+                let mut rep =
+                    bot.edit_message_reply_markup(stats_sub.chat_id, stats_sub.message_id);
+                rep.reply_markup = Some(make_refresh_stats_keyboard());
+                let _ = rep.await;
+            });
+        }
+    }
+
+    pub fn notify_stats_subscribers(&self) {
+        let Some(text) = self.fmt_stats() else {
+            return;
+        };
+        for &stats_sub in self.subscribers.stats_subscribers.iter() {
+            let bot = self.bot.clone();
+            let text = text.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bot
+                    .edit_message_text(stats_sub.chat_id, stats_sub.message_id, text)
+                    .await
+                {
+                    if !matches!(
+                        e,
+                        teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
+                    ) {
+                        METRICS.record_edit_failure();
+                        tracing::warn!("Failed to edit message: {e}");
+                    }
+                }
+            });
+        }
     }
 
     pub fn notify_subscribers(&mut self) {
@@ -263,26 +1018,37 @@ impl TasksCache {
 
         // Update active list subscribers
         if !self.subscribers.list_subscribers.is_empty() {
-            let tasks = self.fmt_tasks();
-            let total_pages = task_list_page_count(tasks.len(), TASK_LIST_PAGE_SIZE);
-            for &list_sub in self.subscribers.list_subscribers.iter() {
+            let mut filtered: HashMap<&TaskFilter, Vec<(String, String)>> = HashMap::new();
+            for list_sub in self.subscribers.list_subscribers.iter() {
+                let tasks = filtered
+                    .entry(&list_sub.filter)
+                    .or_insert_with(|| self.fmt_tasks(&list_sub.filter));
+                let total_pages = task_list_page_count(tasks.len(), TASK_LIST_PAGE_SIZE);
                 let bot = self.bot.clone();
                 let page = list_sub.page.min(total_pages.saturating_sub(1));
-                let keyboard = make_tasks_keyboard(tasks.clone(), page, TASK_LIST_PAGE_SIZE);
+                let keyboard = make_tasks_keyboard(
+                    tasks.clone(),
+                    page,
+                    TASK_LIST_PAGE_SIZE,
+                    &list_sub.filter,
+                    list_sub.role,
+                );
                 let text: String = MsgTaskList {
                     page: page + 1,
                     total_pages,
+                    filter: &list_sub.filter,
                 }
                 .into();
+                let (chat_id, message_id) = (list_sub.chat_id, list_sub.message_id);
                 tokio::spawn(async move {
-                    let mut rep =
-                        bot.edit_message_text(list_sub.chat_id, list_sub.message_id, text);
+                    let mut rep = bot.edit_message_text(chat_id, message_id, text);
                     rep.reply_markup = Some(keyboard);
                     if let Err(e) = rep.await {
                         if !matches!(
                             e,
                             teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
                         ) {
+                            METRICS.record_edit_failure();
                             tracing::warn!("Failed to edit message: {e}");
                         }
                     }
@@ -293,14 +1059,17 @@ impl TasksCache {
         // Update active task subscribers
         for (gid, subscribers) in self.subscribers.task_subscribers.iter() {
             if let Some((task_desc, task_status)) = self.fmt_task(gid) {
-                let keyboard = make_single_task_keyboard(
-                    gid,
-                    task_status.status.unwrap_or(TaskStatus::Removed),
-                );
+                let status = task_status.status.unwrap_or(TaskStatus::Removed);
                 for &task_sub in subscribers.iter() {
                     let bot = self.bot.clone();
                     let text = task_desc.clone();
-                    let keyboard = keyboard.clone();
+                    let keyboard = make_single_task_keyboard(
+                        gid,
+                        status,
+                        task_sub.role,
+                        self.delete_files,
+                        self.upload_files,
+                    );
                     tokio::spawn(async move {
                         let mut rep =
                             bot.edit_message_text(task_sub.chat_id, task_sub.message_id, text);
@@ -310,6 +1079,7 @@ impl TasksCache {
                                 e,
                                 teloxide::RequestError::Api(teloxide::ApiError::MessageNotModified)
                             ) {
+                                METRICS.record_edit_failure();
                                 tracing::warn!("Failed to edit message: {e}");
                             }
                         }
@@ -329,18 +1099,31 @@ impl TasksCache {
         let tasks = tokio::time::timeout(REFRESH_TIMEOUT, selected_client.get_tasks())
             .await
             .map_err(|_| anyhow::anyhow!("Refresh timeout"))??;
-        let mut tasks_cache = this.write();
-        tasks_cache.tasks = tasks
-            .into_iter()
-            .filter_map(|t| {
-                if let Some(gid) = &t.gid {
-                    Some((gid.clone(), Arc::new(t)))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        tasks_cache.last_refresh = std::time::Instant::now();
+        this.write().update_tasks(tasks.into_iter().collect());
+        Ok(())
+    }
+
+    /// Fetch the global stat, and the aria2 version when unknown.
+    pub async fn refresh_stats(
+        this: &Arc<RwLock<Self>>,
+        selected_client: &Aria2Client,
+    ) -> anyhow::Result<()> {
+        let version = if this.read().version.is_none() {
+            let version = tokio::time::timeout(REFRESH_TIMEOUT, selected_client.get_version())
+                .await
+                .map_err(|_| anyhow::anyhow!("Refresh timeout"))??;
+            Some(version)
+        } else {
+            None
+        };
+        let stat = tokio::time::timeout(REFRESH_TIMEOUT, selected_client.get_global_stat())
+            .await
+            .map_err(|_| anyhow::anyhow!("Refresh timeout"))??;
+        let mut this = this.write();
+        if version.is_some() {
+            this.version = version;
+        }
+        this.update_stats(stat);
         Ok(())
     }
 }
@@ -350,23 +1133,28 @@ pub struct ServerState {
     pub name: String,
     pub client: Aria2Client,
     pub tasks_cache: Arc<RwLock<TasksCache>>,
-    pub download_config: DownloadConfig,
+    // replaced in place when only the download config is reloaded
+    download_config: RwLock<Arc<DownloadConfig>>,
     _drop: tokio::sync::oneshot::Receiver<()>,
 }
 
 impl ServerState {
-    pub async fn new(
+    pub fn new(
         name: String,
         client: Aria2Client,
         tasks_cache: Arc<RwLock<TasksCache>>,
         download_config: DownloadConfig,
-    ) -> anyhow::Result<Self> {
+    ) -> Self {
         let (mut drop_tx, _drop) = tokio::sync::oneshot::channel();
+        tasks_cache.write().set_file_actions(
+            download_config.delete_files.is_some(),
+            download_config.upload_files.is_some(),
+        );
         let server_state = Self {
             name,
             client,
             tasks_cache,
-            download_config,
+            download_config: RwLock::new(Arc::new(download_config)),
             _drop,
         };
 
@@ -374,68 +1162,325 @@ impl ServerState {
         {
             let client = server_state.client.clone();
             let tasks_cache = server_state.tasks_cache.clone();
+            let mut events = client.subscribe();
             tokio::spawn(async move {
                 tokio::pin! {
                     let drop = drop_tx.closed();
                 }
+                let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                let mut health_interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+                health_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                let mut healthy = false;
                 loop {
                     tokio::select! {
                         _ = &mut drop => {
                             break;
                         }
-                        _ = tokio::time::sleep(REFRESH_INTERVAL) => {
-                            // Handle expired subscribers first
-                            tasks_cache.write().handle_expired_subscribers();
-
-                            // Skip refresh when no subscriber
-                            if !tasks_cache.read().has_subscriber() {
-                                continue;
-                            }
-
-                            if let Ok(Ok(tasks)) = tokio::time::timeout(REFRESH_TIMEOUT, client.get_tasks()).await {
-                                let tasks = tasks.into_iter().filter_map(|t| {
-                                    if let Some(gid) = &t.gid {
-                                        Some((gid.clone(), Arc::new(t)))
-                                    } else {
-                                        None
+                        event = events.recv() => {
+                            match event {
+                                Ok(Aria2Notification { event, gid }) => {
+                                    tracing::debug!("Received aria2 event {event:?} for {gid}");
+                                    match tokio::time::timeout(REFRESH_TIMEOUT, client.get_task(&gid)).await {
+                                        Ok(Ok(task)) => {
+                                            let mut tasks_cache = tasks_cache.write();
+                                            if tasks_cache.upsert_tasks([task]) {
+                                                tasks_cache.notify_subscribers();
+                                            }
+                                        }
+                                        _ => tasks_cache.write().invalidate(),
                                     }
-                                }).collect();
-
-                                let mut tasks_cache = tasks_cache.write();
-                                // Skip notify when nothing changes
-                                if tasks_cache.tasks == tasks {
-                                    tasks_cache.last_refresh = std::time::Instant::now();
-                                    continue;
                                 }
-                                tasks_cache.tasks = tasks;
-                                tasks_cache.last_refresh = std::time::Instant::now();
-                                tasks_cache.notify_subscribers();
+                                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                                    tracing::warn!("Missed {n} aria2 events, will resync");
+                                    tasks_cache.write().invalidate();
+                                }
+                                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
                             }
                         }
+                        _ = health_interval.tick() => {
+                            let now_healthy = client.check_health().await;
+                            if now_healthy && !healthy {
+                                // Notifications may be lost while disconnected
+                                tasks_cache.write().invalidate();
+                            }
+                            healthy = now_healthy;
+                        }
+                        _ = interval.tick() => {
+                            // Handle expired subscribers first
+                            tasks_cache.write().handle_expired_subscribers();
+                            Self::poll_stats(&client, &tasks_cache).await;
+                            Self::poll(&client, &tasks_cache).await;
+                        }
                     }
                 }
             });
         }
 
-        Ok(server_state)
+        server_state
     }
-}
 
-#[allow(clippy::type_complexity)]
-pub struct State {
-    // user id -> {server name -> ServerState{Aria2Client, TasksCache, DownloadConfig}}
-    pub server_group: HashMap<i64, SingleMultiMap<Arc<ServerState>>>,
-    // user id -> ServerState{Aria2Client, TasksCache, DownloadConfig}
-    pub server_selected: RwLock<HashMap<i64, Arc<ServerState>>>,
+    pub fn download_config(&self) -> Arc<DownloadConfig> {
+        self.download_config.read().clone()
+    }
 
-    // telearia2 internal cache: uuid -> (dir, uris)
-    pub uri_cache: Arc<Mutex<LruCache<String, (SmolStr, SmallVec<String>)>>>,
-    // telearia2 internal cache: uuid -> (dir, file_id)
-    pub file_cache: Arc<Mutex<LruCache<String, (SmolStr, String)>>>,
+    /// Apply a reloaded download config, keeping the running loop and subscribers.
+    pub fn set_download_config(&self, download_config: DownloadConfig) {
+        self.tasks_cache.write().set_file_actions(
+            download_config.delete_files.is_some(),
+            download_config.upload_files.is_some(),
+        );
+        *self.download_config.write() = Arc::new(download_config);
+    }
 
-    // shared http client for downloading files
-    pub http_client: reqwest::Client,
-}
+    /// Poll the global stat for stats subscribers.
+    async fn poll_stats(client: &Aria2Client, tasks_cache: &RwLock<TasksCache>) {
+        if !tasks_cache.read().has_stats_subscriber() {
+            return;
+        }
+        if let Ok(Ok(stat)) = tokio::time::timeout(REFRESH_TIMEOUT, client.get_global_stat()).await
+        {
+            let mut tasks_cache = tasks_cache.write();
+            // Skip notify when nothing changes
+            if tasks_cache.update_stats(stat) {
+                tasks_cache.notify_stats_subscribers();
+            }
+        }
+    }
+
+    /// Poll aria2 for what subscribers and watchers need.
+    ///
+    /// State changes arrive as aria2 notifications, so only progress and speed of
+    /// subscribed active tasks are polled, plus a periodic full sync.
+    async fn poll(client: &Aria2Client, tasks_cache: &RwLock<TasksCache>) {
+        let (needs_sync, has_list_subscriber, gids) = {
+            let tasks_cache = tasks_cache.read();
+            // Skip refresh when no subscriber or watcher
+            if !tasks_cache.has_subscriber() && !tasks_cache.has_watcher() {
+                return;
+            }
+            (
+                tasks_cache.needs_sync(),
+                tasks_cache.has_list_subscriber(),
+                tasks_cache.subscribed_active_gids(),
+            )
+        };
+
+        if needs_sync {
+            if let Ok(Ok(tasks)) = tokio::time::timeout(REFRESH_TIMEOUT, client.get_tasks()).await {
+                let mut tasks_cache = tasks_cache.write();
+                // Skip notify when nothing changes
+                if tasks_cache.update_tasks(tasks.into_iter().collect()) {
+                    tasks_cache.notify_subscribers();
+                }
+            }
+            return;
+        }
+
+        if has_list_subscriber {
+            if let Ok(Ok(tasks)) =
+                tokio::time::timeout(REFRESH_TIMEOUT, client.get_active_tasks()).await
+            {
+                let mut tasks_cache = tasks_cache.write();
+                tasks_cache.touch();
+                if tasks_cache.upsert_tasks(tasks) {
+                    tasks_cache.notify_subscribers();
+                }
+            }
+            return;
+        }
+
+        if !gids.is_empty() {
+            let mut calls = tokio::task::JoinSet::new();
+            for gid in gids {
+                let client = client.clone();
+                calls.spawn(async move {
+                    tokio::time::timeout(REFRESH_TIMEOUT, client.get_task(&gid)).await
+                });
+            }
+            let mut tasks = Vec::new();
+            while let Some(res) = calls.join_next().await {
+                if let Ok(Ok(Ok(task))) = res {
+                    tasks.push(task);
+                }
+            }
+            let mut tasks_cache = tasks_cache.write();
+            if tasks_cache.upsert_tasks(tasks) {
+                tasks_cache.notify_subscribers();
+            }
+        }
+    }
+}
+
+/// Files chosen from an uploaded torrent before it is added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentSelection {
+    pub file_id: String,
+    pub meta: TorrentMeta,
+    pub selected: Vec<bool>,
+}
+
+impl TorrentSelection {
+    pub fn new(file_id: String, meta: TorrentMeta) -> Self {
+        let selected = vec![true; meta.files.len()];
+        Self {
+            file_id,
+            meta,
+            selected,
+        }
+    }
+
+    /// Flip selection of a file, keeping at least one file selected.
+    pub fn toggle(&mut self, pos: usize) {
+        let Some(selected) = self.selected.get(pos).copied() else {
+            return;
+        };
+        if selected && self.selected_count() == 1 {
+            return;
+        }
+        self.selected[pos] = !selected;
+    }
+
+    pub fn selected_count(&self) -> usize {
+        self.selected.iter().filter(|s| **s).count()
+    }
+
+    /// 1-based indexes of selected files, None when all files are selected.
+    pub fn selected_indexes(&self) -> Option<Vec<u64>> {
+        if self.selected.iter().all(|s| *s) {
+            return None;
+        }
+        Some(
+            self.selected
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| **s)
+                .map(|(i, _)| i as u64 + 1)
+                .collect(),
+        )
+    }
+
+    pub fn entries(&self) -> Vec<FileEntry<'_>> {
+        self.meta
+            .files
+            .iter()
+            .zip(self.selected.iter())
+            .map(|(file, &selected)| FileEntry {
+                path: &file.path,
+                length: file.length,
+                selected,
+            })
+            .collect()
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A running server and the config it was built from.
+#[derive(Clone)]
+struct RunningServer {
+    config: Aria2Config,
+    // download config in effect, after applying the server override
+    download_config: DownloadConfig,
+    server: Arc<ServerState>,
+}
+
+impl RunningServer {
+    /// Start the server, connecting to aria2 in background.
+    fn start(
+        name: String,
+        config: Aria2Config,
+        download_config: DownloadConfig,
+        telegram_config: &TelegramConfig,
+        bot: &Bot,
+    ) -> Self {
+        let client = Aria2Client::connect(&config);
+        let tasks_cache = Arc::new(RwLock::new(TasksCache::new(
+            subscribe_expire(telegram_config),
+            telegram_config.notify_chat.map(ChatId),
+            bot.clone(),
+        )));
+        let server = Arc::new(ServerState::new(
+            name,
+            client,
+            tasks_cache,
+            download_config.clone(),
+        ));
+        Self {
+            config,
+            download_config,
+            server,
+        }
+    }
+}
+
+fn subscribe_expire(telegram_config: &TelegramConfig) -> Duration {
+    telegram_config
+        .subscribe_expire_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SUBSCRIBER_EXPIRE)
+}
+
+#[allow(clippy::type_complexity)]
+pub struct State {
+    // user id -> {server name -> ServerState{Aria2Client, TasksCache, DownloadConfig}}
+    server_group: RwLock<HashMap<i64, SingleMultiMap<Arc<ServerState>>>>,
+    // chat id -> selected server name, users without access to it must switch
+    server_selected: RwLock<HashMap<i64, String>>,
+    // groups whose members are authorized by their own user id
+    allowed_groups: RwLock<HashSet<i64>>,
+    // server name -> running server, diffed against reloaded configs
+    servers: Mutex<HashMap<String, RunningServer>>,
+    // user id -> {server name -> role}
+    roles: RwLock<HashMap<i64, HashMap<String, Role>>>,
+    // server name -> {user id -> role} from the config, before grants
+    config_roles: Mutex<HashMap<String, HashMap<i64, Role>>>,
+    // roles granted at runtime, layered on the config
+    auth: Mutex<AuthStore>,
+    // file to persist the grants above
+    auth_path: Option<PathBuf>,
+    // serializes changes of grants and invites while they are saved
+    auth_update_lock: tokio::sync::Mutex<()>,
+    // users who can grant and revoke roles
+    owners: RwLock<HashSet<i64>>,
+    // actions changing aria2 state, disabled without a path
+    pub audit: AuditLog,
+    // largest file sent to chats in bytes
+    upload_limit: AtomicU64,
+    // whether the Bot API server is local, fixed at start like the bot itself
+    local_api: bool,
+
+    // telearia2 internal cache: uuid -> (dir, uris)
+    pub uri_cache: Arc<Mutex<LruCache<String, (SmolStr, SmallVec<String>)>>>,
+    // telearia2 internal cache: uuid -> (dir, file_id, selection uuid)
+    pub file_cache: Arc<Mutex<LruCache<String, (SmolStr, String, Option<String>)>>>,
+    // telearia2 internal cache: uuid -> torrent file selection
+    pub torrent_selection: Arc<Mutex<LruCache<String, TorrentSelection>>>,
+    // telearia2 internal cache: file_id -> torrent file downloaded from Telegram
+    pub torrent_data: Arc<Mutex<LruCache<String, Bytes>>>,
+    // uuid -> action waiting for confirmation
+    pending_confirm: Mutex<LruCache<String, PendingConfirm>>,
+    // (server, gid) of tasks whose files are being sent
+    uploading: Mutex<HashSet<(String, SmolStr)>>,
+    // users who run remove and purge without confirming
+    skip_confirm: RwLock<HashSet<i64>>,
+
+    // shared http client for downloading files
+    pub http_client: reqwest::Client,
+    // bot without the request timeout, for sending large files
+    pub upload_bot: Bot,
+
+    // file to persist the state above across restarts
+    store_path: Option<PathBuf>,
+    // serializes saves of the periodic task and shutdown
+    save_lock: tokio::sync::Mutex<()>,
+    // used by task caches of servers added on reload
+    bot: Bot,
+}
 
 impl State {
     pub async fn new<C: Param<Aria2ConfigGroup> + Param<TelegramConfig> + Param<DownloadConfig>>(
@@ -445,41 +1490,197 @@ impl State {
         let telegram_config: TelegramConfig = cfg.param();
         let client_config_group: Aria2ConfigGroup = cfg.param();
         let default_download_config: DownloadConfig = cfg.param();
+        if telegram_config.local_api && telegram_config.api_url.is_none() {
+            // The public Bot API never hands out local paths
+            anyhow::bail!("local_api requires api_url of the local Bot API server");
+        }
 
-        let mut server_group_builder: HashMap<i64, HashMap<String, Arc<ServerState>>> =
-            HashMap::new();
+        let mut servers = HashMap::new();
         for (name, client_config) in client_config_group.into_iter() {
-            let client = Aria2Client::connect(&client_config).await?;
-            let tasks_cache = Arc::new(RwLock::new(TasksCache::new(
-                telegram_config
-                    .subscribe_expire_secs
-                    .map(std::time::Duration::from_secs)
-                    .unwrap_or(DEFAULT_SUBSCRIBER_EXPIRE),
-                bot.clone(),
-            )));
             let download_config = client_config
                 .download_override
                 .clone()
                 .unwrap_or_else(|| default_download_config.clone());
-            let server_state = Arc::new(
-                ServerState::new(
-                    name.clone(),
-                    client.clone(),
-                    tasks_cache.clone(),
-                    download_config,
-                )
-                .await?,
+            let running = RunningServer::start(
+                name.clone(),
+                client_config,
+                download_config,
+                &telegram_config,
+                &bot,
             );
+            servers.insert(name, running);
+        }
+
+        let auth_path = telegram_config.auth_path.as_ref().map(PathBuf::from);
+        let auth = match auth_path.as_ref().map(AuthStore::load) {
+            Some(Ok(auth)) => auth,
+            Some(Err(e)) => anyhow::bail!("unable to load granted roles: {e}"),
+            None => AuthStore::default(),
+        };
+
+        let state = Self {
+            server_group: RwLock::new(HashMap::new()),
+            server_selected: RwLock::new(HashMap::new()),
+            allowed_groups: RwLock::new(HashSet::new()),
+            servers: Mutex::new(HashMap::new()),
+            roles: RwLock::new(HashMap::new()),
+            config_roles: Mutex::new(HashMap::new()),
+            auth: Mutex::new(auth),
+            auth_path,
+            auth_update_lock: tokio::sync::Mutex::new(()),
+            owners: RwLock::new(HashSet::new()),
+            upload_limit: AtomicU64::new(DEFAULT_UPLOAD_LIMIT),
+            local_api: telegram_config.local_api,
+            audit: AuditLog::new(telegram_config.audit_path.as_ref().map(PathBuf::from)),
+            uri_cache: Arc::new(Mutex::new(LruCache::new(URI_LRU_SIZE))),
+            file_cache: Arc::new(Mutex::new(LruCache::new(URI_LRU_SIZE))),
+            torrent_selection: Arc::new(Mutex::new(LruCache::new(URI_LRU_SIZE))),
+            torrent_data: Arc::new(Mutex::new(LruCache::new(TORRENT_DATA_LRU_SIZE))),
+            pending_confirm: Mutex::new(LruCache::new(URI_LRU_SIZE)),
+            uploading: Mutex::new(HashSet::new()),
+            skip_confirm: RwLock::new(HashSet::new()),
+            http_client: reqwest::Client::new(),
+            upload_bot: Bot::with_client(
+                bot.token(),
+                reqwest::Client::builder()
+                    .connect_timeout(UPLOAD_CONNECT_TIMEOUT)
+                    .build()?,
+            )
+            .set_api_url(bot.api_url()),
+            store_path: telegram_config.state_path.as_ref().map(PathBuf::from),
+            save_lock: tokio::sync::Mutex::new(()),
+            bot,
+        };
+        state.apply_servers(servers, &telegram_config);
+        if let Some(path) = state.store_path.as_ref() {
+            match StoredState::load(path) {
+                Ok(stored) => state.restore(stored),
+                Err(e) => tracing::error!("Unable to load state from {}: {e}", path.display()),
+            }
+        }
+        Ok(state)
+    }
+
+    /// Apply a reloaded config.
+    ///
+    /// Only servers whose config changed are rebuilt, the others keep running
+    /// with their subscribers. Servers failing to connect keep their old config.
+    pub async fn reload<
+        C: Param<Aria2ConfigGroup> + Param<TelegramConfig> + Param<DownloadConfig>,
+    >(
+        &self,
+        cfg: &C,
+    ) {
+        let telegram_config: TelegramConfig = cfg.param();
+        let client_config_group: Aria2ConfigGroup = cfg.param();
+        let default_download_config: DownloadConfig = cfg.param();
 
-            let admins = client_config
-                .admins_override
-                .as_ref()
-                .unwrap_or(&telegram_config.admins);
-            for admin in admins.iter() {
+        let mut current = self.servers.lock().clone();
+        let mut servers = HashMap::new();
+        for (name, client_config) in client_config_group.into_iter() {
+            let download_config = client_config
+                .download_override
+                .clone()
+                .unwrap_or_else(|| default_download_config.clone());
+            let running = match current.remove(&name) {
+                Some(running) if running.config.same_connection(&client_config) => {
+                    running.server.tasks_cache.write().reconfigure(
+                        subscribe_expire(&telegram_config),
+                        telegram_config.notify_chat.map(ChatId),
+                    );
+                    if running.download_config != download_config {
+                        running.server.set_download_config(download_config.clone());
+                        tracing::info!("Download config of server {name} updated");
+                    }
+                    RunningServer {
+                        config: client_config,
+                        download_config,
+                        ..running
+                    }
+                }
+                old => {
+                    let running = RunningServer::start(
+                        name.clone(),
+                        client_config,
+                        download_config,
+                        &telegram_config,
+                        &self.bot,
+                    );
+                    match old {
+                        // Keep a working server until the new config connects, e.g. on a typo
+                        Some(old) => {
+                            let client = &running.server.client;
+                            if client.wait_connected(ARIA2_CONNECT_TIMEOUT).await {
+                                tracing::info!("Server {name} reconnected");
+                                running
+                            } else {
+                                tracing::error!(
+                                    "Unable to connect server {name} with the new config, keep the old one"
+                                );
+                                old
+                            }
+                        }
+                        None => {
+                            tracing::info!("Server {name} added");
+                            running
+                        }
+                    }
+                }
+            };
+            servers.insert(name, running);
+        }
+        for name in current.keys() {
+            tracing::info!("Server {name} removed");
+        }
+        self.apply_servers(servers, &telegram_config);
+    }
+
+    /// Replace the running servers, keeping selections of servers still available.
+    fn apply_servers(
+        &self,
+        servers: HashMap<String, RunningServer>,
+        telegram_config: &TelegramConfig,
+    ) {
+        *self.config_roles.lock() = servers
+            .iter()
+            .map(|(name, running)| (name.clone(), running.config.roles(telegram_config)))
+            .collect();
+        self.server_selected
+            .write()
+            .retain(|_, name| servers.contains_key(name));
+        *self.servers.lock() = servers;
+        *self.allowed_groups.write() = telegram_config.allowed_groups.iter().copied().collect();
+        *self.owners.write() = telegram_config.owners.iter().copied().collect();
+        let default_limit = if self.local_api {
+            LOCAL_UPLOAD_LIMIT
+        } else {
+            DEFAULT_UPLOAD_LIMIT
+        };
+        let upload_limit = telegram_config
+            .upload_limit_mb
+            .map_or(default_limit, |mb| mb.saturating_mul(1024 * 1024));
+        self.upload_limit.store(upload_limit, Ordering::Relaxed);
+        self.rebuild_groups();
+    }
+
+    /// Rebuild user server groups from the configured and granted roles.
+    fn rebuild_groups(&self) {
+        let servers = self.servers.lock();
+        let config_roles = self.config_roles.lock();
+        let auth = self.auth.lock();
+
+        let mut server_group_builder: HashMap<i64, HashMap<String, Arc<ServerState>>> =
+            HashMap::new();
+        let mut roles: HashMap<i64, HashMap<String, Role>> = HashMap::new();
+        for (name, running) in servers.iter() {
+            let mut server_roles = config_roles.get(name).cloned().unwrap_or_default();
+            server_roles.extend(auth.roles(name));
+            for (user, role) in server_roles {
                 server_group_builder
-                    .entry(*admin)
+                    .entry(user)
                     .or_default()
-                    .insert(name.clone(), server_state.clone());
+                    .insert(name.clone(), running.server.clone());
+                roles.entry(user).or_default().insert(name.clone(), role);
             }
         }
 
@@ -487,45 +1688,390 @@ impl State {
             .into_iter()
             .filter_map(|(k, v)| SingleMultiMap::try_from(v).ok().map(|smm| (k, smm)))
             .collect();
+        *self.server_group.write() = server_group;
+        *self.roles.write() = roles;
+    }
 
-        let mut server_selected = HashMap::new();
-        for (&user, servers) in server_group.iter() {
-            // If user only has one server, select it automatically
-            if let Some(server) = servers.unwrap_single_ref() {
-                server_selected.insert(user, server.clone());
-            }
+    pub fn is_owner(&self, user_id: i64) -> bool {
+        self.owners.read().contains(&user_id)
+    }
+
+    /// Name of the only server, if there is just one.
+    pub fn single_server(&self) -> Option<String> {
+        let servers = self.servers.lock();
+        (servers.len() == 1).then(|| servers.keys().next().cloned())?
+    }
+
+    /// Grant the role on the server, returns the role granted before.
+    pub async fn grant(
+        &self,
+        user_id: i64,
+        server: &str,
+        role: Role,
+    ) -> anyhow::Result<Option<Role>> {
+        if !self.servers.lock().contains_key(server) {
+            anyhow::bail!("unknown server {server}");
         }
+        self.update_auth(|auth| auth.grant(user_id, server, role))
+            .await
+    }
 
-        Ok(Self {
-            server_group,
-            server_selected: RwLock::new(server_selected),
-            uri_cache: Arc::new(Mutex::new(LruCache::new(URI_LRU_SIZE))),
-            file_cache: Arc::new(Mutex::new(LruCache::new(URI_LRU_SIZE))),
-            http_client: reqwest::Client::new(),
+    /// Revoke grants on the server or on all servers, returns how many were removed.
+    pub async fn revoke(&self, user_id: i64, server: Option<&str>) -> anyhow::Result<usize> {
+        self.update_auth(|auth| auth.revoke(user_id, server)).await
+    }
+
+    /// Whether grants and invites are kept across restarts.
+    pub fn persists_auth(&self) -> bool {
+        self.auth_path.is_some()
+    }
+
+    /// Change the grants and persist them, a failed save changes nothing.
+    ///
+    /// The file is written on a blocking thread, updates wait for each other meanwhile.
+    async fn update_auth<R>(&self, f: impl FnOnce(&mut AuthStore) -> R) -> anyhow::Result<R> {
+        let _guard = self.auth_update_lock.lock().await;
+        let mut updated = self.auth.lock().clone();
+        let res = f(&mut updated);
+        if updated == *self.auth.lock() {
+            return Ok(res);
+        }
+        if let Some(path) = self.auth_path.clone() {
+            let stored = updated.clone();
+            tokio::task::spawn_blocking(move || stored.save(path)).await??;
+        }
+        *self.auth.lock() = updated;
+        self.rebuild_groups();
+        Ok(res)
+    }
+
+    /// Servers the user can invite to: every server for owners, else those the user administers.
+    pub fn invitable_servers(&self, user_id: i64) -> Vec<String> {
+        if self.is_owner(user_id) {
+            let mut servers: Vec<String> = self.servers.lock().keys().cloned().collect();
+            servers.sort_unstable();
+            return servers;
+        }
+        let mut servers: Vec<String> = self
+            .roles
+            .read()
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, role)| **role >= Role::Admin)
+            .map(|(name, _)| name.clone())
+            .collect();
+        servers.sort_unstable();
+        servers
+    }
+
+    /// Create an invite code valid for [`INVITE_EXPIRE`].
+    pub async fn create_invite(&self, role: Role, servers: Vec<String>) -> anyhow::Result<String> {
+        let now = unix_secs(SystemTime::now());
+        let invite = Invite {
+            role,
+            servers,
+            expires_at: now + INVITE_EXPIRE.as_secs(),
+        };
+        self.update_auth(|auth| auth.add_invite(invite, now)).await
+    }
+
+    /// Redeem an invite code, only raising the roles the user already has.
+    ///
+    /// Returns the role the user ends up with on each invited server.
+    pub async fn redeem_invite(
+        &self,
+        user_id: i64,
+        code: &str,
+    ) -> anyhow::Result<Option<BTreeMap<String, Role>>> {
+        let current = self.roles.read().get(&user_id).cloned().unwrap_or_default();
+        let servers: HashSet<String> = self.servers.lock().keys().cloned().collect();
+        let now = unix_secs(SystemTime::now());
+        self.update_auth(|auth| {
+            let invite = auth.take_invite(code, now)?;
+            let mut roles = BTreeMap::new();
+            for server in invite.servers.into_iter().filter(|s| servers.contains(s)) {
+                let role = match current.get(&server) {
+                    Some(role) if *role >= invite.role => *role,
+                    _ => {
+                        auth.grant(user_id, &server, invite.role);
+                        invite.role
+                    }
+                };
+                roles.insert(server, role);
+            }
+            Some(roles)
         })
+        .await
+    }
+
+    /// Largest file sent to chats in bytes.
+    pub fn upload_limit(&self) -> u64 {
+        self.upload_limit.load(Ordering::Relaxed)
+    }
+
+    /// Whether files are read from the local Bot API server's disk.
+    pub fn local_api(&self) -> bool {
+        self.local_api
+    }
+
+    /// Largest torrent file accepted in bytes.
+    pub fn torrent_size_limit(&self) -> u32 {
+        if self.local_api {
+            LOCAL_MAX_TORRENT_SIZE
+        } else {
+            MAX_TORRENT_SIZE
+        }
+    }
+
+    /// Whether the user confirms remove and purge before they run.
+    pub fn confirms(&self, user_id: i64) -> bool {
+        !self.skip_confirm.read().contains(&user_id)
+    }
+
+    pub fn set_confirms(&self, user_id: i64, confirms: bool) {
+        let mut skip_confirm = self.skip_confirm.write();
+        if confirms {
+            skip_confirm.remove(&user_id);
+        } else {
+            skip_confirm.insert(user_id);
+        }
+    }
+
+    /// Keep the action until confirmed, returns the uuid of the confirm button.
+    pub fn request_confirm(&self, action: ConfirmAction, server: &str) -> String {
+        let uuid = uuid::Uuid::new_v4().simple().to_string();
+        let pending = PendingConfirm {
+            action,
+            server: server.to_string(),
+            expires_at: unix_secs(SystemTime::now()) + CONFIRM_EXPIRE.as_secs(),
+        };
+        self.pending_confirm.lock().insert(uuid.clone(), pending);
+        uuid
+    }
+
+    /// Take the action to run on the server.
+    ///
+    /// Asked on another server, the action is kept to be confirmed after switching back.
+    pub fn take_confirm(&self, uuid: &str, server: &str) -> ConfirmResult {
+        let now = unix_secs(SystemTime::now());
+        let mut pending_confirm = self.pending_confirm.lock();
+        let Some(pending) = pending_confirm.get(uuid) else {
+            return ConfirmResult::Expired;
+        };
+        if pending.expires_at > now && pending.server != server {
+            return ConfirmResult::OtherServer(pending.server.clone());
+        }
+        match pending_confirm.remove(uuid) {
+            Some(pending) if pending.expires_at > now => ConfirmResult::Run(pending.action),
+            _ => ConfirmResult::Expired,
+        }
+    }
+
+    /// Mark files of the task as being sent, false when they already are.
+    pub fn start_upload(&self, server: &str, gid: &str) -> bool {
+        self.uploading
+            .lock()
+            .insert((server.to_string(), gid.into()))
+    }
+
+    pub fn finish_upload(&self, server: &str, gid: &str) {
+        self.uploading
+            .lock()
+            .remove(&(server.to_string(), gid.into()));
+    }
+
+    /// Forget the action, its prompt was cancelled.
+    pub fn cancel_confirm(&self, uuid: &str) {
+        self.pending_confirm.lock().remove(uuid);
+    }
+
+    /// Users of every server with their role and whether it was granted at runtime.
+    pub fn users(&self) -> BTreeMap<String, BTreeMap<i64, (Role, bool)>> {
+        let config_roles = self.config_roles.lock();
+        let auth = self.auth.lock();
+        config_roles
+            .iter()
+            .map(|(name, roles)| {
+                let mut users: BTreeMap<i64, (Role, bool)> = roles
+                    .iter()
+                    .map(|(&user, &role)| (user, (role, false)))
+                    .collect();
+                users.extend(auth.roles(name).map(|(user, role)| (user, (role, true))));
+                (name.clone(), users)
+            })
+            .collect()
+    }
+
+    /// All servers by name.
+    pub fn servers(&self) -> HashMap<String, Arc<ServerState>> {
+        self.servers
+            .lock()
+            .iter()
+            .map(|(name, running)| (name.clone(), running.server.clone()))
+            .collect()
+    }
+
+    fn snapshot(&self) -> StoredState {
+        StoredState {
+            server_selected: self.server_selected.read().clone(),
+            uri_cache: self
+                .uri_cache
+                .lock()
+                .iter()
+                .map(|(uuid, (dir, uris))| (uuid.clone(), dir.clone(), uris.to_vec()))
+                .collect(),
+            file_cache: self
+                .file_cache
+                .lock()
+                .iter()
+                .map(|(uuid, (dir, file_id, selection))| {
+                    (
+                        uuid.clone(),
+                        dir.clone(),
+                        file_id.clone(),
+                        selection.clone(),
+                    )
+                })
+                .collect(),
+            torrent_selection: self
+                .torrent_selection
+                .lock()
+                .iter()
+                .map(|(uuid, selection)| (uuid.clone(), selection.clone()))
+                .collect(),
+            pending_confirm: self
+                .pending_confirm
+                .lock()
+                .iter()
+                .map(|(uuid, pending)| (uuid.clone(), pending.clone()))
+                .collect(),
+            skip_confirm: self.skip_confirm.read().iter().copied().collect(),
+            servers: self
+                .servers()
+                .into_iter()
+                .map(|(name, server)| (name, server.tasks_cache.read().snapshot()))
+                .collect(),
+        }
+    }
+
+    fn restore(&self, stored: StoredState) {
+        {
+            let servers = self.servers.lock();
+            self.server_selected.write().extend(
+                stored
+                    .server_selected
+                    .into_iter()
+                    .filter(|(_, name)| servers.contains_key(name)),
+            );
+        }
+        {
+            let mut uri_cache = self.uri_cache.lock();
+            for (uuid, dir, uris) in stored.uri_cache {
+                uri_cache.insert(uuid, (dir, uris.into()));
+            }
+        }
+        {
+            let mut file_cache = self.file_cache.lock();
+            for (uuid, dir, file_id, selection) in stored.file_cache {
+                file_cache.insert(uuid, (dir, file_id, selection));
+            }
+        }
+        {
+            let mut torrent_selection = self.torrent_selection.lock();
+            for (uuid, selection) in stored.torrent_selection {
+                torrent_selection.insert(uuid, selection);
+            }
+        }
+        {
+            let mut pending_confirm = self.pending_confirm.lock();
+            for (uuid, pending) in stored.pending_confirm {
+                pending_confirm.insert(uuid, pending);
+            }
+        }
+        self.skip_confirm.write().extend(stored.skip_confirm);
+        let servers = self.servers();
+        for (name, stored_server) in stored.servers {
+            if let Some(server) = servers.get(name.as_str()) {
+                server.tasks_cache.write().restore(stored_server);
+            }
+        }
+    }
+
+    /// Save the state if a store is configured.
+    ///
+    /// Only the snapshot is taken here, serializing and writing run on a blocking thread.
+    pub async fn save(&self) {
+        let Some(path) = self.store_path.clone() else {
+            return;
+        };
+        // Saves share the temporary file, and a later snapshot must be written last
+        let _guard = self.save_lock.lock().await;
+        let stored = self.snapshot();
+        let res = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || stored.save(path)).await
+        };
+        if let Err(e) = res.map_err(anyhow::Error::from).and_then(|res| res) {
+            tracing::error!("Unable to save state to {}: {e}", path.display());
+        }
     }
 
     #[inline]
-    pub fn authorized(&self, user_id: i64) -> Option<impl Iterator<Item = &str>> {
+    pub fn authorized(&self, user_id: i64) -> Option<Vec<Arc<ServerState>>> {
         self.server_group
+            .read()
+            .get(&user_id)
+            .map(|servers| servers.iter().map(|(_, server)| server.clone()).collect())
+    }
+
+    /// Role of the user on the server, None when unknown, e.g. just removed by a reload.
+    pub fn role(&self, user_id: i64, server: &ServerState) -> Option<Role> {
+        self.roles
+            .read()
             .get(&user_id)
-            .map(|servers| servers.iter().map(|(name, _)| name))
+            .and_then(|roles| roles.get(&server.name))
+            .copied()
+    }
+
+    /// Id whose roles apply to a message from the user in the chat.
+    ///
+    /// Private chats and groups listed as a whole act as the chat, members of
+    /// allowed groups act as themselves.
+    pub fn principal(&self, chat: &Chat, user: Option<&User>) -> i64 {
+        if chat.is_private() || self.server_group.read().contains_key(&chat.id.0) {
+            return chat.id.0;
+        }
+        match user {
+            Some(user) if self.allowed_groups.read().contains(&chat.id.0) => user.id.0 as i64,
+            _ => chat.id.0,
+        }
     }
 
+    /// Server selected in the chat, if the user can access it.
     #[inline]
-    pub fn selected(&self, user_id: i64) -> Option<Arc<ServerState>> {
-        self.server_selected.read().get(&user_id).cloned()
+    pub fn selected(&self, chat_id: i64, user_id: i64) -> Option<Arc<ServerState>> {
+        let server_group = self.server_group.read();
+        let servers = server_group.get(&user_id)?;
+        // If user only has one server, select it automatically
+        if let Some(server) = servers.unwrap_single_ref() {
+            return Some(server.clone());
+        }
+        let selected = self.server_selected.read();
+        servers.get(selected.get(&chat_id)?).cloned()
     }
 
     #[inline]
-    pub fn try_select(&self, user_id: i64, server: &str) -> SelectResult {
-        if let Some(servers) = self.server_group.get(&user_id) {
+    pub fn try_select(&self, chat_id: i64, user_id: i64, server: &str) -> SelectResult {
+        if let Some(servers) = self.server_group.read().get(&user_id) {
             if servers.unwrap_single_ref().is_some() {
                 // If user only has one server, no need to select
                 return SelectResult::NoNeed;
             }
-            if let Some(server) = servers.get(server) {
-                self.server_selected.write().insert(user_id, server.clone());
+            if servers.get(server).is_some() {
+                self.server_selected
+                    .write()
+                    .insert(chat_id, server.to_string());
                 return SelectResult::Success;
             }
         }
@@ -539,3 +2085,458 @@ pub enum SelectResult {
     NoNeed,
     Failure,
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfirmResult {
+    Run(ConfirmAction),
+    // server the action was asked on
+    OtherServer(String),
+    // used or expired
+    Expired,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::TorrentFile;
+
+    fn make_task(gid: &str, status: TaskStatus, seeder: Option<bool>) -> (SmolStr, Arc<Status>) {
+        let task = Status {
+            gid: Some(gid.into()),
+            status: Some(status),
+            total_length: None,
+            completed_length: None,
+            upload_length: None,
+            bitfield: None,
+            download_speed: None,
+            upload_speed: None,
+            info_hash: None,
+            num_seeders: None,
+            seeder,
+            connections: None,
+            error_code: None,
+            error_message: None,
+            followed_by: None,
+            following: None,
+            belongs_to: None,
+            dir: None,
+            files: None,
+            bittorrent: None,
+            num_pieces: None,
+            piece_length: None,
+        };
+        (gid.into(), Arc::new(task))
+    }
+
+    fn events(old: &TasksMap, new: &TasksMap, tracked: &[&str]) -> Vec<(String, TaskEvent)> {
+        let tracked = tracked
+            .iter()
+            .map(|gid| (SmolStr::from(*gid), ChatId(1)))
+            .collect();
+        let mut events: Vec<_> = detect_transitions(old, new, &tracked)
+            .into_iter()
+            .map(|(t, e)| (t.gid.as_deref().unwrap().to_string(), e))
+            .collect();
+        events.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        events
+    }
+
+    #[test]
+    fn test_speed_tracker() {
+        let start = Instant::now();
+        let mut tracker = SpeedTracker::default();
+        let mut task = Arc::unwrap_or_clone(make_task("a", TaskStatus::Active, None).1);
+        task.total_length = Some(10000);
+        for (i, speed) in [100, 200, 300, 400, 500, 600].into_iter().enumerate() {
+            task.completed_length = Some(1000 * i as u64);
+            task.download_speed = Some(speed);
+            tracker.record(&task, start + Duration::from_secs(i as u64));
+        }
+        // Only the latest samples count, averaging 400 B/s for 5000 B remaining
+        assert_eq!(tracker.eta(&task), Some(Duration::from_secs(13)));
+        assert_eq!(tracker.average_speed(), None);
+
+        task.status = Some(TaskStatus::Complete);
+        task.completed_length = Some(10000);
+        tracker.record(&task, start + Duration::from_secs(10));
+        assert_eq!(tracker.eta(&task), None);
+        assert_eq!(tracker.average_speed(), Some(1000));
+
+        // First seen midway, the average since start is unknown
+        let mut tracker = SpeedTracker::default();
+        task.status = Some(TaskStatus::Active);
+        task.completed_length = Some(5000);
+        tracker.record(&task, start);
+        task.status = Some(TaskStatus::Complete);
+        task.completed_length = Some(10000);
+        tracker.record(&task, start + Duration::from_secs(5));
+        assert_eq!(tracker.average_speed(), None);
+    }
+
+    #[test]
+    fn test_task_filter_args() {
+        assert_eq!(TaskFilter::from_args(""), TaskFilter::All);
+        assert_eq!(TaskFilter::from_args(" Error "), TaskFilter::Error);
+        assert_eq!(
+            TaskFilter::from_args("Ubuntu ISO"),
+            TaskFilter::Search("ubuntu iso".into())
+        );
+        let long = "é".repeat(30);
+        let TaskFilter::Search(search) = TaskFilter::from_args(&long) else {
+            panic!("expect search filter");
+        };
+        assert_eq!(search, "é".repeat(20));
+        // 'İ' lowercases from 2 to 3 bytes
+        let TaskFilter::Search(search) = TaskFilter::from_args(&"İ".repeat(20)) else {
+            panic!("expect search filter");
+        };
+        assert!(search.len() <= TaskFilter::MAX_SEARCH_LEN);
+    }
+
+    #[test]
+    fn test_task_filter_callback() {
+        for filter in [
+            TaskFilter::All,
+            TaskFilter::Active,
+            TaskFilter::Error,
+            TaskFilter::Complete,
+            TaskFilter::Search("a|b".into()),
+        ] {
+            assert_eq!(
+                TaskFilter::from_callback(&filter.to_callback()),
+                Some(filter)
+            );
+        }
+        assert_eq!(TaskFilter::from_callback("unknown"), None);
+    }
+
+    #[test]
+    fn test_task_filter_matches() {
+        let (_, paused) = make_task("Paused-Task", TaskStatus::Paused, None);
+        let (_, errored) = make_task("errored", TaskStatus::Error, None);
+        assert!(TaskFilter::Active.matches(&paused));
+        assert!(!TaskFilter::Active.matches(&errored));
+        assert!(TaskFilter::Error.matches(&errored));
+        assert!(TaskFilter::Search("task".into()).matches(&paused));
+        assert!(!TaskFilter::Search("task".into()).matches(&errored));
+    }
+
+    #[test]
+    fn test_torrent_selection() {
+        let files = (0..3)
+            .map(|i| TorrentFile {
+                path: format!("f{i}"),
+                length: 1,
+            })
+            .collect();
+        let meta = TorrentMeta {
+            name: "t".into(),
+            info_hash: String::new(),
+            piece_length: 16384,
+            files,
+        };
+        let mut selection = TorrentSelection::new("id".into(), meta);
+        assert_eq!(selection.selected_indexes(), None);
+        selection.toggle(1);
+        assert_eq!(selection.selected_indexes(), Some(vec![1, 3]));
+        selection.toggle(0);
+        selection.toggle(2);
+        // The last selected file can not be deselected
+        assert_eq!(selection.selected_indexes(), Some(vec![3]));
+        selection.toggle(5);
+        assert_eq!(selection.selected_count(), 1);
+    }
+
+    #[test]
+    fn test_transition_active_to_complete() {
+        let old = TasksMap::from_iter([make_task("a", TaskStatus::Active, None)]);
+        let new = TasksMap::from_iter([make_task("a", TaskStatus::Complete, None)]);
+        assert_eq!(
+            events(&old, &new, &[]),
+            vec![("a".to_string(), TaskEvent::Complete)]
+        );
+    }
+
+    #[test]
+    fn test_transition_unchanged_state() {
+        let old = TasksMap::from_iter([make_task("a", TaskStatus::Error, None)]);
+        let new = TasksMap::from_iter([make_task("a", TaskStatus::Error, None)]);
+        assert!(events(&old, &new, &["a"]).is_empty());
+    }
+
+    #[test]
+    fn test_transition_unknown_task() {
+        let old = TasksMap::new();
+        let new = TasksMap::from_iter([
+            make_task("a", TaskStatus::Complete, None),
+            make_task("b", TaskStatus::Error, None),
+        ]);
+        // Only tracked tasks fire when there is no previous state
+        assert_eq!(
+            events(&old, &new, &["b"]),
+            vec![("b".to_string(), TaskEvent::Error)]
+        );
+    }
+
+    #[test]
+    fn test_transition_seeding() {
+        let old = TasksMap::from_iter([make_task("a", TaskStatus::Active, Some(false))]);
+        let seeding = TasksMap::from_iter([make_task("a", TaskStatus::Active, Some(true))]);
+        let complete = TasksMap::from_iter([make_task("a", TaskStatus::Complete, Some(true))]);
+        assert_eq!(
+            events(&old, &seeding, &[]),
+            vec![("a".to_string(), TaskEvent::Seeding)]
+        );
+        assert!(events(&seeding, &seeding, &[]).is_empty());
+        assert_eq!(
+            events(&seeding, &complete, &[]),
+            vec![("a".to_string(), TaskEvent::Complete)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        let mut cache = TasksCache::new(DEFAULT_SUBSCRIBER_EXPIRE, None, Bot::new("bot_token"));
+        cache.add_list_subscriber(
+            ChatId(1),
+            MessageId(2),
+            3,
+            TaskFilter::Search("iso".into()),
+            Role::Operator,
+        );
+        cache.add_task_subscriber("a".into(), ChatId(1), MessageId(4), Role::Admin);
+        cache.add_stats_subscriber(ChatId(5), MessageId(6));
+        cache.track_task("a".into(), ChatId(1));
+        let source = TorrentSource {
+            file_id: "id".into(),
+            selected_files: Some(vec![1, 3]),
+        };
+        cache.track_torrent("a".into(), source.clone());
+
+        let json = serde_json::to_string(&cache.snapshot()).unwrap();
+        let mut restored = TasksCache::new(DEFAULT_SUBSCRIBER_EXPIRE, None, Bot::new("bot_token"));
+        restored.restore(serde_json::from_str(&json).unwrap());
+
+        assert_eq!(restored.owners.get("a"), Some(&ChatId(1)));
+        assert_eq!(restored.torrent_source("a"), Some(&source));
+        assert_eq!(
+            restored.list_subscriber_view(ChatId(1), MessageId(2)),
+            Some((3, &TaskFilter::Search("iso".into())))
+        );
+        let task_sub = restored.subscribers.task_subscribers["a"].iter().next();
+        assert_eq!(
+            task_sub.map(|sub| (sub.message_id, sub.role)),
+            Some((MessageId(4), Role::Admin))
+        );
+        assert!(restored.has_stats_subscriber());
+    }
+
+    #[tokio::test]
+    async fn test_forget_gone_owners() {
+        let mut cache = TasksCache::new(DEFAULT_SUBSCRIBER_EXPIRE, None, Bot::new("bot_token"));
+        cache.track_task("a".into(), ChatId(1));
+        cache.track_task("b".into(), ChatId(1));
+        cache.track_torrent(
+            "a".into(),
+            TorrentSource {
+                file_id: "id".into(),
+                selected_files: None,
+            },
+        );
+        cache.update_tasks(TasksMap::from_iter([
+            make_task("a", TaskStatus::Active, None),
+            make_task("b", TaskStatus::Paused, None),
+        ]));
+        assert!(cache.has_watcher());
+
+        // Removed tasks are forgotten on any update
+        cache.upsert_tasks([Arc::unwrap_or_clone(
+            make_task("b", TaskStatus::Removed, None).1,
+        )]);
+        assert!(!cache.owners.contains_key("b"));
+        // Tasks missing from two full snapshots, e.g. removed and purged outside the bot
+        cache.update_tasks(TasksMap::new());
+        assert!(cache.has_watcher());
+        assert!(cache.torrent_source("a").is_some());
+        cache.update_tasks(TasksMap::new());
+        assert!(!cache.has_watcher());
+        assert!(cache.torrent_source("a").is_none());
+
+        // Tasks added while a snapshot was fetched are kept
+        cache.track_task("c".into(), ChatId(1));
+        cache.update_tasks(TasksMap::new());
+        cache.update_tasks(TasksMap::from_iter([make_task(
+            "c",
+            TaskStatus::Active,
+            None,
+        )]));
+        cache.update_tasks(TasksMap::new());
+        assert!(cache.has_watcher());
+    }
+
+    #[tokio::test]
+    async fn test_group_principal() {
+        let toml = r#"
+[aria2.one]
+rpc_url = "ws://127.0.0.1:1/jsonrpc"
+token = "secret"
+
+[aria2.two]
+rpc_url = "ws://127.0.0.1:2/jsonrpc"
+token = "secret"
+admins_override = [1]
+
+[telegram]
+token = "bot_token"
+admins = [1, -1001]
+allowed_groups = [-1002]
+
+[download]
+magnet_dirs = []
+torrent_dirs = []
+link_dirs = []
+default_dir = "/data"
+"#;
+        let config: crate::config::Config = toml::from_str(toml).unwrap();
+        let state = State::new(&config, Bot::new("bot_token")).await.unwrap();
+        let chat = |id: i64, kind: &str| -> Chat {
+            serde_json::from_value(serde_json::json!({"id": id, "type": kind, "title": "g"}))
+                .unwrap()
+        };
+        let user: User = serde_json::from_value(
+            serde_json::json!({"id": 1, "is_bot": false, "first_name": "u"}),
+        )
+        .unwrap();
+
+        assert_eq!(state.principal(&chat(1, "private"), Some(&user)), 1);
+        // Groups listed as a whole act for every member
+        assert_eq!(
+            state.principal(&chat(-1001, "supergroup"), Some(&user)),
+            -1001
+        );
+        assert_eq!(state.principal(&chat(-1002, "supergroup"), Some(&user)), 1);
+        assert_eq!(
+            state.principal(&chat(-1003, "supergroup"), Some(&user)),
+            -1003
+        );
+        assert!(state.authorized(-1003).is_none());
+
+        // Single server is selected automatically
+        assert_eq!(state.selected(-1001, -1001).unwrap().name, "one");
+        // Selection is kept per chat
+        assert!(state.selected(1, 1).is_none());
+        assert_eq!(state.try_select(-1002, 1, "two"), SelectResult::Success);
+        assert_eq!(state.selected(-1002, 1).unwrap().name, "two");
+        assert!(state.selected(1, 1).is_none());
+
+        // Grants are layered on the config and rebuild the groups
+        assert!(state.authorized(5).is_none());
+        assert!(state.grant(5, "three", Role::Viewer).await.is_err());
+        assert_eq!(state.grant(5, "two", Role::Operator).await.unwrap(), None);
+        let server = state.selected(5, 5).unwrap();
+        assert_eq!(server.name, "two");
+        assert_eq!(state.role(5, &server), Some(Role::Operator));
+        assert_eq!(state.users()["two"][&5], (Role::Operator, true));
+        assert_eq!(state.revoke(5, None).await.unwrap(), 1);
+        assert!(state.authorized(5).is_none());
+
+        // Invites only raise roles and are single-use
+        assert_eq!(state.invitable_servers(1), ["one", "two"]);
+        assert_eq!(state.invitable_servers(-1001), ["one"]);
+        let code = state
+            .create_invite(Role::Operator, vec!["one".into(), "two".into()])
+            .await
+            .unwrap();
+        assert_eq!(
+            state.redeem_invite(1, &code).await.unwrap(),
+            Some(BTreeMap::from([
+                ("one".into(), Role::Admin),
+                ("two".into(), Role::Admin)
+            ]))
+        );
+        assert!(state.redeem_invite(5, &code).await.unwrap().is_none());
+        assert_eq!(state.users()["one"][&1], (Role::Admin, false));
+        let code = state
+            .create_invite(Role::Viewer, vec!["two".into()])
+            .await
+            .unwrap();
+        assert_eq!(
+            state.redeem_invite(5, &code).await.unwrap(),
+            Some(BTreeMap::from([("two".into(), Role::Viewer)]))
+        );
+        assert_eq!(state.users()["two"][&5], (Role::Viewer, true));
+
+        // Confirmations are single-use and can be skipped per user
+        let uuid = state.request_confirm(ConfirmAction::Purge, "one");
+        assert_eq!(
+            state.take_confirm(&uuid, "two"),
+            ConfirmResult::OtherServer("one".into())
+        );
+        assert_eq!(
+            state.take_confirm(&uuid, "one"),
+            ConfirmResult::Run(ConfirmAction::Purge)
+        );
+        assert_eq!(state.take_confirm(&uuid, "one"), ConfirmResult::Expired);
+        let uuid = state.request_confirm(ConfirmAction::Purge, "one");
+        state.cancel_confirm(&uuid);
+        // Files of a task are sent once at a time
+        assert!(state.start_upload("one", "gid"));
+        assert!(!state.start_upload("one", "gid"));
+        assert!(state.start_upload("two", "gid"));
+        state.finish_upload("one", "gid");
+        assert!(state.start_upload("one", "gid"));
+        assert_eq!(state.take_confirm(&uuid, "one"), ConfirmResult::Expired);
+        assert!(state.confirms(1));
+        state.set_confirms(1, false);
+        assert!(!state.confirms(1));
+        assert!(state.confirms(5));
+    }
+
+    #[tokio::test]
+    async fn test_local_api_limits() {
+        let toml = |telegram: &str| {
+            format!(
+                r#"
+[aria2]
+rpc_url = "ws://127.0.0.1:1/jsonrpc"
+token = "secret"
+
+[telegram]
+token = "bot_token"
+admins = [1]
+{telegram}
+
+[download]
+magnet_dirs = []
+torrent_dirs = []
+link_dirs = []
+default_dir = "/data"
+"#
+            )
+        };
+        let try_state = |telegram: &str| {
+            let config: crate::config::Config = toml::from_str(&toml(telegram)).unwrap();
+            async move { State::new(&config, Bot::new("bot_token")).await }
+        };
+        let state = |telegram: &str| {
+            let state = try_state(telegram);
+            async move { state.await.unwrap() }
+        };
+
+        let public = state("").await;
+        assert!(!public.local_api());
+        assert_eq!(public.upload_limit(), DEFAULT_UPLOAD_LIMIT);
+        assert_eq!(public.torrent_size_limit(), MAX_TORRENT_SIZE);
+
+        let local = state("api_url = \"http://127.0.0.1:8081\"\nlocal_api = true").await;
+        assert!(local.local_api());
+        assert_eq!(local.upload_limit(), LOCAL_UPLOAD_LIMIT);
+        assert_eq!(local.torrent_size_limit(), LOCAL_MAX_TORRENT_SIZE);
+
+        assert!(try_state("local_api = true").await.is_err());
+        let limited = state("upload_limit_mb = 100").await;
+        assert_eq!(limited.upload_limit(), 100 * 1024 * 1024);
+        let huge = state("upload_limit_mb = 9223372036854775807").await;
+        assert_eq!(huge.upload_limit(), u64::MAX);
+    }
+}
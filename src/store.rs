@@ -0,0 +1,150 @@
+//! Optional on-disk store of bot state.
+//!
+//! Server selections, pending confirm payloads and live message subscriptions
+//! are saved as JSON, so messages sent before a restart keep working.
+
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::{
+    role::Role,
+    state::{PendingConfirm, TorrentSelection, TorrentSource},
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoredState {
+    // user id -> selected server name
+    pub server_selected: HashMap<i64, String>,
+    // uuid, dir, uris; least recently used first
+    pub uri_cache: Vec<(String, SmolStr, Vec<String>)>,
+    // uuid, dir, file id, selection uuid; least recently used first
+    pub file_cache: Vec<(String, SmolStr, String, Option<String>)>,
+    // uuid, torrent file selection; least recently used first
+    pub torrent_selection: Vec<(String, TorrentSelection)>,
+    // uuid, action waiting for confirmation; least recently used first
+    pub pending_confirm: Vec<(String, PendingConfirm)>,
+    // users who run remove and purge without confirming
+    pub skip_confirm: Vec<i64>,
+    // server name -> server state
+    pub servers: HashMap<String, StoredServer>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoredServer {
+    pub list_subscribers: Vec<StoredListSubscriber>,
+    pub task_subscribers: Vec<StoredTaskSubscriber>,
+    pub stats_subscribers: Vec<StoredSubscriber>,
+    // gid -> chat to notify
+    pub owners: HashMap<SmolStr, i64>,
+    pub torrents: HashMap<SmolStr, TorrentSource>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSubscriber {
+    pub chat_id: i64,
+    pub message_id: i32,
+    // seconds until the message stops updating
+    pub expire_secs: u64,
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredListSubscriber {
+    #[serde(flatten)]
+    pub subscriber: StoredSubscriber,
+    pub page: usize,
+    // filter in callback form
+    pub filter: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTaskSubscriber {
+    #[serde(flatten)]
+    pub subscriber: StoredSubscriber,
+    pub gid: SmolStr,
+}
+
+impl StoredState {
+    /// Load the state, or an empty one if the file does not exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save the state, replacing the file atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        write_atomic(path.as_ref(), &serde_json::to_vec(self)?)
+    }
+}
+
+/// Write to a temporary file next to the path, then rename it over the path.
+pub fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ConfirmAction;
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("telearia2-{}.json", uuid::Uuid::new_v4()));
+        assert!(StoredState::load(&path).unwrap().servers.is_empty());
+
+        let mut state = StoredState::default();
+        state.server_selected.insert(1, "server1".to_string());
+        state.uri_cache.push((
+            "uuid".to_string(),
+            "/data".into(),
+            vec!["https://example.org/a".to_string()],
+        ));
+        state.pending_confirm.push((
+            "uuid".to_string(),
+            PendingConfirm {
+                action: ConfirmAction::Remove {
+                    gid: "2089b05ecca3d829".into(),
+                },
+                server: "server1".to_string(),
+                expires_at: 100,
+            },
+        ));
+        state
+            .servers
+            .entry("server1".to_string())
+            .or_default()
+            .task_subscribers
+            .push(StoredTaskSubscriber {
+                subscriber: StoredSubscriber {
+                    chat_id: 1,
+                    message_id: 2,
+                    expire_secs: 3,
+                    role: Role::Operator,
+                },
+                gid: "2089b05ecca3d829".into(),
+            });
+        state.save(&path).unwrap();
+
+        let loaded = StoredState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.server_selected.get(&1).unwrap(), "server1");
+        assert_eq!(loaded.uri_cache, state.uri_cache);
+        assert_eq!(loaded.pending_confirm, state.pending_confirm);
+        let sub = &loaded.servers["server1"].task_subscribers[0];
+        assert_eq!(sub.gid, "2089b05ecca3d829");
+        assert_eq!(sub.subscriber.message_id, 2);
+        assert_eq!(sub.subscriber.role, Role::Operator);
+    }
+}
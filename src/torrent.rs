@@ -0,0 +1,395 @@
+//! Minimal bencode decoder and torrent metadata reader.
+//!
+//! Only what the bot needs to validate and inspect uploaded torrent files is
+//! implemented.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Maximum nesting depth of bencode values.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(BTreeMap<&'a [u8], Value<'a>>),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Self::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value<'a>]> {
+        match self {
+            Self::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value<'a>> {
+        match self {
+            Self::Dict(d) => d.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeError {
+    UnexpectedEof,
+    InvalidByte(usize),
+    InvalidInteger(usize),
+    TooDeep,
+    TrailingData(usize),
+}
+
+impl std::fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedEof => f.write_str("unexpected end of data"),
+            Self::InvalidByte(pos) => write!(f, "invalid byte at offset {pos}"),
+            Self::InvalidInteger(pos) => write!(f, "invalid integer at offset {pos}"),
+            Self::TooDeep => f.write_str("nesting too deep"),
+            Self::TrailingData(pos) => write!(f, "trailing data at offset {pos}"),
+        }
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+/// Decode a complete bencoded buffer.
+pub fn decode(data: &[u8]) -> Result<Value<'_>, BencodeError> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let value = decoder.value(0)?;
+    if decoder.pos != data.len() {
+        return Err(BencodeError::TrailingData(decoder.pos));
+    }
+    Ok(value)
+}
+
+/// Find the raw encoded value of a key in a top-level dictionary.
+pub fn raw_dict_value<'a>(data: &'a [u8], key: &str) -> Result<Option<&'a [u8]>, BencodeError> {
+    let mut decoder = Decoder { data, pos: 0 };
+    if decoder.peek()? != b'd' {
+        return Err(BencodeError::InvalidByte(0));
+    }
+    decoder.pos += 1;
+    while decoder.peek()? != b'e' {
+        let k = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if k == key.as_bytes() {
+            return Ok(Some(&data[start..decoder.pos]));
+        }
+    }
+    Ok(None)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or(BencodeError::UnexpectedEof)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value<'a>, BencodeError> {
+        if depth > MAX_DEPTH {
+            return Err(BencodeError::TooDeep);
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let int = self.integer(b'e')?;
+                Ok(Value::Int(int))
+            }
+            b'0'..=b'9' => self.bytes().map(Value::Bytes),
+            b'l' => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let value = self.value(depth + 1)?;
+                    dict.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            _ => Err(BencodeError::InvalidByte(self.pos)),
+        }
+    }
+
+    fn integer(&mut self, terminator: u8) -> Result<i64, BencodeError> {
+        let start = self.pos;
+        let len = self.data[start..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or(BencodeError::UnexpectedEof)?;
+        self.pos += len + 1;
+        std::str::from_utf8(&self.data[start..start + len])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(BencodeError::InvalidInteger(start))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        if !self.peek()?.is_ascii_digit() {
+            return Err(BencodeError::InvalidByte(start));
+        }
+        let len = usize::try_from(self.integer(b':')?)
+            .map_err(|_| BencodeError::InvalidInteger(start))?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(BencodeError::UnexpectedEof)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentFile {
+    pub path: String,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentMeta {
+    pub name: String,
+    // hex encoded SHA-1 of the info dictionary
+    pub info_hash: String,
+    pub piece_length: u64,
+    pub files: Vec<TorrentFile>,
+}
+
+impl TorrentMeta {
+    /// Parse and validate torrent metadata. Files keep the torrent order, which
+    /// is what aria2 uses for `select-file` indexes.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let root = decode(data)?;
+        let info = root
+            .get("info")
+            .ok_or_else(|| anyhow::anyhow!("missing info dictionary"))?;
+        if !matches!(info, Value::Dict(_)) {
+            anyhow::bail!("info is not a dictionary");
+        }
+        let name = utf8_field(info, "name")
+            .ok_or_else(|| anyhow::anyhow!("missing torrent name"))?
+            .to_string();
+        let piece_length = info
+            .get("piece length")
+            .and_then(Value::as_int)
+            .and_then(|len| u64::try_from(len).ok())
+            .filter(|len| *len > 0)
+            .ok_or_else(|| anyhow::anyhow!("invalid piece length"))?;
+        match info.get("pieces").and_then(Value::as_bytes) {
+            Some(pieces) if !pieces.is_empty() && pieces.len() % 20 == 0 => (),
+            Some(_) => anyhow::bail!("invalid pieces"),
+            None if info.get("file tree").is_some() => {
+                anyhow::bail!("BitTorrent v2 only torrents are not supported")
+            }
+            None => anyhow::bail!("missing pieces"),
+        }
+        let info_hash = raw_dict_value(data, "info")?
+            .map(|raw| sha1_smol::Sha1::from(raw).digest().to_string())
+            .ok_or_else(|| anyhow::anyhow!("missing info dictionary"))?;
+
+        let files = match info.get("files").and_then(Value::as_list) {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    let length = length_field(file)?;
+                    let path = file
+                        .get("path.utf-8")
+                        .or_else(|| file.get("path"))
+                        .and_then(Value::as_list)
+                        .and_then(|parts| {
+                            parts.iter().map(Value::as_str).collect::<Option<Vec<_>>>()
+                        })
+                        .ok_or_else(|| anyhow::anyhow!("invalid file path"))?
+                        .join("/");
+                    Ok(TorrentFile { path, length })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => vec![TorrentFile {
+                path: name.clone(),
+                length: length_field(info)?,
+            }],
+        };
+        // Lengths are checked once so total_length can't overflow
+        files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.length))
+            .ok_or_else(|| anyhow::anyhow!("total length too large"))?;
+        Ok(Self {
+            name,
+            info_hash,
+            piece_length,
+            files,
+        })
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
+
+    /// Positions of the largest files, largest first.
+    pub fn largest_files(&self, n: usize) -> Vec<usize> {
+        let mut positions: Vec<_> = (0..self.files.len()).collect();
+        positions.sort_by(|&a, &b| self.files[b].length.cmp(&self.files[a].length));
+        positions.truncate(n);
+        positions
+    }
+}
+
+fn utf8_field<'a>(dict: &Value<'a>, key: &str) -> Option<&'a str> {
+    dict.get(&format!("{key}.utf-8"))
+        .or_else(|| dict.get(key))
+        .and_then(Value::as_str)
+}
+
+fn length_field(dict: &Value<'_>) -> anyhow::Result<u64> {
+    dict.get("length")
+        .and_then(Value::as_int)
+        .and_then(|len| u64::try_from(len).ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid file length"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_values() {
+        assert_eq!(decode(b"i42e").unwrap(), Value::Int(42));
+        assert_eq!(decode(b"i-3e").unwrap(), Value::Int(-3));
+        assert_eq!(decode(b"4:spam").unwrap(), Value::Bytes(b"spam"));
+        assert_eq!(
+            decode(b"l4:spami1ee").unwrap(),
+            Value::List(vec![Value::Bytes(b"spam"), Value::Int(1)])
+        );
+        let dict = decode(b"d3:cow3:moo4:spami7ee").unwrap();
+        assert_eq!(dict.get("cow").and_then(Value::as_str), Some("moo"));
+        assert_eq!(dict.get("spam").and_then(Value::as_int), Some(7));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b"i42"), Err(BencodeError::UnexpectedEof));
+        assert_eq!(decode(b"10:short"), Err(BencodeError::UnexpectedEof));
+        assert_eq!(decode(b"ixe"), Err(BencodeError::InvalidInteger(1)));
+        assert_eq!(decode(b"x"), Err(BencodeError::InvalidByte(0)));
+        assert_eq!(decode(b"i1ei2e"), Err(BencodeError::TrailingData(3)));
+        let nested = "l".repeat(MAX_DEPTH + 2) + &"e".repeat(MAX_DEPTH + 2);
+        assert_eq!(decode(nested.as_bytes()), Err(BencodeError::TooDeep));
+    }
+
+    const PIECES: &str = "6:pieces20:aaaaaaaaaaaaaaaaaaaa";
+
+    fn single_file_torrent() -> String {
+        format!(
+            "d8:announce3:url4:infod6:lengthi1024e4:name8:file.iso12:piece lengthi16384e{PIECES}ee"
+        )
+    }
+
+    #[test]
+    fn test_raw_dict_value() {
+        let data = single_file_torrent();
+        let raw = raw_dict_value(data.as_bytes(), "info").unwrap().unwrap();
+        assert!(raw.starts_with(b"d6:length"));
+        assert!(raw.ends_with(b"aaaae"));
+        assert_eq!(
+            raw_dict_value(data.as_bytes(), "announce").unwrap(),
+            Some(&b"3:url"[..])
+        );
+        assert_eq!(raw_dict_value(data.as_bytes(), "missing").unwrap(), None);
+        assert!(raw_dict_value(b"i1e", "info").is_err());
+    }
+
+    #[test]
+    fn test_parse_single_file() {
+        let data = single_file_torrent();
+        let meta = TorrentMeta::parse(data.as_bytes()).unwrap();
+        assert_eq!(meta.name, "file.iso");
+        assert_eq!(meta.piece_length, 16384);
+        assert_eq!(meta.info_hash, "d63e83b213742e2840b48dd2b69f5a96d619ebb0");
+        assert_eq!(
+            meta.files,
+            vec![TorrentFile {
+                path: "file.iso".into(),
+                length: 1024
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_file() {
+        let data = format!(
+            "d4:infod5:filesld6:lengthi1e4:pathl1:a5:b.txteed6:lengthi2e4:pathl5:c.txteee4:name3:dir12:piece lengthi16384e{PIECES}ee"
+        );
+        let meta = TorrentMeta::parse(data.as_bytes()).unwrap();
+        assert_eq!(meta.name, "dir");
+        assert_eq!(meta.files.len(), 2);
+        assert_eq!(meta.files[0].path, "a/b.txt");
+        assert_eq!(meta.files[1].length, 2);
+        assert_eq!(meta.total_length(), 3);
+        assert_eq!(meta.largest_files(1), vec![1]);
+    }
+
+    #[test]
+    fn test_parse_not_torrent() {
+        assert!(TorrentMeta::parse(b"d3:foo3:bare").is_err());
+        assert!(TorrentMeta::parse(b"not a torrent").is_err());
+        // missing pieces
+        assert!(
+            TorrentMeta::parse(b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384eee").is_err()
+        );
+        // pieces not a multiple of 20 bytes
+        assert!(TorrentMeta::parse(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces3:abcee"
+        )
+        .is_err());
+        // total length overflowing u64
+        let data = format!(
+            "d4:infod5:filesld6:lengthi9223372036854775807e4:pathl1:aeed6:lengthi9223372036854775807e4:pathl1:bee\
+             d6:lengthi2e4:pathl1:ceee4:name3:dir12:piece lengthi16384e{PIECES}ee"
+        );
+        let err = TorrentMeta::parse(data.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "total length too large");
+    }
+}
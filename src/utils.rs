@@ -4,7 +4,7 @@ use std::{
 };
 
 use serde::{de::DeserializeOwned, Deserialize};
-use teloxide::types::ReplyParameters;
+use teloxide::types::{Message, ReplyParameters, ThreadId};
 use toml::Value;
 
 #[derive(Debug, Clone)]
@@ -34,6 +34,28 @@ impl<T> ExpiredDeque<T> {
         });
     }
 
+    pub fn set_expire(&mut self, expire: Duration) {
+        self.expire = expire;
+    }
+
+    /// Push a value which expires after `remaining` instead of the default duration.
+    ///
+    /// Values are expected to be pushed in expiry order.
+    pub fn push_back_expire_in(&mut self, value: T, remaining: Duration) {
+        self.inner.push_back(ExpiredElement {
+            value,
+            expire: Instant::now() + remaining.min(self.expire),
+        });
+    }
+
+    /// Iterate all values with their remaining time, zero for expired ones.
+    pub fn iter_remaining(&self) -> impl Iterator<Item = (&T, Duration)> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .map(move |item| (&item.value, item.expire.saturating_duration_since(now)))
+    }
+
     #[allow(unused)]
     pub fn pop_front(&mut self) -> Option<T> {
         let now = Instant::now();
@@ -264,8 +286,16 @@ impl<T> Iterator for SingleMultiMapIntoIter<T> {
     }
 }
 
+/// Forum topic of the message, messages sent back to its chat belong there too.
+pub fn topic_of(msg: &Message) -> Option<ThreadId> {
+    msg.thread_id.filter(|_| msg.is_topic_message)
+}
+
 pub trait SendMessageSettersExt {
     fn reply_to_message_id_opt(self, message_id: Option<teloxide::types::MessageId>) -> Self;
+    fn message_thread_id_opt(self, thread_id: Option<ThreadId>) -> Self;
+    /// Reply to the message, in its forum topic if any.
+    fn reply_to(self, msg: &Message) -> Self;
 }
 
 impl<T: teloxide::payloads::SendMessageSetters> SendMessageSettersExt for T {
@@ -276,6 +306,19 @@ impl<T: teloxide::payloads::SendMessageSetters> SendMessageSettersExt for T {
             self
         }
     }
+
+    fn message_thread_id_opt(self, thread_id: Option<ThreadId>) -> Self {
+        if let Some(thread_id) = thread_id {
+            self.message_thread_id(thread_id)
+        } else {
+            self
+        }
+    }
+
+    fn reply_to(self, msg: &Message) -> Self {
+        self.reply_parameters(ReplyParameters::new(msg.id))
+            .message_thread_id_opt(topic_of(msg))
+    }
 }
 
 #[cfg(test)]
@@ -316,6 +359,20 @@ mod tests {
             assert_eq!(deque.iter().count(), 0);
         }
 
+        #[test]
+        fn test_expire_in() {
+            let mut deque = ExpiredDeque::new(Duration::from_secs(10));
+            deque.push_back_expire_in(1, Duration::ZERO);
+            deque.push_back_expire_in(2, Duration::from_secs(60));
+            std::thread::sleep(Duration::from_millis(10));
+
+            let remaining: Vec<_> = deque.iter_remaining().collect();
+            assert_eq!(remaining[0], (&1, Duration::ZERO));
+            // Capped at the deque expire
+            assert!(remaining[1].1 <= Duration::from_secs(10));
+            assert_eq!(deque.drain_expired(), vec![1]);
+        }
+
         #[test]
         fn test_pop_front() {
             let mut deque = ExpiredDeque::new(Duration::from_secs(10));